@@ -6,8 +6,7 @@ extern crate common;
 extern crate uuid;
 
 use clap::{Arg, App, SubCommand};
-use common::datastore;
-use braid::Datastore;
+use common::{datastore, create_account, delete_account, record_audit_entry, AuditEntry};
 use uuid::Uuid;
 
 /// App for managing accounts
@@ -23,17 +22,24 @@ fn main() {
     let datastore = datastore();
 
     if let Some(_) = matches.subcommand_matches("add") {
-        match datastore.create_account() {
+        match create_account(&datastore) {
             Ok((id, secret)) => {
+                record_audit_entry(&AuditEntry::new("create_account").with_account_id(Some(id)));
                 println!("Account ID: {}", id);
                 println!("Account secret: {}", secret);
             },
-            Err(err) => exit_with_err!("Could not create account: {:?}", err),
+            Err(err) => {
+                record_audit_entry(&AuditEntry::new("create_account").with_success(false));
+                exit_with_err!("Could not create account: {:?}", err);
+            }
         }
     } else if let Some(matches) = matches.subcommand_matches("remove") {
         let id = value_t!(matches, "ID", Uuid).unwrap();
 
-        if let Err(err) = datastore.delete_account(id) {
+        let result = delete_account(&datastore, id);
+        record_audit_entry(&AuditEntry::new("delete_account").with_account_id(Some(id)).with_success(result.is_ok()));
+
+        if let Err(err) = result {
             exit_with_err!("Could not delete account: {:?}", err);
         }
     } else {
@@ -0,0 +1,283 @@
+//! This module layers account management on top of the datastore's account
+//! calls. Datastores cannot list accounts, disable them, or change their
+//! secrets, so this keeps that state in metadata:
+//!
+//! * A global metadata entry lists the IDs of registered accounts.
+//! * An account metadata entry holds whether the account is disabled,
+//!   whether it has been registered, and the digest of its current secret if
+//!   it has been rotated.
+//!
+//! Metadata keys that start with `_braid_` are reserved for this purpose,
+//! and cannot be accessed through the HTTP API or scripts.
+//!
+//! Changes to the list of accounts take a datastore lock, so concurrent
+//! creations and deletions don't overwrite each other, even when they're made
+//! by different servers or by `braid-account`. Accounts are registered when
+//! they're created through this module. Since the datastore can't enumerate
+//! the accounts that were created before then, those are registered the first
+//! time that they authenticate, and aren't listed until they have.
+
+use braid::{Datastore, Transaction, Error};
+use datastore::{ProxyDatastore, ProxyTransaction};
+use openssl::hash::{hash, MessageDigest};
+use openssl::memcmp;
+use rand::{OsRng, Rng};
+use serde_json::Value as JsonValue;
+use serde_json;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// The prefix of metadata keys reserved for internal use.
+const RESERVED_METADATA_PREFIX: &'static str = "_braid_";
+
+/// The global metadata key listing the IDs of all registered accounts.
+const ACCOUNTS_KEY: &'static str = "_braid_accounts";
+
+/// The account metadata key holding an account's `AccountState`.
+const ACCOUNT_STATE_KEY: &'static str = "_braid_account_state";
+
+/// The length of generated secrets.
+const SECRET_LENGTH: usize = 32;
+
+/// Returns whether a metadata key is reserved for internal use.
+pub fn is_reserved_metadata_key(key: &str) -> bool {
+    key.starts_with(RESERVED_METADATA_PREFIX)
+}
+
+/// Management state of an account that the datastore does not track.
+#[derive(Clone, Debug, Default)]
+pub struct AccountState {
+    pub disabled: bool,
+
+    /// Whether the account has been added to the list of registered
+    /// accounts.
+    registered: bool,
+
+    /// The salt and SHA-256 digest, both hex-encoded, of the account's
+    /// secret if it has been rotated. Otherwise the datastore checks the
+    /// secret that it issued when the account was created.
+    secret_digest: Option<(String, String)>,
+}
+
+impl AccountState {
+    /// Returns whether the account's secret has been rotated.
+    pub fn secret_rotated(&self) -> bool {
+        self.secret_digest.is_some()
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut o: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+        o.insert("disabled".to_string(), JsonValue::Bool(self.disabled));
+        o.insert("registered".to_string(), JsonValue::Bool(self.registered));
+
+        if let Some((ref salt, ref digest)) = self.secret_digest {
+            o.insert("salt".to_string(), JsonValue::String(salt.clone()));
+            o.insert("digest".to_string(), JsonValue::String(digest.clone()));
+        }
+
+        JsonValue::Object(o)
+    }
+
+    fn from_json(value: &JsonValue) -> AccountState {
+        let salt = value.get("salt").and_then(|v| v.as_str());
+        let digest = value.get("digest").and_then(|v| v.as_str());
+
+        AccountState {
+            disabled: value.get("disabled").and_then(|v| v.as_bool()).unwrap_or(false),
+            registered: value.get("registered").and_then(|v| v.as_bool()).unwrap_or(false),
+            secret_digest: match (salt, digest) {
+                (Some(salt), Some(digest)) => Some((salt.to_string(), digest.to_string())),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Creates an account, and adds it to the list of registered accounts.
+pub fn create_account(datastore: &ProxyDatastore) -> Result<(Uuid, String), Error> {
+    let (account_id, secret) = datastore.create_account()?;
+    register_account(datastore, account_id)?;
+    Ok((account_id, secret))
+}
+
+/// Deletes an account, and removes it from the list of registered accounts.
+/// It's removed from the list even if the datastore no longer has it.
+pub fn delete_account(datastore: &ProxyDatastore, account_id: Uuid) -> Result<(), Error> {
+    let result = match datastore.delete_account(account_id) {
+        Ok(_) => Ok(()),
+        Err(Error::AccountNotFound) => Err(Error::AccountNotFound),
+        Err(err) => return Err(err),
+    };
+
+    let trans = datastore.transaction(Uuid::nil())?;
+    let _lock = trans.lock(ACCOUNTS_KEY)?;
+    let account_ids = get_account_ids(&trans)?;
+
+    if account_ids.contains(&account_id) {
+        let account_ids: Vec<Uuid> = account_ids.into_iter().filter(|id| *id != account_id).collect();
+        set_account_ids(&trans, &account_ids)?;
+        trans.commit()?;
+    } else {
+        trans.rollback()?;
+    }
+
+    result
+}
+
+/// Lists the IDs of registered accounts. Accounts that were created before
+/// account registration was introduced are only included once they have
+/// authenticated.
+pub fn list_accounts(datastore: &ProxyDatastore) -> Result<Vec<Uuid>, Error> {
+    let trans = datastore.transaction(Uuid::nil())?;
+    let account_ids = get_account_ids(&trans)?;
+    trans.rollback()?;
+    Ok(account_ids)
+}
+
+/// Gets the management state of an account.
+///
+/// # Errors
+/// Returns `Error::AccountNotFound` if the account does not exist.
+pub fn get_account_state(datastore: &ProxyDatastore, account_id: Uuid) -> Result<AccountState, Error> {
+    if !datastore.has_account(account_id)? {
+        return Err(Error::AccountNotFound);
+    }
+
+    let trans = datastore.transaction(account_id)?;
+    let state = read_account_state(&trans, account_id)?;
+    trans.rollback()?;
+    Ok(state)
+}
+
+/// Disables or re-enables an account. Disabled accounts fail to
+/// authenticate.
+pub fn set_account_disabled(datastore: &ProxyDatastore, account_id: Uuid, disabled: bool) -> Result<(), Error> {
+    update_account_state(datastore, account_id, |state| state.disabled = disabled)
+}
+
+/// Replaces an account's secret with a newly generated one, which is
+/// returned. The previous secret stops working immediately.
+pub fn rotate_account_secret(datastore: &ProxyDatastore, account_id: Uuid) -> Result<String, Error> {
+    let secret = generate_random_string(SECRET_LENGTH)?;
+    let salt = generate_random_string(SECRET_LENGTH)?;
+    let digest = digest_secret(&salt, &secret)?;
+    update_account_state(datastore, account_id, |state| state.secret_digest = Some((salt, digest)))?;
+    Ok(secret)
+}
+
+/// Checks an account's credentials, taking into account whether it has been
+/// disabled or had its secret rotated. Accounts that authenticate and aren't
+/// registered yet are registered.
+pub fn authenticate(datastore: &ProxyDatastore, account_id: Uuid, secret: String) -> Result<bool, Error> {
+    let trans = datastore.transaction(account_id)?;
+    let state = read_account_state(&trans, account_id);
+    trans.rollback()?;
+
+    let state = match state {
+        Ok(state) => state,
+        Err(Error::AccountNotFound) => return Ok(false),
+        Err(err) => return Err(err),
+    };
+
+    if state.disabled {
+        return Ok(false);
+    }
+
+    let authenticated = match state.secret_digest {
+        Some((salt, digest)) => {
+            let expected = digest_secret(&salt, &secret)?;
+            secrets_match(&expected[..], &digest[..])
+        }
+        None => datastore.auth(account_id, secret)?,
+    };
+
+    if authenticated && !state.registered {
+        register_account(datastore, account_id)?;
+    }
+
+    Ok(authenticated)
+}
+
+/// Compares a secret against the expected one in constant time, so the
+/// comparison doesn't reveal how much of the secret is right.
+pub fn secrets_match(expected: &str, actual: &str) -> bool {
+    expected.len() == actual.len() && memcmp::eq(expected.as_bytes(), actual.as_bytes())
+}
+
+/// Adds an account to the list of registered accounts, if it isn't already
+/// on it, and then marks it as registered.
+fn register_account(datastore: &ProxyDatastore, account_id: Uuid) -> Result<(), Error> {
+    let trans = datastore.transaction(account_id)?;
+    let lock = trans.lock(ACCOUNTS_KEY)?;
+    let mut account_ids = get_account_ids(&trans)?;
+
+    if account_ids.contains(&account_id) {
+        trans.rollback()?;
+    } else {
+        account_ids.push(account_id);
+        set_account_ids(&trans, &account_ids)?;
+        trans.commit()?;
+    }
+
+    drop(lock);
+    update_account_state(datastore, account_id, |state| state.registered = true)
+}
+
+fn get_account_ids(trans: &ProxyTransaction) -> Result<Vec<Uuid>, Error> {
+    let value = match trans.get_global_metadata(ACCOUNTS_KEY.to_string()) {
+        Ok(value) => value,
+        Err(Error::MetadataNotFound) => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let ids = match value {
+        JsonValue::Array(ids) => ids,
+        _ => return Err(Error::Unexpected(format!("Invalid `{}` metadata", ACCOUNTS_KEY))),
+    };
+
+    Ok(ids.iter().filter_map(|id| id.as_str().and_then(|id| Uuid::from_str(id).ok())).collect())
+}
+
+fn set_account_ids(trans: &ProxyTransaction, account_ids: &[Uuid]) -> Result<(), Error> {
+    let ids: Vec<JsonValue> = account_ids.iter().map(|id| JsonValue::String(id.hyphenated().to_string())).collect();
+    trans.set_global_metadata(ACCOUNTS_KEY.to_string(), JsonValue::Array(ids))
+}
+
+fn read_account_state(trans: &ProxyTransaction, account_id: Uuid) -> Result<AccountState, Error> {
+    match trans.get_account_metadata(account_id, ACCOUNT_STATE_KEY.to_string()) {
+        Ok(value) => Ok(AccountState::from_json(&value)),
+        Err(Error::MetadataNotFound) => Ok(AccountState::default()),
+        Err(err) => Err(err),
+    }
+}
+
+fn update_account_state<F: FnOnce(&mut AccountState)>(datastore: &ProxyDatastore, account_id: Uuid, f: F) -> Result<(), Error> {
+    if !datastore.has_account(account_id)? {
+        return Err(Error::AccountNotFound);
+    }
+
+    let trans = datastore.transaction(account_id)?;
+    let _lock = trans.lock(&format!("{}:{}", ACCOUNT_STATE_KEY, account_id.hyphenated()))?;
+    let mut state = read_account_state(&trans, account_id)?;
+    f(&mut state);
+    trans.set_account_metadata(account_id, ACCOUNT_STATE_KEY.to_string(), state.to_json())?;
+    trans.commit()
+}
+
+fn generate_random_string(len: usize) -> Result<String, Error> {
+    let mut rng = OsRng::new().map_err(|err| Error::Unexpected(format!("Could not create random number generator: {}", err)))?;
+    Ok(rng.gen_ascii_chars().take(len).collect())
+}
+
+fn digest_secret(salt: &str, secret: &str) -> Result<String, Error> {
+    let input = format!("{}{}", salt, secret);
+    sha256_hex(input.as_bytes())
+}
+
+/// Returns the hex-encoded SHA-256 digest of some bytes.
+pub fn sha256_hex(input: &[u8]) -> Result<String, Error> {
+    match hash(MessageDigest::sha256(), input) {
+        Ok(digest) => Ok(digest.iter().map(|b| format!("{:02x}", b)).collect()),
+        Err(err) => Err(Error::Unexpected(format!("Could not compute digest: {}", err))),
+    }
+}
@@ -0,0 +1,338 @@
+//! This module maintains an append-only audit log of administrative and
+//! destructive actions, such as account management, failed authentication
+//! attempts, deletions and script executions. Entries are written as JSON
+//! lines to the file specified by `BRAID_AUDIT_LOG`; if it is not set,
+//! nothing is logged.
+//!
+//! Once the file grows past `BRAID_AUDIT_LOG_MAX_SIZE`, it's rotated to
+//! `<path>.<time>`, where the time is when it was rotated. Rotated files are
+//! never replaced or removed, and queries read all of them, in order, before
+//! the current file.
+
+use chrono::{DateTime, UTC};
+use serde_json;
+use serde_json::Value as JsonValue;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// The format of the times that rotated audit logs are suffixed with. These
+/// sort in the order that they happened.
+const ROTATED_TIME_FORMAT: &'static str = "%Y%m%dT%H%M%S%.9fZ";
+
+lazy_static! {
+    static ref AUDIT_LOG_PATH: Option<PathBuf> = env::var("BRAID_AUDIT_LOG").ok().map(PathBuf::from);
+
+    /// The size, in bytes, past which the audit log is rotated
+    static ref AUDIT_LOG_MAX_SIZE: u64 = match env::var("BRAID_AUDIT_LOG_MAX_SIZE") {
+        Ok(s) => s.parse().expect("Could not parse environment variable `BRAID_AUDIT_LOG_MAX_SIZE`: must be a u64"),
+        Err(_) => 64 * 1024 * 1024,
+    };
+
+    /// Serializes writes and rotations from within this process. Writes
+    /// from other processes, e.g. `braid-account`, are kept whole by opening
+    /// the file in append mode.
+    static ref AUDIT_LOG_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// A single entry in the audit log.
+#[derive(Clone, Debug)]
+pub struct AuditEntry {
+    pub time: DateTime<UTC>,
+    pub action: String,
+    pub account_id: Option<Uuid>,
+    pub source_ip: Option<String>,
+    pub request_id: Option<String>,
+
+    /// What the action was applied to, e.g. the query of a deletion or the
+    /// name of a script
+    pub target: Option<String>,
+    pub success: bool,
+}
+
+impl AuditEntry {
+    pub fn new(action: &str) -> AuditEntry {
+        AuditEntry {
+            time: UTC::now(),
+            action: action.to_string(),
+            account_id: None,
+            source_ip: None,
+            request_id: None,
+            target: None,
+            success: true,
+        }
+    }
+
+    pub fn with_account_id(mut self, account_id: Option<Uuid>) -> AuditEntry {
+        self.account_id = account_id;
+        self
+    }
+
+    pub fn with_source_ip(mut self, source_ip: Option<String>) -> AuditEntry {
+        self.source_ip = source_ip;
+        self
+    }
+
+    pub fn with_request_id(mut self, request_id: Option<String>) -> AuditEntry {
+        self.request_id = request_id;
+        self
+    }
+
+    pub fn with_target(mut self, target: String) -> AuditEntry {
+        self.target = Some(target);
+        self
+    }
+
+    pub fn with_success(mut self, success: bool) -> AuditEntry {
+        self.success = success;
+        self
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let optional_string = |value: &Option<String>| match *value {
+            Some(ref value) => JsonValue::String(value.clone()),
+            None => JsonValue::Null,
+        };
+
+        let mut o: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+        o.insert("time".to_string(), JsonValue::String(self.time.to_rfc3339()));
+        o.insert("action".to_string(), JsonValue::String(self.action.clone()));
+        o.insert("account_id".to_string(), optional_string(&self.account_id.map(|id| id.hyphenated().to_string())));
+        o.insert("source_ip".to_string(), optional_string(&self.source_ip));
+        o.insert("request_id".to_string(), optional_string(&self.request_id));
+        o.insert("target".to_string(), optional_string(&self.target));
+        o.insert("success".to_string(), JsonValue::Bool(self.success));
+        JsonValue::Object(o)
+    }
+
+    /// Parses an entry from a line in the audit log. Returns `None` if the
+    /// line is malformed.
+    fn from_json(value: &JsonValue) -> Option<AuditEntry> {
+        let optional_string = |name: &str| value.get(name).and_then(|v| v.as_str()).map(|v| v.to_string());
+
+        let time = match value.get("time").and_then(|v| v.as_str()).map(DateTime::<UTC>::from_str) {
+            Some(Ok(time)) => time,
+            _ => return None,
+        };
+
+        let action = match optional_string("action") {
+            Some(action) => action,
+            None => return None,
+        };
+
+        let account_id = match optional_string("account_id").map(|id| Uuid::from_str(&id[..])) {
+            Some(Ok(account_id)) => Some(account_id),
+            Some(Err(_)) => return None,
+            None => None,
+        };
+
+        Some(AuditEntry {
+            time: time,
+            action: action,
+            account_id: account_id,
+            source_ip: optional_string("source_ip"),
+            request_id: optional_string("request_id"),
+            target: optional_string("target"),
+            success: value.get("success").and_then(|v| v.as_bool()).unwrap_or(false),
+        })
+    }
+}
+
+/// Restricts which audit log entries are returned by `query_audit_log`.
+#[derive(Clone, Debug, Default)]
+pub struct AuditFilter {
+    pub account_id: Option<Uuid>,
+
+    /// Only include entries at or after this time
+    pub from: Option<DateTime<UTC>>,
+
+    /// Only include entries before this time
+    pub to: Option<DateTime<UTC>>,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        if self.account_id.is_some() && entry.account_id != self.account_id {
+            return false;
+        }
+
+        if let Some(from) = self.from {
+            if entry.time < from {
+                return false;
+            }
+        }
+
+        if let Some(to) = self.to {
+            if entry.time >= to {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Returns whether audit logging is enabled.
+pub fn audit_log_enabled() -> bool {
+    AUDIT_LOG_PATH.is_some()
+}
+
+/// Appends an entry to the audit log. Failures to write are reported on
+/// stderr rather than returned, since by the time an action is audited it
+/// has already taken place.
+pub fn record_audit_entry(entry: &AuditEntry) {
+    let path = match *AUDIT_LOG_PATH {
+        Some(ref path) => path,
+        None => return,
+    };
+
+    let mut line = serde_json::to_string(&entry.to_json()).unwrap();
+    line.push('\n');
+
+    let _guard = AUDIT_LOG_LOCK.lock().unwrap();
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .and_then(|_| rotate_audit_log(path));
+
+    if let Err(err) = result {
+        let _ = writeln!(io::stderr(), "Could not write to the audit log: {}", err);
+    }
+}
+
+/// Rotates the audit log if it has reached the maximum size. The file is
+/// linked to its rotated path before being removed, which fails rather than
+/// replace an existing file. Writers that still have the old file open
+/// append to the rotated file.
+fn rotate_audit_log(path: &Path) -> io::Result<()> {
+    // Another process may have rotated the file since it was written to, so
+    // this checks the file at the path rather than the one that was opened.
+    match fs::metadata(path) {
+        Ok(ref metadata) if metadata.len() >= *AUDIT_LOG_MAX_SIZE => (),
+        Ok(_) => return Ok(()),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    }
+
+    let mut rotated = path.as_os_str().to_os_string();
+    rotated.push(format!(".{}", UTC::now().format(ROTATED_TIME_FORMAT)));
+    fs::hard_link(path, &rotated)?;
+    fs::remove_file(path)
+}
+
+/// Lists the rotated audit log files, oldest first.
+fn list_rotated_paths(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let (dir, prefix) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => {
+            let mut prefix = name.to_os_string();
+            prefix.push(".");
+            (dir, prefix)
+        }
+        _ => return Ok(Vec::new()),
+    };
+
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let mut paths = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+
+        if is_rotated_name(&entry.file_name(), &prefix) {
+            paths.push(entry.path());
+        }
+    }
+
+    // Rotation times sort in the order that they happened.
+    paths.sort();
+    Ok(paths)
+}
+
+/// Returns whether a file name is the name of a rotated audit log, i.e. the
+/// audit log's name followed by a dot and the time it was rotated.
+fn is_rotated_name(name: &OsString, prefix: &OsString) -> bool {
+    match (name.to_str(), prefix.to_str()) {
+        (Some(name), Some(prefix)) if name.len() > prefix.len() && name.starts_with(prefix) => {
+            name[prefix.len()..].chars().all(|c| c.is_digit(10) || c == 'T' || c == 'Z' || c == '.')
+        }
+        _ => false,
+    }
+}
+
+/// Returns up to `limit` audit log entries that match a filter, oldest first.
+pub fn query_audit_log(filter: &AuditFilter, limit: usize) -> Result<Vec<AuditEntry>, String> {
+    let path = match *AUDIT_LOG_PATH {
+        Some(ref path) => path,
+        None => return Ok(Vec::new()),
+    };
+
+    // The lock is only held while listing the rotated files and opening the
+    // current one, so that a rotation can't happen in between. The opened
+    // file can still be read after it's rotated, and rotated files are
+    // never removed, so the files can be scanned without the lock.
+    let (rotated_paths, current) = {
+        let _guard = AUDIT_LOG_LOCK.lock().unwrap();
+        let rotated_paths = list_rotated_paths(path).map_err(|err| format!("Could not list the rotated audit logs: {}", err))?;
+        (rotated_paths, open_audit_log_file(path)?)
+    };
+
+    let mut entries: Vec<AuditEntry> = Vec::new();
+
+    for path in rotated_paths {
+        if entries.len() >= limit {
+            return Ok(entries);
+        }
+
+        if let Some(file) = open_audit_log_file(&path)? {
+            read_audit_log_file(file, filter, limit, &mut entries)?;
+        }
+    }
+
+    if let Some(file) = current {
+        if entries.len() < limit {
+            read_audit_log_file(file, filter, limit, &mut entries)?;
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Opens an audit log file for reading. Returns `None` if it doesn't exist.
+fn open_audit_log_file(path: &Path) -> Result<Option<File>, String> {
+    match File::open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(format!("Could not open the audit log: {}", err)),
+    }
+}
+
+/// Adds the entries of one audit log file that match a filter to `entries`,
+/// until there are `limit` of them.
+fn read_audit_log_file<R: Read>(file: R, filter: &AuditFilter, limit: usize, entries: &mut Vec<AuditEntry>) -> Result<(), String> {
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|err| format!("Could not read the audit log: {}", err))?;
+
+        let entry = match serde_json::from_str::<JsonValue>(&line[..]).ok().and_then(|v| AuditEntry::from_json(&v)) {
+            Some(entry) => entry,
+            None => continue,
+        };
+
+        if filter.matches(&entry) {
+            entries.push(entry);
+
+            if entries.len() >= limit {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -10,6 +10,62 @@ use braid::{Datastore, Transaction, RocksdbDatastore, PostgresDatastore,
 use uuid::Uuid;
 use serde_json::Value as JsonValue;
 use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use std::time::{Duration, Instant};
+
+/// A function that is notified of every call made through a
+/// `ProxyTransaction`, with the name of the method called and how long it
+/// took.
+pub type TransactionObserver = fn(&'static str, Duration);
+
+/// The prefix of the global metadata keys written to take a
+/// `ProxyTransaction::lock`.
+const LOCK_KEY_PREFIX: &'static str = "_braid_lock:";
+
+/// The number of `ProxyTransaction`s that haven't been committed, rolled
+/// back or dropped.
+static OPEN_TRANSACTIONS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Set by `abort_transactions`.
+static ABORTING: AtomicBool = ATOMIC_BOOL_INIT;
+
+lazy_static! {
+    static ref TRANSACTION_OBSERVER: RwLock<Option<TransactionObserver>> = RwLock::new(None);
+
+    /// Serializes locked sections within this process, for datastores whose
+    /// transactions don't lock anything.
+    static ref LOCAL_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Sets the function that is notified of calls made through
+/// `ProxyTransaction`s, replacing any previously set one.
+pub fn set_transaction_observer(observer: TransactionObserver) {
+    *TRANSACTION_OBSERVER.write().unwrap() = Some(observer);
+}
+
+/// Returns the number of transactions that are open.
+pub fn open_transactions() -> usize {
+    OPEN_TRANSACTIONS.load(Ordering::SeqCst)
+}
+
+/// Aborts every open transaction, for when the process is about to exit.
+/// From then on, calls made through a transaction fail, committing one
+/// rolls it back instead, and new transactions can't be started, so that
+/// whoever holds a transaction rolls it back at the next call they make.
+pub fn abort_transactions() {
+    ABORTING.store(true, Ordering::SeqCst);
+}
+
+fn aborted_error() -> Error {
+    Error::Unexpected("Transaction was aborted".to_string())
+}
+
+fn observe_transaction_call(method: &'static str, start: Instant) {
+    if let Some(observer) = *TRANSACTION_OBSERVER.read().unwrap() {
+        observer(method, start.elapsed());
+    }
+}
 
 /// This macro is used to proxy most methods.
 macro_rules! proxy_datastore {
@@ -47,34 +103,106 @@ impl Datastore<ProxyTransaction> for ProxyDatastore {
     }
 
     fn transaction(&self, account_id: Uuid) -> Result<ProxyTransaction, Error> {
+        if ABORTING.load(Ordering::SeqCst) {
+            return Err(aborted_error());
+        }
+
         match *self {
             ProxyDatastore::Postgres(ref pg) => {
                 let transaction = pg.transaction(account_id)?;
-                Ok(ProxyTransaction::Postgres(transaction))
+                Ok(ProxyTransaction::Postgres(transaction, OpenTransactionGuard::new()))
             }
             ProxyDatastore::Rocksdb(ref r) => {
                 let transaction = r.transaction(account_id)?;
-                Ok(ProxyTransaction::Rocksdb(transaction))
+                Ok(ProxyTransaction::Rocksdb(transaction, OpenTransactionGuard::new()))
             }
         }
     }
 }
 
+/// This macro is used to proxy most methods, notifying the transaction
+/// observer of each call. Calls fail once transactions are aborted.
 macro_rules! proxy_transaction {
     ($this: expr, $name:ident, $($arg:tt)*) => (
         {
-            match *$this {
-                ProxyTransaction::Postgres(ref pg) => pg.$name($($arg)*),
-                ProxyTransaction::Rocksdb(ref r) => r.$name($($arg)*)
+            if ABORTING.load(Ordering::SeqCst) {
+                return Err(aborted_error());
             }
+
+            let start = Instant::now();
+
+            let result = match *$this {
+                ProxyTransaction::Postgres(ref pg, _) => pg.$name($($arg)*),
+                ProxyTransaction::Rocksdb(ref r, _) => r.$name($($arg)*)
+            };
+
+            observe_transaction_call(stringify!($name), start);
+            result
         }
     )
 }
 
 #[derive(Debug)]
 pub enum ProxyTransaction {
-    Postgres(PostgresTransaction),
-    Rocksdb(RocksdbTransaction),
+    Postgres(PostgresTransaction, OpenTransactionGuard),
+    Rocksdb(RocksdbTransaction, OpenTransactionGuard),
+}
+
+/// Counts a transaction as open for as long as it is held.
+#[derive(Debug)]
+pub struct OpenTransactionGuard {}
+
+impl OpenTransactionGuard {
+    fn new() -> OpenTransactionGuard {
+        OPEN_TRANSACTIONS.fetch_add(1, Ordering::SeqCst);
+        OpenTransactionGuard {}
+    }
+}
+
+impl Drop for OpenTransactionGuard {
+    fn drop(&mut self) {
+        OPEN_TRANSACTIONS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ProxyTransaction {
+    /// Returns whether rolling the transaction back undoes its changes.
+    /// Rocksdb transactions apply changes as they're made, and can't be
+    /// rolled back.
+    pub fn can_roll_back(&self) -> bool {
+        match *self {
+            ProxyTransaction::Postgres(..) => true,
+            ProxyTransaction::Rocksdb(..) => false,
+        }
+    }
+
+    /// Takes a named lock that's shared by every server and tool using the
+    /// datastore, so that read-modify-write updates of metadata don't
+    /// overwrite each other.
+    ///
+    /// With postgres, this writes a global metadata entry for the lock,
+    /// whose row then stays locked until the transaction is committed or
+    /// rolled back, so it must be called before reading what's updated.
+    /// A rocksdb datastore can only be opened by one process, and applies
+    /// changes as they're made, so there the lock is instead held within
+    /// this process until the returned guard is dropped.
+    pub fn lock(&self, name: &str) -> Result<TransactionLock, Error> {
+        match *self {
+            ProxyTransaction::Postgres(ref trans, _) => {
+                trans.set_global_metadata(format!("{}{}", LOCK_KEY_PREFIX, name), JsonValue::Bool(true))?;
+                Ok(TransactionLock { _guard: None })
+            }
+            ProxyTransaction::Rocksdb(..) => {
+                let guard = LOCAL_LOCK.lock().unwrap();
+                Ok(TransactionLock { _guard: Some(guard) })
+            }
+        }
+    }
+}
+
+/// A lock taken by `ProxyTransaction::lock`.
+pub struct TransactionLock {
+    _guard: Option<MutexGuard<'static, ()>>,
 }
 
 impl Transaction for ProxyTransaction {
@@ -155,17 +283,32 @@ impl Transaction for ProxyTransaction {
     }
 
     fn commit(self) -> Result<(), Error> {
-        match self {
-            ProxyTransaction::Postgres(pg) => pg.commit(),
-            ProxyTransaction::Rocksdb(r) => r.commit(),
+        if ABORTING.load(Ordering::SeqCst) {
+            self.rollback()?;
+            return Err(aborted_error());
         }
+
+        let start = Instant::now();
+
+        let result = match self {
+            ProxyTransaction::Postgres(pg, _) => pg.commit(),
+            ProxyTransaction::Rocksdb(r, _) => r.commit(),
+        };
+
+        observe_transaction_call("commit", start);
+        result
     }
 
     fn rollback(self) -> Result<(), Error> {
-        match self {
-            ProxyTransaction::Postgres(pg) => pg.rollback(),
-            ProxyTransaction::Rocksdb(r) => r.rollback(),
-        }
+        let start = Instant::now();
+
+        let result = match self {
+            ProxyTransaction::Postgres(pg, _) => pg.rollback(),
+            ProxyTransaction::Rocksdb(r, _) => r.rollback(),
+        };
+
+        observe_transaction_call("rollback", start);
+        result
     }
 }
 
@@ -184,19 +327,7 @@ pub fn datastore() -> ProxyDatastore {
     let secure_uuids = env::var("BRAID_SECURE_UUIDS").unwrap_or_else(|_| "false".to_string()) == "true";
 
     if connection_string.starts_with("rocksdb://") {
-        let path = &connection_string[10..connection_string.len()];
-
-        let max_open_files_str = env::var("ROCKSDB_MAX_OPEN_FILES").unwrap_or_else(|_| "512".to_string());
-        let max_open_files = max_open_files_str.parse::<i32>()
-            .expect("Could not parse environment variable `ROCKSDB_MAX_OPEN_FILES`: must be an \
-                     i32");
-
-        let datastore = match RocksdbDatastore::new(path, Some(max_open_files), secure_uuids) {
-            Ok(datastore) => datastore,
-            Err(err) => panic!("Could not instantiate a rocksdb datastore: {:?}", err),
-        };
-
-        ProxyDatastore::Rocksdb(datastore)
+        rocksdb_datastore(&connection_string[10..connection_string.len()])
     } else if connection_string.starts_with("postgres://") {
         let pool_size = match env::var("DATABASE_POOL_SIZE") {
             Ok(str_val) => {
@@ -214,3 +345,21 @@ pub fn datastore() -> ProxyDatastore {
         panic!("Cannot parse environment variable `DATABASE_URL`");
     }
 }
+
+/// Creates a rocksdb datastore at the given path, regardless of
+/// `DATABASE_URL`. This is used for throwaway datastores, such as the ones
+/// that script tests run against.
+pub fn rocksdb_datastore(path: &str) -> ProxyDatastore {
+    let secure_uuids = env::var("BRAID_SECURE_UUIDS").unwrap_or_else(|_| "false".to_string()) == "true";
+    let max_open_files_str = env::var("ROCKSDB_MAX_OPEN_FILES").unwrap_or_else(|_| "512".to_string());
+    let max_open_files = max_open_files_str.parse::<i32>()
+        .expect("Could not parse environment variable `ROCKSDB_MAX_OPEN_FILES`: must be an \
+                 i32");
+
+    let datastore = match RocksdbDatastore::new(path, Some(max_open_files), secure_uuids) {
+        Ok(datastore) => datastore,
+        Err(err) => panic!("Could not instantiate a rocksdb datastore: {:?}", err),
+    };
+
+    ProxyDatastore::Rocksdb(datastore)
+}
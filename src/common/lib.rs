@@ -2,8 +2,26 @@ extern crate braid;
 extern crate uuid;
 extern crate serde_json;
 extern crate chrono;
+extern crate openssl;
+extern crate regex;
+extern crate rand;
+extern crate lua;
+extern crate libc;
+#[macro_use]
+extern crate lazy_static;
 
+mod accounts;
+mod audit;
 mod datastore;
 mod macros;
+mod scripts;
+pub mod script;
 
-pub use datastore::{ProxyDatastore, ProxyTransaction, datastore};
+pub use accounts::{AccountState, authenticate, create_account, delete_account, get_account_state,
+                   is_reserved_metadata_key, list_accounts, rotate_account_secret, secrets_match, set_account_disabled};
+pub use audit::{AuditEntry, AuditFilter, audit_log_enabled, query_audit_log, record_audit_entry};
+pub use scripts::{ScriptHistory, ScriptPromotion, ScriptScope, ScriptVersion, StoredScript, delete_stored_script,
+                  get_script_history, get_stored_script, is_valid_script_name, list_stored_scripts, promote_stored_script,
+                  put_stored_script, resolve_script_history, resolve_stored_script};
+pub use datastore::{OpenTransactionGuard, ProxyDatastore, ProxyTransaction, TransactionLock, TransactionObserver,
+                    abort_transactions, datastore, open_transactions, rocksdb_datastore, set_transaction_observer};
@@ -2,11 +2,14 @@
 // Above ignore is there because otherwise the macro is noisy
 
 use lua;
-use common::ProxyTransaction;
+use datastore::ProxyTransaction;
+use scripts::resolve_stored_script;
 use braid::{Transaction, EdgeKey};
+use std::cmp::min;
 use std::i32;
 use super::util::*;
 use super::errors::LuaError;
+use super::traversal;
 
 lua_fn! {
     pub unsafe fn create_vertex(trans: &mut ProxyTransaction, l: &mut lua::ExternState) -> Result<i32, LuaError> {
@@ -60,28 +63,28 @@ lua_fn! {
     }
 
     pub unsafe fn get_global_metadata(trans: &mut ProxyTransaction, l: &mut lua::ExternState) -> Result<i32, LuaError> {
-        let key = get_string_param(l, 1)?;
+        let key = get_metadata_key_param(l, 1)?;
         let result = trans.get_global_metadata(key)?;
         serialize_json(l, &result);
         Ok(1)
     }
 
     pub unsafe fn set_global_metadata(trans: &mut ProxyTransaction, l: &mut lua::ExternState) -> Result<i32, LuaError> {
-        let key = get_string_param(l, 1)?;
+        let key = get_metadata_key_param(l, 1)?;
         let value = deserialize_json(l, 2)?;
         trans.set_global_metadata(key, value)?;
         Ok(0)
     }
 
     pub unsafe fn delete_global_metadata(trans: &mut ProxyTransaction, l: &mut lua::ExternState) -> Result<i32, LuaError> {
-        let key = get_string_param(l, 1)?;
+        let key = get_metadata_key_param(l, 1)?;
         trans.delete_global_metadata(key)?;
         Ok(0)
     }
 
     pub unsafe fn get_account_metadata(trans: &mut ProxyTransaction, l: &mut lua::ExternState) -> Result<i32, LuaError> {
         let owner_id = get_uuid_param(l, 1)?;
-        let key = get_string_param(l, 2)?;
+        let key = get_metadata_key_param(l, 2)?;
         let result = trans.get_account_metadata(owner_id, key)?;
         serialize_json(l, &result);
         Ok(1)
@@ -89,7 +92,7 @@ lua_fn! {
 
     pub unsafe fn set_account_metadata(trans: &mut ProxyTransaction, l: &mut lua::ExternState) -> Result<i32, LuaError> {
         let owner_id = get_uuid_param(l, 1)?;
-        let key = get_string_param(l, 2)?;
+        let key = get_metadata_key_param(l, 2)?;
         let value = deserialize_json(l, 3)?;
         trans.set_account_metadata(owner_id, key, value)?;
         Ok(0)
@@ -97,7 +100,7 @@ lua_fn! {
 
     pub unsafe fn delete_account_metadata(trans: &mut ProxyTransaction, l: &mut lua::ExternState) -> Result<i32, LuaError> {
         let owner_id = get_uuid_param(l, 1)?;
-        let key = get_string_param(l, 2)?;
+        let key = get_metadata_key_param(l, 2)?;
         trans.delete_account_metadata(owner_id, key)?;
         Ok(0)
     }
@@ -180,4 +183,61 @@ lua_fn! {
         trans.delete_edge_metadata(q, key)?;
         Ok(0)
     }
+
+    pub unsafe fn neighbors(trans: &mut ProxyTransaction, l: &mut lua::ExternState) -> Result<i32, LuaError> {
+        let id = get_uuid_param(l, 1)?;
+        let t = get_optional_type_param(l, 2)?;
+        let direction = get_direction_param(l, 3)?;
+        let result = traversal::neighbors(trans, id, &t, direction)?;
+        serialize_vertices(l, result);
+        Ok(1)
+    }
+
+    /// Returns the vertices that were reached, followed by the edges that
+    /// they were reached by.
+    pub unsafe fn bfs(trans: &mut ProxyTransaction, l: &mut lua::ExternState) -> Result<i32, LuaError> {
+        let start_id = get_uuid_param(l, 1)?;
+        let t = get_optional_type_param(l, 2)?;
+        let direction = get_direction_param(l, 3)?;
+        let max_depth = get_optional_u32_param(l, 4)?.unwrap_or(traversal::DEFAULT_MAX_DEPTH);
+        let limit = get_optional_u32_param(l, 5)?.unwrap_or(traversal::DEFAULT_LIMIT);
+        let (max_depth, limit) = (min(max_depth, traversal::MAX_MAX_DEPTH), min(limit, traversal::MAX_LIMIT));
+        let (vertices, edges) = traversal::bfs(trans, start_id, &t, direction, max_depth, limit)?;
+        serialize_vertices(l, vertices);
+        serialize_edges(l, edges);
+        Ok(2)
+    }
+
+    /// Returns the edges of the path, or nil if there isn't one.
+    pub unsafe fn shortest_path(trans: &mut ProxyTransaction, l: &mut lua::ExternState) -> Result<i32, LuaError> {
+        let from_id = get_uuid_param(l, 1)?;
+        let to_id = get_uuid_param(l, 2)?;
+        let t = get_optional_type_param(l, 3)?;
+        let direction = get_direction_param(l, 4)?;
+        let max_depth = min(get_optional_u32_param(l, 5)?.unwrap_or(traversal::DEFAULT_MAX_DEPTH), traversal::MAX_MAX_DEPTH);
+
+        match traversal::shortest_path(trans, from_id, to_id, &t, direction, max_depth)? {
+            Some(path) => serialize_edges(l, path),
+            None => l.pushnil(),
+        }
+
+        Ok(1)
+    }
+
+    /// Gets the source of the current version of the stored script that a
+    /// name refers to for the running account, or nil if there isn't one.
+    /// This isn't exposed to scripts, but is used by the sandbox's `require`.
+    pub unsafe fn load_stored_script(trans: &mut ProxyTransaction, l: &mut lua::ExternState) -> Result<i32, LuaError> {
+        let name = get_string_param(l, 1)?;
+        l.getglobal("account_id");
+        let account_id = get_uuid_param(l, -1)?;
+        l.pop(1);
+
+        match resolve_stored_script(trans, account_id, &name[..], None)? {
+            Some(script) => l.pushstring(&script.source[..]),
+            None => l.pushnil(),
+        }
+
+        Ok(1)
+    }
 }
@@ -2,6 +2,9 @@ use lua;
 use braid::{Error, ValidationError};
 use std::i32;
 
+/// The message of memory errors that weren't caused by a script's limit.
+const OUT_OF_MEMORY_MESSAGE: &'static str = "Not enough memory";
+
 /// Error that is returnable from lua-exposed functions.
 ///
 /// The `lua_fn!` macro takes these errors and serializes them appropriately
@@ -37,9 +40,11 @@ impl From<ValidationError> for LuaError {
 #[derive(Debug)]
 pub enum ScriptError {
     Syntax(String),
-    Memory,
+    Memory(String),
     Runtime(String),
     Panicked(String),
+    Timeout(String),
+    Forbidden(String),
     File
 }
 
@@ -50,7 +55,7 @@ impl ScriptError {
                 ScriptError::Syntax(String::from(state.checkstring(-1).unwrap()))
             },
             lua::LoadFileError::ErrMem => {
-                ScriptError::Memory
+                ScriptError::Memory(OUT_OF_MEMORY_MESSAGE.to_string())
             },
             lua::LoadFileError::ErrFile => {
                 ScriptError::File
@@ -58,12 +63,23 @@ impl ScriptError {
         }
     }
 
+    pub fn new_from_load_error(state: &mut lua::State, err: lua::LoadError) -> ScriptError {
+        match err {
+            lua::LoadError::ErrSyntax => {
+                ScriptError::Syntax(String::from(state.checkstring(-1).unwrap()))
+            },
+            lua::LoadError::ErrMem => {
+                ScriptError::Memory(OUT_OF_MEMORY_MESSAGE.to_string())
+            }
+        }
+    }
+
     pub fn new_from_pcallerror(state: &mut lua::State, err: lua::PCallError) -> ScriptError {
         match err {
             lua::PCallError::ErrRun => {
                 ScriptError::Runtime(String::from(state.checkstring(-1).unwrap()))
             }
-            lua::PCallError::ErrMem => ScriptError::Memory,
+            lua::PCallError::ErrMem => ScriptError::Memory(OUT_OF_MEMORY_MESSAGE.to_string()),
             lua::PCallError::ErrErr => ScriptError::Panicked("Unknown pcall error".to_string()),
         }
     }
@@ -0,0 +1,62 @@
+-- Snapshots the globals of a lua state, and returns a function that restores
+-- them. This runs once per lua state, after it has been prepared, so that
+-- whatever a run changes in the globals, the libraries they hold, or the
+-- string metatable can be undone before the state is reused.
+--
+-- Scripts can reach the real globals even when they run in their own
+-- environment, e.g. through the `__index` of an unsandboxed environment, or
+-- through `debug`, so the per-run environment alone isn't enough.
+
+local next, pairs, rawset, type = next, pairs, rawset, type
+local getmetatable, setmetatable = debug.getmetatable, debug.setmetatable
+
+local function snapshot(t)
+    local c = {}
+
+    for k, v in next, t do
+        c[k] = v
+    end
+
+    return {fields = c, metatable = getmetatable(t)}
+end
+
+local function restore(t, saved)
+    setmetatable(t, saved.metatable)
+
+    -- Clearing fields while traversing a table is allowed, but adding them
+    -- isn't, so this is done in two passes.
+    for k in next, t do
+        if saved.fields[k] == nil then
+            rawset(t, k, nil)
+        end
+    end
+
+    for k, v in next, saved.fields do
+        rawset(t, k, v)
+    end
+end
+
+local tables = {}
+tables[_G] = snapshot(_G)
+
+for _, value in next, _G do
+    if type(value) == "table" and tables[value] == nil then
+        tables[value] = snapshot(value)
+    end
+end
+
+-- Modules that are loaded during a run are dropped along with the rest.
+for _, value in next, {package.loaded, package.loaders, package.preload} do
+    tables[value] = snapshot(value)
+end
+
+local string_metatable = getmetatable("")
+tables[string_metatable] = snapshot(string_metatable)
+
+return function()
+    setmetatable("", string_metatable)
+
+    for t, saved in pairs(tables) do
+        restore(t, saved)
+    end
+end
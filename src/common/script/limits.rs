@@ -0,0 +1,123 @@
+//! Enforces the time and instruction limits of scripts. Every lua state has a
+//! count hook installed that checks the limits of the script being run by the
+//! current thread, and raises an error once one has been exceeded.
+//!
+//! Scripts that catch the error with `pcall` are interrupted again on the
+//! next check, and are reported as timed out even if they go on to finish.
+//! Limits are not checked while a script is waiting on the datastore, except
+//! for the time limit, which traversals check between steps.
+
+use lua;
+use lua::raw::{lua_State, lua_Debug};
+use std::cell::RefCell;
+use std::time::Instant;
+use super::settings::ScriptSettings;
+
+/// How many instructions are run between checks of the limits.
+const CHECK_INTERVAL: i32 = 1000;
+
+struct RunLimits {
+    deadline: Instant,
+    max_instructions: Option<u64>,
+    instructions: u64,
+    exceeded: Option<&'static str>,
+}
+
+thread_local! {
+    static LIMITS: RefCell<Option<RunLimits>> = RefCell::new(None);
+}
+
+/// Installs the hook that checks limits.
+pub fn install_hook(l: &mut lua::State) {
+    unsafe {
+        l.sethook(check_limits, lua::raw::LUA_MASKCOUNT, CHECK_INTERVAL);
+    }
+}
+
+/// Enforces the limits of a script on the current thread, until the returned
+/// guard is dropped.
+pub fn enforce(settings: &ScriptSettings) -> LimitsGuard {
+    LIMITS.with(|limits| {
+        *limits.borrow_mut() = Some(RunLimits {
+            deadline: Instant::now() + settings.timeout,
+            max_instructions: settings.max_instructions,
+            instructions: 0,
+            exceeded: None,
+        });
+    });
+
+    LimitsGuard {}
+}
+
+/// Stops enforcing limits on the current thread when dropped.
+pub struct LimitsGuard {}
+
+impl LimitsGuard {
+    /// Returns a description of the limit that the script exceeded, if any.
+    pub fn exceeded(&self) -> Option<&'static str> {
+        LIMITS.with(|limits| limits.borrow().as_ref().and_then(|limits| limits.exceeded))
+    }
+}
+
+impl Drop for LimitsGuard {
+    fn drop(&mut self) {
+        LIMITS.with(|limits| *limits.borrow_mut() = None);
+    }
+}
+
+/// Checks the time limit of the script being run by the current thread, for
+/// API functions that make many datastore calls without running lua code.
+///
+/// # Errors
+/// Returns a description of the limit if it has been exceeded.
+pub fn check_deadline() -> Result<(), &'static str> {
+    LIMITS.with(|limits| {
+        let mut limits = limits.borrow_mut();
+
+        let limits = match *limits {
+            Some(ref mut limits) => limits,
+            None => return Ok(()),
+        };
+
+        if limits.exceeded.is_none() && Instant::now() > limits.deadline {
+            limits.exceeded = Some("Script exceeded its time limit");
+        }
+
+        match limits.exceeded {
+            Some(message) => Err(message),
+            None => Ok(()),
+        }
+    })
+}
+
+extern "C" fn check_limits(l: *mut lua_State, _: *mut lua_Debug) {
+    let exceeded = LIMITS.with(|limits| {
+        let mut limits = limits.borrow_mut();
+
+        let limits = match *limits {
+            Some(ref mut limits) => limits,
+            None => return None,
+        };
+
+        limits.instructions += CHECK_INTERVAL as u64;
+
+        if limits.exceeded.is_none() {
+            if Instant::now() > limits.deadline {
+                limits.exceeded = Some("Script exceeded its time limit");
+            } else if limits.max_instructions.map_or(false, |max| limits.instructions > max) {
+                limits.exceeded = Some("Script exceeded its instruction limit");
+            }
+        }
+
+        limits.exceeded
+    });
+
+    // Raising the error unwinds past this frame, so nothing that needs to be
+    // dropped can be alive here.
+    if let Some(message) = exceeded {
+        unsafe {
+            let mut l = lua::ExternState::from_lua_State(l);
+            l.errorstr(message);
+        }
+    }
+}
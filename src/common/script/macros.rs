@@ -0,0 +1,58 @@
+/// Convenience macro for exposing functions that are callable from lua. This
+/// automatically passes in the global `trans` parameter, and serializes a
+/// / `Result` to lua, so that rust's error handling sugar (try!) can be used.
+///
+/// The function body is run with `pcall`, with the memory limit lifted, so
+/// that allocations can't fail while rust code is running. Once it's done,
+/// the limit is checked, and errors are raised again.
+macro_rules! lua_fn {
+    ($($(#[$attr:meta])* pub unsafe fn $name:ident($targ:ident: &mut ProxyTransaction, $larg:ident: &mut $typ:ty) -> Result<i32, LuaError> $code:block)+) => (
+        $(
+            $(#[$attr])*
+            pub unsafe extern "C" fn $name(l: *mut ::lua::raw::lua_State) -> ::libc::c_int {
+                let mut state = ::lua::ExternState::from_lua_State(l);
+
+                let result = {
+                    let _memory = ::script::memory::suspend_limit(l);
+                    let nargs = state.gettop();
+                    state.pushcfunction(protected);
+                    state.insert(1);
+                    state.pcall(nargs, ::lua::MULTRET, 0)
+                };
+
+                if !::script::memory::within_limit(l) {
+                    state.errorstr("not enough memory");
+                }
+
+                return match result {
+                    Ok(()) => state.gettop(),
+                    Err(_) => state.error(),
+                } as ::libc::c_int;
+
+                unsafe extern "C" fn protected($larg: *mut ::lua::raw::lua_State) -> ::libc::c_int {
+                    let mut $larg = &mut ::lua::ExternState::from_lua_State($larg);
+
+                    $larg.getglobal("trans");
+
+                    if !$larg.islightuserdata(-1) {
+                        $larg.errorstr("Corrupted transaction");
+                        return 1;
+                    }
+
+                    let trans_ptr = $larg.touserdata(-1);
+                    let $targ = &mut **(trans_ptr as *mut &mut ProxyTransaction);
+
+                    return match inner($targ, &mut $larg) {
+                        Ok(i) => i,
+                        Err(err) => {
+                            err.serialize($larg);
+                            1
+                        }
+                    } as ::libc::c_int;
+                }
+
+                unsafe fn inner($targ: &mut ProxyTransaction, $larg: &mut $typ) -> Result<i32, LuaError> $code
+            }
+        )+
+    )
+}
@@ -0,0 +1,162 @@
+//! Tracks the memory used by lua states, so that scripts can be held to a
+//! memory limit. Each state gets an allocator that counts the bytes it has
+//! in use, and refuses allocations that would take a running script past its
+//! limit. Lua reports refused allocations as memory errors.
+//!
+//! Memory errors are raised with a `longjmp`, which would skip over the
+//! frames of rust code that allocates through lua, so the limit is lifted
+//! while API functions run. Whatever they allocate is checked against the
+//! limit once they return.
+
+use libc;
+use lua;
+use lua::raw::lua_State;
+use std::cmp::max;
+use std::ptr;
+
+/// Counts the memory in use by a lua state.
+pub struct MemoryTracker {
+    allocated: usize,
+
+    /// The most memory in use since the limit was set
+    peak: usize,
+
+    /// The memory in use when the limit was set
+    baseline: usize,
+
+    /// The most memory that may be in use, if limited
+    limit: Option<usize>,
+
+    /// Whether an allocation was refused since the limit was set
+    exceeded: bool,
+
+    /// How many API functions are running, with the limit lifted
+    suspended: u32,
+}
+
+impl MemoryTracker {
+    /// Installs a tracking allocator in a state. The returned tracker must
+    /// outlive the state, since the state keeps calling the allocator until
+    /// it is closed.
+    pub fn install(l: &mut lua::State) -> Box<MemoryTracker> {
+        let allocated = l.gc(lua::GCOption::Count, 0) as usize * 1024 + l.gc(lua::GCOption::CountB, 0) as usize;
+
+        let mut tracker = Box::new(MemoryTracker {
+            allocated: allocated,
+            peak: allocated,
+            baseline: allocated,
+            limit: None,
+            exceeded: false,
+            suspended: 0,
+        });
+
+        unsafe {
+            let ud = &mut *tracker as *mut MemoryTracker as *mut libc::c_void;
+            l.setallocf(allocate, ud);
+        }
+
+        tracker
+    }
+
+    /// Limits how much more memory can be allocated, on top of what is
+    /// already in use.
+    pub fn set_limit(&mut self, max_memory: Option<usize>) {
+        self.baseline = self.allocated;
+        self.peak = self.allocated;
+        self.limit = max_memory.map(|max_memory| self.allocated + max_memory);
+        self.exceeded = false;
+    }
+
+    /// Lifts the limit. Returns a description of the limit and the peak
+    /// usage if the limit was exceeded.
+    pub fn clear_limit(&mut self) -> Option<String> {
+        let limit = self.limit.take();
+
+        match limit {
+            Some(limit) if self.exceeded => {
+                Some(format!(
+                    "Script exceeded its memory limit of {} bytes (peak usage: {} bytes)",
+                    limit - self.baseline,
+                    self.peak - self.baseline
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Lifts the limit of a state until the returned guard is dropped.
+///
+/// This must only be called with pooled states, since the tracker is found
+/// through the state's allocator.
+pub unsafe fn suspend_limit(l: *mut lua_State) -> SuspendedLimit {
+    let tracker = get_tracker(l);
+    (*tracker).suspended += 1;
+    SuspendedLimit { tracker: tracker }
+}
+
+/// Lifts the limit of a state for as long as it's alive.
+pub struct SuspendedLimit {
+    tracker: *mut MemoryTracker,
+}
+
+impl Drop for SuspendedLimit {
+    fn drop(&mut self) {
+        unsafe {
+            (*self.tracker).suspended -= 1;
+        }
+    }
+}
+
+/// Returns whether a state has no more memory in use than its limit allows.
+/// A state can go over while its limit is lifted, in which case the limit is
+/// considered exceeded, as if an allocation had been refused.
+pub unsafe fn within_limit(l: *mut lua_State) -> bool {
+    let tracker = &mut *get_tracker(l);
+
+    match tracker.limit {
+        Some(limit) if tracker.suspended == 0 && tracker.allocated > limit => {
+            tracker.exceeded = true;
+            false
+        }
+        _ => true,
+    }
+}
+
+unsafe fn get_tracker(l: *mut lua_State) -> *mut MemoryTracker {
+    let mut ud: *mut libc::c_void = ptr::null_mut();
+    lua::raw::lua_getallocf(l, &mut ud);
+    ud as *mut MemoryTracker
+}
+
+extern "C" fn allocate(ud: *mut libc::c_void, p: *mut libc::c_void, osize: libc::size_t, nsize: libc::size_t) -> *mut libc::c_void {
+    let tracker = unsafe { &mut *(ud as *mut MemoryTracker) };
+
+    // Lua passes an `osize` of 0 for new blocks, so the old size can always
+    // be subtracted.
+    if nsize == 0 {
+        unsafe { libc::free(p) };
+        tracker.allocated -= osize as usize;
+        return ptr::null_mut();
+    }
+
+    let allocated = tracker.allocated - osize as usize + nsize as usize;
+
+    // Lua assumes that shrinking a block never fails, so only growth is
+    // checked against the limit.
+    if let Some(limit) = tracker.limit {
+        if nsize > osize && allocated > limit && tracker.suspended == 0 {
+            tracker.exceeded = true;
+            return ptr::null_mut();
+        }
+    }
+
+    let new_p = unsafe { libc::realloc(p, nsize) };
+
+    if !new_p.is_null() {
+        tracker.allocated = allocated;
+        tracker.peak = max(tracker.peak, allocated);
+    }
+
+    new_p
+}
@@ -0,0 +1,252 @@
+#[macro_use]
+mod macros;
+mod api;
+mod errors;
+mod limits;
+mod memory;
+mod output;
+mod pool;
+mod sandbox;
+mod settings;
+mod traversal;
+mod util;
+
+use lua;
+use libc;
+use serde_json::value::Value as JsonValue;
+use datastore::ProxyTransaction;
+use braid::Error;
+use scripts::{StoredScript, resolve_stored_script};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use uuid::Uuid;
+pub use self::errors::ScriptError;
+pub use self::output::{ScriptLogEntry, ScriptLogLevel};
+pub use self::settings::{SCRIPT_ROOT, ScriptSettings};
+use self::pool::PreparedState;
+
+/// The first byte of precompiled lua chunks.
+const BYTECODE_SIGNATURE: u8 = 0x1b;
+
+/// The error message given for precompiled scripts.
+const BYTECODE_ERROR_MESSAGE: &'static str = "Precompiled scripts are not allowed";
+
+/// Something that happened while preparing or running scripts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScriptEvent {
+    /// A script's compiled chunk was reused
+    CacheHit,
+
+    /// A script had to be compiled
+    CacheMiss,
+
+    /// A new lua state was prepared
+    StateCreated,
+}
+
+/// A function that is notified of script events.
+pub type ScriptObserver = fn(ScriptEvent);
+
+lazy_static! {
+    static ref SCRIPT_OBSERVER: RwLock<Option<ScriptObserver>> = RwLock::new(None);
+}
+
+/// Sets the function that is notified of script events, replacing any
+/// previously set one.
+pub fn set_script_observer(observer: ScriptObserver) {
+    *SCRIPT_OBSERVER.write().unwrap() = Some(observer);
+}
+
+fn observe_script_event(event: ScriptEvent) {
+    if let Some(observer) = *SCRIPT_OBSERVER.read().unwrap() {
+        observer(event);
+    }
+}
+
+/// Where the code of a script comes from.
+pub enum ScriptSource {
+    /// A file under the script root
+    File(PathBuf),
+
+    /// A script stored in the datastore
+    Stored(StoredScript),
+}
+
+impl ScriptSource {
+    /// Gets the settings to run the script with. Per-script settings only
+    /// apply to files under the script root: they're keyed by name, and any
+    /// account can store a script under any name, so stored scripts always
+    /// run with the defaults.
+    pub fn settings(&self, name: &str) -> ScriptSettings {
+        match *self {
+            ScriptSource::File(_) => ScriptSettings::for_script(name),
+            ScriptSource::Stored(_) => ScriptSettings::default(),
+        }
+    }
+}
+
+/// Finds the code of the script that a name refers to for an account. Scripts
+/// stored in the datastore take precedence over files under the script root.
+/// If a version is given, only that version of a stored script is found, and
+/// `None` is returned if there isn't one.
+pub fn find_source(trans: &ProxyTransaction, account_id: Uuid, name: &str, version: Option<u32>) -> Result<Option<ScriptSource>, Error> {
+    match resolve_stored_script(trans, account_id, name, version)? {
+        Some(script) => Ok(Some(ScriptSource::Stored(script))),
+        None if version.is_some() => Ok(None),
+        None => Ok(Some(ScriptSource::File(Path::new(&SCRIPT_ROOT[..]).join(name)))),
+    }
+}
+
+/// Returns whether a script source is a precompiled lua chunk, rather than
+/// source code. Lua loads these too, but malformed bytecode can crash the
+/// interpreter, so stored scripts can't be precompiled.
+pub fn is_bytecode(source: &[u8]) -> bool {
+    source.first() == Some(&BYTECODE_SIGNATURE)
+}
+
+/// Checks that a script compiles, without running it.
+///
+/// # Errors
+/// Returns a `ScriptError::Syntax` describing the first syntax error, or
+/// saying that the script is precompiled.
+pub fn check_syntax(name: &str, source: &str) -> Result<(), ScriptError> {
+    if is_bytecode(source.as_bytes()) {
+        return Err(ScriptError::Syntax(BYTECODE_ERROR_MESSAGE.to_string()));
+    }
+
+    let mut l = lua::State::new();
+    let chunk_name = format!("={}", name);
+
+    match l.loadbuffer(source, &chunk_name[..]) {
+        Ok(()) => Ok(()),
+        Err(err) => Err(ScriptError::new_from_load_error(&mut l, err)),
+    }
+}
+
+/// Runs a script. What the script prints or logs is added to `log`, whether
+/// or not it succeeds.
+///
+/// # Errors
+/// Returns an error if the script produced an error, or a
+/// `ScriptError::Forbidden` if a stored script would run outside of the
+/// sandbox.
+///
+/// # Panics
+/// We try to avoid panics, but there is a lot of unsafe code here.
+pub fn run(mut trans: &ProxyTransaction,
+           account_id: Uuid,
+           source: &ScriptSource,
+           arg: &JsonValue,
+           settings: &ScriptSettings,
+           log: &mut Vec<ScriptLogEntry>)
+           -> Result<JsonValue, ScriptError> {
+    // Stored scripts are uploaded by accounts, so they're never trusted with
+    // access to the host.
+    if let ScriptSource::Stored(_) = *source {
+        if !settings.sandbox || !settings.libraries.is_empty() {
+            return Err(ScriptError::Forbidden("Stored scripts can only be run in the sandbox".to_string()));
+        }
+    }
+
+    let mut state = pool::checkout();
+    let capture = output::capture();
+    let result = run_in_state(&mut state, &mut trans, account_id, source, arg, settings);
+    log.extend(capture.finish());
+
+    // A state that ran out of memory or failed in its error handler may be
+    // in a bad way, so it isn't reused.
+    match result {
+        Err(ScriptError::Memory(_)) | Err(ScriptError::Panicked(_)) => (),
+        _ => pool::checkin(state),
+    }
+
+    result
+}
+
+/// Loads the script settings, and checks that each script's libraries
+/// exist, so that mistakes in the settings are found when the server starts
+/// rather than when a script is first run.
+///
+/// # Errors
+/// Returns a description of the first script with unknown libraries.
+///
+/// # Panics
+/// Panics if the settings can't be loaded.
+pub fn validate_settings() -> Result<(), String> {
+    let mut state = pool::checkout();
+
+    for (name, settings) in ScriptSettings::all() {
+        if let Err(err) = state.check_libraries(&settings.libraries) {
+            return Err(format!("invalid settings for `{}`: {:?}", name, err));
+        }
+    }
+
+    pool::checkin(state);
+    Ok(())
+}
+
+fn run_in_state(state: &mut PreparedState,
+                trans: &mut &ProxyTransaction,
+                account_id: Uuid,
+                source: &ScriptSource,
+                arg: &JsonValue,
+                settings: &ScriptSettings)
+                -> Result<JsonValue, ScriptError> {
+    state.load_chunk(source)?;
+    state.push_environment(settings)?;
+
+    let l = &mut state.l;
+
+    // Add the transaction as a global variable.
+    {
+        let trans_ptr: *mut libc::c_void = trans as *mut _ as *mut libc::c_void;
+        l.pushlightuserdata(trans_ptr);
+        l.setglobal("trans");
+    }
+
+    // Add the account id as a global variable. It's also set in the
+    // environment, since sandboxed scripts can't see the globals.
+    {
+        l.pushstring(&account_id.to_string()[..]);
+        l.pushvalue(-1);
+        l.setglobal("account_id");
+        l.setfield(-2, "account_id");
+    }
+
+    // Add the input arg as a global variable, likewise.
+    {
+        unsafe {
+            util::serialize_json(l.as_extern(), &arg);
+        }
+        l.pushvalue(-1);
+        l.setglobal("arg");
+        l.setfield(-2, "arg");
+    }
+
+    l.setfenv(-2);
+
+    // The memory limit only applies while the script is running, since
+    // allocation failures outside of `pcall` aren't recoverable.
+    let limits = limits::enforce(settings);
+    state.memory.set_limit(settings.max_memory);
+    let result = l.pcall(0, lua::MULTRET, 0);
+
+    if let Some(message) = state.memory.clear_limit() {
+        return Err(ScriptError::Memory(message));
+    }
+
+    if let Some(message) = limits.exceeded() {
+        return Err(ScriptError::Timeout(message.to_string()));
+    }
+
+    if let Err(err) = result {
+        return Err(ScriptError::new_from_pcallerror(l, err));
+    }
+
+    if l.gettop() == 0 {
+        Ok(JsonValue::Null)
+    } else {
+        let payload = unsafe { util::deserialize_json(l.as_extern(), -1)? };
+        Ok(payload)
+    }
+}
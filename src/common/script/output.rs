@@ -0,0 +1,223 @@
+//! Captures what scripts print and log. `print` and the `log` functions don't
+//! write anywhere themselves; entries are collected for the run that's in
+//! progress on the current thread, and handed back to the caller of `run`,
+//! which knows where they should go.
+//!
+//! Each run keeps at most `MAX_ENTRIES` entries of at most
+//! `MAX_MESSAGE_LENGTH` bytes, so a chatty script can't use up the server's
+//! memory outside of its own memory limit.
+
+use lua;
+use lua::raw::lua_State;
+use libc::c_int;
+use serde_json;
+use serde_json::value::Value as JsonValue;
+use std::cell::RefCell;
+
+/// The maximum number of entries kept per run.
+const MAX_ENTRIES: usize = 1000;
+
+/// The maximum length of a message, in bytes. Longer ones are truncated.
+const MAX_MESSAGE_LENGTH: usize = 4096;
+
+/// The functions of the `log` table.
+const LOG_FUNCTIONS: &'static [(&'static str, lua::CFunction)] = &[
+    ("debug", log_debug),
+    ("info", log_info),
+    ("warn", log_warn),
+    ("error", log_error),
+];
+
+/// The severity of something a script logged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScriptLogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl ScriptLogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ScriptLogLevel::Debug => "debug",
+            ScriptLogLevel::Info => "info",
+            ScriptLogLevel::Warn => "warn",
+            ScriptLogLevel::Error => "error",
+        }
+    }
+}
+
+/// Something a script printed or logged. Printed messages are logged at the
+/// info level.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScriptLogEntry {
+    pub level: ScriptLogLevel,
+    pub message: String,
+}
+
+impl ScriptLogEntry {
+    pub fn to_json(&self) -> JsonValue {
+        let mut o: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+        o.insert("level".to_string(), JsonValue::String(self.level.as_str().to_string()));
+        o.insert("message".to_string(), JsonValue::String(self.message.clone()));
+        JsonValue::Object(o)
+    }
+}
+
+struct Capture {
+    entries: Vec<ScriptLogEntry>,
+    dropped: usize,
+}
+
+thread_local! {
+    static CAPTURE: RefCell<Option<Capture>> = RefCell::new(None);
+}
+
+/// Collects entries for as long as it's alive.
+pub struct CaptureGuard {}
+
+impl CaptureGuard {
+    /// Stops capturing, returning what was captured.
+    pub fn finish(self) -> Vec<ScriptLogEntry> {
+        let capture = CAPTURE.with(|capture| capture.borrow_mut().take());
+
+        match capture {
+            Some(mut capture) => {
+                if capture.dropped > 0 {
+                    capture.entries.push(ScriptLogEntry {
+                        level: ScriptLogLevel::Warn,
+                        message: format!("{} more log entries were dropped", capture.dropped),
+                    });
+                }
+
+                capture.entries
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Drop for CaptureGuard {
+    fn drop(&mut self) {
+        CAPTURE.with(|capture| *capture.borrow_mut() = None);
+    }
+}
+
+/// Starts capturing entries for a run on the current thread.
+pub fn capture() -> CaptureGuard {
+    CAPTURE.with(|capture| {
+        *capture.borrow_mut() = Some(Capture {
+            entries: Vec::new(),
+            dropped: 0,
+        });
+    });
+
+    CaptureGuard {}
+}
+
+/// Replaces `print`, and adds the `log` table to the globals. This should be
+/// done before the sandbox is installed, so sandboxes pick them up.
+pub fn install(l: &mut lua::State) {
+    l.register("print", print);
+    l.newtable();
+
+    for &(name, f) in LOG_FUNCTIONS {
+        l.pushcfunction(f);
+        l.setfield(-2, name);
+    }
+
+    l.setglobal("log");
+}
+
+fn record(level: ScriptLogLevel, mut message: String) {
+    if message.len() > MAX_MESSAGE_LENGTH {
+        let mut end = MAX_MESSAGE_LENGTH;
+
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        message.truncate(end);
+    }
+
+    CAPTURE.with(|capture| {
+        if let Some(ref mut capture) = *capture.borrow_mut() {
+            if capture.entries.len() < MAX_ENTRIES {
+                capture.entries.push(ScriptLogEntry {
+                    level: level,
+                    message: message,
+                });
+            } else {
+                capture.dropped += 1;
+            }
+        }
+    });
+}
+
+/// Converts the arguments of the running function to strings with
+/// `tostring`, and joins them with tabs, the way the standard `print` does.
+///
+/// `tostring` can run a script's `__tostring`, so it's called with `pcall`.
+/// If it fails, this returns an error with no message, and leaves the error
+/// on top of the stack. If it returns something other than a string, the
+/// error has a message. Either way, the caller raises the error, once
+/// nothing that needs to be dropped is alive.
+unsafe fn get_message(l: &mut lua::ExternState) -> Result<String, Option<&'static str>> {
+    let n = l.gettop();
+    let mut parts: Vec<String> = Vec::new();
+    l.getglobal("tostring");
+
+    for i in 1..(n + 1) {
+        l.pushvalue(-1);
+        l.pushvalue(i);
+
+        if l.pcall(1, 1, 0).is_err() {
+            return Err(None);
+        }
+
+        if l.type_(-1) != Some(lua::Type::String) {
+            return Err(Some("`tostring` must return a string"));
+        }
+
+        parts.push(l.tostring(-1).unwrap_or("").to_string());
+        l.pop(1);
+    }
+
+    l.pop(1);
+    Ok(parts.join("\t"))
+}
+
+unsafe fn log_with_level(l: *mut lua_State, level: ScriptLogLevel) -> c_int {
+    let mut l = lua::ExternState::from_lua_State(l);
+
+    // Raising an error unwinds past this frame, so it's only done once
+    // nothing that needs to be dropped is alive.
+    match get_message(&mut l) {
+        Ok(message) => record(level, message),
+        Err(None) => l.error(),
+        Err(Some(message)) => l.errorstr(message),
+    }
+
+    0
+}
+
+unsafe extern "C" fn print(l: *mut lua_State) -> c_int {
+    log_with_level(l, ScriptLogLevel::Info)
+}
+
+unsafe extern "C" fn log_debug(l: *mut lua_State) -> c_int {
+    log_with_level(l, ScriptLogLevel::Debug)
+}
+
+unsafe extern "C" fn log_info(l: *mut lua_State) -> c_int {
+    log_with_level(l, ScriptLogLevel::Info)
+}
+
+unsafe extern "C" fn log_warn(l: *mut lua_State) -> c_int {
+    log_with_level(l, ScriptLogLevel::Warn)
+}
+
+unsafe extern "C" fn log_error(l: *mut lua_State) -> c_int {
+    log_with_level(l, ScriptLogLevel::Error)
+}
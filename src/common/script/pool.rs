@@ -0,0 +1,266 @@
+//! This module keeps a pool of prepared lua states, so that scripts don't pay
+//! for creating a state, opening the standard libraries and registering the
+//! API on every run. Each state also caches the chunks it has compiled, keyed
+//! by revision, so unchanged scripts are not reparsed.
+//!
+//! States are reset between runs:
+//!
+//! * Scripts run with a fresh environment table, either a sandbox or one
+//!   that falls back to the globals, so globals they set are discarded
+//!   afterwards.
+//! * The globals, the library tables they hold and the string metatable are
+//!   restored to how they were when the state was prepared, since scripts
+//!   can reach them through their environment. This also unloads modules
+//!   loaded with `require`, and clears the `trans`, `account_id` and `arg`
+//!   globals.
+//!
+//! States that can't be restored are dropped rather than reused.
+
+use lua;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use super::{api, ScriptEvent, ScriptSource, BYTECODE_ERROR_MESSAGE, is_bytecode, observe_script_event};
+use super::errors::ScriptError;
+use super::limits;
+use super::memory::MemoryTracker;
+use super::output;
+use super::sandbox;
+use super::settings::{SCRIPT_ROOT, ScriptSettings};
+
+/// The registry field holding the table of compiled chunks, keyed by path.
+const CHUNKS_REGISTRY_KEY: &'static str = "braid_chunks";
+
+/// The functions exposed to scripts.
+const API_FUNCTIONS: &'static [(&'static str, lua::CFunction)] = &[
+    ("create_vertex", api::create_vertex),
+    ("get_vertices", api::get_vertices),
+    ("delete_vertices", api::delete_vertices),
+
+    ("create_edge", api::create_edge),
+    ("get_edges", api::get_edges),
+    ("delete_edges", api::delete_edges),
+    ("get_edge_count", api::get_edge_count),
+
+    ("get_global_metadata", api::get_global_metadata),
+    ("set_global_metadata", api::set_global_metadata),
+    ("delete_global_metadata", api::delete_global_metadata),
+    ("get_account_metadata", api::get_account_metadata),
+    ("set_account_metadata", api::set_account_metadata),
+    ("delete_account_metadata", api::delete_account_metadata),
+    ("get_vertex_metadata", api::get_vertex_metadata),
+    ("set_vertex_metadata", api::set_vertex_metadata),
+    ("delete_vertex_metadata", api::delete_vertex_metadata),
+    ("get_edge_metadata", api::get_edge_metadata),
+    ("set_edge_metadata", api::set_edge_metadata),
+    ("delete_edge_metadata", api::delete_edge_metadata),
+
+    ("neighbors", api::neighbors),
+    ("bfs", api::bfs),
+    ("shortest_path", api::shortest_path),
+];
+
+/// The registry field holding the function that restores the globals.
+const RESTORE_REGISTRY_KEY: &'static str = "braid_restore_globals";
+
+const GLOBALS_SOURCE: &'static str = include_str!("globals.lua");
+
+lazy_static! {
+    /// The maximum number of idle lua states kept for reuse
+    static ref POOL_SIZE: usize = match env::var("BRAID_SCRIPT_POOL_SIZE") {
+        Ok(s) => s.parse().expect("Could not parse environment variable `BRAID_SCRIPT_POOL_SIZE`: must be a usize"),
+        Err(_) => 16,
+    };
+
+    static ref POOL: Mutex<Vec<PreparedState>> = Mutex::new(Vec::new());
+}
+
+/// A lua state with the standard libraries opened and the API registered.
+pub struct PreparedState {
+    pub l: lua::State,
+
+    /// Counts the memory used by the state. This is declared after the
+    /// state so that it's dropped after the state is closed.
+    pub memory: Box<MemoryTracker>,
+
+    /// The revisions of the scripts whose chunks are cached, keyed by the
+    /// same keys as the chunks
+    chunk_revisions: HashMap<String, String>,
+}
+
+// Lua states aren't thread-safe, but a pooled state is only ever used by the
+// thread that checked it out.
+unsafe impl Send for PreparedState {}
+
+impl PreparedState {
+    fn new() -> PreparedState {
+        let mut l = lua::State::new();
+        let memory = MemoryTracker::install(&mut l);
+        l.openlibs();
+        output::install(&mut l);
+
+        for &(name, f) in API_FUNCTIONS {
+            l.register(name, f);
+        }
+
+        // Update the `package.path` to include the script root, so it's easier
+        // for scripts to require each other.
+        {
+            l.getglobal("package");
+            l.getfield(-1, "path");
+            let old_path = l.checkstring(-1).unwrap().to_string();
+            let script_path =
+                Path::new(&SCRIPT_ROOT[..]).join("?.lua").to_str().unwrap().to_string();
+            let new_path = format!("{};{}", old_path, script_path);
+            l.pop(1);
+            l.pushstring(&new_path[..]);
+            l.setfield(-2, "path");
+            l.pop(1);
+        }
+
+        l.newtable();
+        l.setfield(lua::REGISTRYINDEX, CHUNKS_REGISTRY_KEY);
+
+        l.newtable();
+
+        for &(name, f) in API_FUNCTIONS {
+            l.pushcfunction(f);
+            l.setfield(-2, name);
+        }
+
+        sandbox::install(&mut l);
+        limits::install_hook(&mut l);
+
+        // This has to come last, so the snapshot has everything above.
+        if l.loadbuffer(GLOBALS_SOURCE, "=globals").is_err() {
+            panic!("Could not load the globals snapshot: {:?}", l.tostring(-1));
+        }
+
+        if l.pcall(0, 1, 0).is_err() {
+            panic!("Could not snapshot the globals: {:?}", l.tostring(-1));
+        }
+
+        l.setfield(lua::REGISTRYINDEX, RESTORE_REGISTRY_KEY);
+        observe_script_event(ScriptEvent::StateCreated);
+
+        PreparedState {
+            l: l,
+            memory: memory,
+            chunk_revisions: HashMap::new(),
+        }
+    }
+
+    /// Pushes the compiled chunk of a script onto the stack, compiling it
+    /// unless it's cached and the script hasn't changed since. File scripts
+    /// are considered changed when their modification time changes, and
+    /// stored scripts when their digest changes.
+    pub fn load_chunk(&mut self, source: &ScriptSource) -> Result<(), ScriptError> {
+        let (key, revision) = match *source {
+            ScriptSource::File(ref path) => {
+                let mtime = match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+                    Ok(mtime) => mtime,
+                    Err(_) => return Err(ScriptError::File),
+                };
+
+                (path.to_string_lossy().into_owned(), format!("{:?}", mtime))
+            }
+            ScriptSource::Stored(ref script) => (script.id(), script.digest.clone()),
+        };
+
+        self.l.getfield(lua::REGISTRYINDEX, CHUNKS_REGISTRY_KEY);
+
+        if self.chunk_revisions.get(&key) == Some(&revision) {
+            observe_script_event(ScriptEvent::CacheHit);
+            self.l.getfield(-1, &key[..]);
+            self.l.remove(-2);
+            return Ok(());
+        }
+
+        observe_script_event(ScriptEvent::CacheMiss);
+
+        match *source {
+            ScriptSource::File(ref path) => {
+                if let Err(err) = self.l.loadfile(Some(path)) {
+                    return Err(ScriptError::new_from_load_file_error(&mut self.l, err));
+                }
+            }
+            ScriptSource::Stored(ref script) => {
+                // Stored scripts are checked when they're uploaded, but this
+                // also covers ones stored before precompiled scripts were
+                // rejected.
+                if is_bytecode(script.source.as_bytes()) {
+                    return Err(ScriptError::Syntax(BYTECODE_ERROR_MESSAGE.to_string()));
+                }
+
+                let chunk_name = format!("={}@{}", script.name, script.version);
+
+                if let Err(err) = self.l.loadbuffer(&script.source[..], &chunk_name[..]) {
+                    return Err(ScriptError::new_from_load_error(&mut self.l, err));
+                }
+            }
+        }
+
+        self.l.pushvalue(-1);
+        self.l.setfield(-3, &key[..]);
+        self.l.remove(-2);
+        self.chunk_revisions.insert(key, revision);
+        Ok(())
+    }
+
+    /// Pushes a fresh environment for a run onto the stack. Unless the
+    /// sandbox is disabled, this is a sandbox environment; otherwise it
+    /// falls back to the globals for reads.
+    pub fn push_environment(&mut self, settings: &ScriptSettings) -> Result<(), ScriptError> {
+        if settings.sandbox {
+            return sandbox::push_environment(&mut self.l, &settings.libraries);
+        }
+
+        self.l.newtable();
+        self.l.newtable();
+        self.l.pushvalue(lua::GLOBALSINDEX);
+        self.l.setfield(-2, "__index");
+        self.l.setmetatable(-2);
+        Ok(())
+    }
+
+    /// Checks that a sandbox environment can be built with the given
+    /// libraries, i.e. that each of them is a standard global.
+    pub fn check_libraries(&mut self, libraries: &[String]) -> Result<(), ScriptError> {
+        let result = sandbox::push_environment(&mut self.l, libraries);
+        self.l.settop(0);
+        result
+    }
+
+    /// Clears what the last run left behind, so the state can be reused.
+    /// Returns whether that worked.
+    fn reset(&mut self) -> bool {
+        self.l.settop(0);
+        self.l.getfield(lua::REGISTRYINDEX, RESTORE_REGISTRY_KEY);
+        let restored = self.l.pcall(0, 0, 0).is_ok();
+        self.l.settop(0);
+        restored
+    }
+}
+
+/// Takes a prepared state from the pool, or prepares a new one if there are
+/// no idle states.
+pub fn checkout() -> PreparedState {
+    let state = POOL.lock().unwrap().pop();
+    state.unwrap_or_else(PreparedState::new)
+}
+
+/// Resets a state and returns it to the pool. The state is dropped instead
+/// if it couldn't be reset, or the pool is full.
+pub fn checkin(mut state: PreparedState) {
+    if !state.reset() {
+        return;
+    }
+
+    let mut pool = POOL.lock().unwrap();
+
+    if pool.len() < *POOL_SIZE {
+        pool.push(state);
+    }
+}
@@ -0,0 +1,175 @@
+-- Builds the environments that sandboxed scripts run in. This runs once per
+-- lua state, before any script, so the globals it captures are pristine.
+--
+-- Sandboxed scripts get the braid API, the safe base functions, copies of the
+-- `coroutine`, `log`, `math`, `string` and `table` libraries, and the time
+-- functions of `os`. `require` only loads stored scripts and lua modules from
+-- the script root. Modules from the script root run in the same sandbox as
+-- the script that requires them, and stored scripts in the default sandbox.
+
+local api, script_root, load_stored_script = ...
+
+local error, ipairs, loadfile, loadstring, pairs, setfenv, tostring, type = error, ipairs, loadfile, loadstring, pairs, setfenv, tostring, type
+local byte, find, gsub = string.byte, string.find, string.gsub
+local open = io.open
+
+-- The first byte of precompiled lua chunks. `loadstring` accepts them, but
+-- malformed bytecode can crash the interpreter.
+local BYTECODE_SIGNATURE = 27
+
+local SAFE_FUNCTIONS = {
+    "assert", "error", "getmetatable", "ipairs", "next", "pairs", "pcall",
+    "print", "rawequal", "rawget", "rawset", "select", "setmetatable",
+    "tonumber", "tostring", "type", "unpack", "xpcall", "_VERSION",
+}
+
+local SAFE_LIBRARIES = {"coroutine", "log", "math", "string", "table"}
+local SAFE_OS_FUNCTIONS = {"clock", "date", "difftime", "time"}
+
+local function copy(t)
+    local c = {}
+
+    for k, v in pairs(t) do
+        c[k] = v
+    end
+
+    return c
+end
+
+-- Strings share a metatable, which would otherwise let scripts change the
+-- string methods of every later run.
+getmetatable("").__metatable = false
+
+local globals = {}
+
+for name, value in pairs(_G) do
+    if type(value) == "table" and name ~= "_G" then
+        globals[name] = copy(value)
+    else
+        globals[name] = value
+    end
+end
+
+local function is_valid_module_name(name)
+    return type(name) == "string"
+        and find(name, "^[%w_%-%.]+$") ~= nil
+        and find(name, "^%.") == nil
+        and find(name, "%.$") == nil
+        and find(name, "%.%.") == nil
+end
+
+local function file_exists(path)
+    local file = open(path, "r")
+
+    if file == nil then
+        return false
+    end
+
+    file:close()
+    return true
+end
+
+local new_environment
+
+-- Stored scripts are written by accounts, so they always run in a default
+-- sandbox, even when they're required by a script with extra libraries.
+-- Those scripts also prefer modules from the script root over stored
+-- scripts, so accounts can't shadow a module that a trusted script
+-- requires. Other scripts prefer stored scripts, like runs do.
+local function create_require(env, trusted)
+    local modules = {}
+
+    return function(name)
+        if modules[name] ~= nil then
+            return modules[name]
+        end
+
+        if not is_valid_module_name(name) then
+            error("Invalid module name: " .. tostring(name), 2)
+        end
+
+        local path = script_root .. "/" .. gsub(name, "%.", "/") .. ".lua"
+        local source = nil
+
+        -- Stored script names can't have dots, so names with dots can only
+        -- refer to modules in subdirectories of the script root.
+        if find(name, "%.") == nil and not (trusted and file_exists(path)) then
+            source = load_stored_script(name .. ".lua")
+        end
+
+        local chunk, err
+
+        if source == nil then
+            chunk, err = loadfile(path)
+        elseif byte(source, 1) == BYTECODE_SIGNATURE then
+            err = "precompiled chunks are not allowed"
+        else
+            chunk, err = loadstring(source, "=" .. name .. ".lua")
+        end
+
+        if chunk == nil then
+            error("Could not load module `" .. name .. "`: " .. err, 2)
+        end
+
+        if source == nil then
+            setfenv(chunk, env)
+        else
+            setfenv(chunk, new_environment({}))
+        end
+
+        local result = chunk(name)
+
+        if modules[name] == nil then
+            if result == nil then
+                modules[name] = true
+            else
+                modules[name] = result
+            end
+        end
+
+        return modules[name]
+    end
+end
+
+-- Returns a new sandbox environment, with the named standard globals
+-- exposed in full on top of the safe ones.
+new_environment = function(libraries)
+    local env = {}
+
+    for _, name in ipairs(SAFE_FUNCTIONS) do
+        env[name] = globals[name]
+    end
+
+    for _, name in ipairs(SAFE_LIBRARIES) do
+        env[name] = copy(globals[name])
+    end
+
+    env.os = {}
+
+    for _, name in ipairs(SAFE_OS_FUNCTIONS) do
+        env.os[name] = globals.os[name]
+    end
+
+    for name, f in pairs(api) do
+        env[name] = f
+    end
+
+    env.require = create_require(env, #libraries > 0)
+
+    for _, name in ipairs(libraries) do
+        local value = globals[name]
+
+        if value == nil then
+            error("Unknown library: " .. name)
+        elseif type(value) == "table" then
+            env[name] = copy(value)
+        else
+            env[name] = value
+        end
+    end
+
+    env._G = env
+    return env
+end
+
+return new_environment
@@ -0,0 +1,49 @@
+//! Restricts what scripts can access. Sandboxed scripts run in an environment
+//! that is built by `sandbox.lua`, rather than one that falls back to the
+//! globals, which include libraries like `io` that give access to the host.
+
+use lua;
+use super::api;
+use super::errors::ScriptError;
+use super::settings::SCRIPT_ROOT;
+
+/// The registry field holding the function that builds sandbox environments.
+const SANDBOX_REGISTRY_KEY: &'static str = "braid_sandbox";
+
+const SANDBOX_SOURCE: &'static str = include_str!("sandbox.lua");
+
+/// Prepares a state for running sandboxed scripts. The table of functions
+/// exposed to scripts is taken from the top of the stack.
+pub fn install(l: &mut lua::State) {
+    if l.loadbuffer(SANDBOX_SOURCE, "=sandbox").is_err() {
+        panic!("Could not load the script sandbox: {:?}", l.tostring(-1));
+    }
+
+    l.insert(-2);
+    l.pushstring(&SCRIPT_ROOT[..]);
+    l.pushcfunction(api::load_stored_script);
+
+    if l.pcall(3, 1, 0).is_err() {
+        panic!("Could not initialize the script sandbox: {:?}", l.tostring(-1));
+    }
+
+    l.setfield(lua::REGISTRYINDEX, SANDBOX_REGISTRY_KEY);
+}
+
+/// Pushes a new sandbox environment onto the stack, which exposes the named
+/// standard globals in full on top of the safe ones.
+pub fn push_environment(l: &mut lua::State, libraries: &[String]) -> Result<(), ScriptError> {
+    l.getfield(lua::REGISTRYINDEX, SANDBOX_REGISTRY_KEY);
+    l.newtable();
+
+    for (i, library) in libraries.iter().enumerate() {
+        l.pushstring(&library[..]);
+        l.rawseti(-2, i as i32 + 1);
+    }
+
+    if let Err(err) = l.pcall(1, 1, 0) {
+        return Err(ScriptError::new_from_pcallerror(l, err));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,170 @@
+//! Settings for running scripts. Defaults come from environment variables,
+//! and can be overridden for individual scripts by the JSON file at
+//! `BRAID_SCRIPT_SETTINGS`, which maps script names to settings, e.g.:
+//!
+//! ```json
+//! {"report.lua": {"timeout_ms": 120000, "max_memory": 268435456, "libraries": ["io"]}}
+//! ```
+
+use serde_json;
+use serde_json::value::Value as JsonValue;
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
+
+lazy_static! {
+    /// The path to the script root directory
+    pub static ref SCRIPT_ROOT: String = match env::var("BRAID_SCRIPT_ROOT") {
+        Ok(s) => s,
+        Err(_) => Path::new(".").join("scripts").to_str().unwrap().to_string()
+    };
+
+    /// How long a script may run for, in milliseconds
+    static ref DEFAULT_TIMEOUT: Duration = match env::var("BRAID_SCRIPT_TIMEOUT_MS") {
+        Ok(s) => Duration::from_millis(s.parse().expect("Could not parse environment variable `BRAID_SCRIPT_TIMEOUT_MS`: must be a u64")),
+        Err(_) => Duration::from_secs(30),
+    };
+
+    /// How many lua instructions a script may execute, if limited
+    static ref DEFAULT_MAX_INSTRUCTIONS: Option<u64> = env::var("BRAID_SCRIPT_MAX_INSTRUCTIONS").ok().map(|s| {
+        s.parse().expect("Could not parse environment variable `BRAID_SCRIPT_MAX_INSTRUCTIONS`: must be a u64")
+    });
+
+    /// How much memory a script may allocate, in bytes, if limited
+    static ref DEFAULT_MAX_MEMORY: Option<usize> = match env::var("BRAID_SCRIPT_MAX_MEMORY") {
+        Ok(ref s) if s == "unlimited" => None,
+        Ok(s) => Some(s.parse().expect("Could not parse environment variable `BRAID_SCRIPT_MAX_MEMORY`: must be a usize or `unlimited`")),
+        Err(_) => Some(64 * 1024 * 1024),
+    };
+
+    /// Whether scripts run in a sandbox
+    static ref SANDBOX: bool = match env::var("BRAID_SCRIPT_SANDBOX") {
+        Ok(s) => s.parse().expect("Could not parse environment variable `BRAID_SCRIPT_SANDBOX`: must be a bool"),
+        Err(_) => true,
+    };
+
+    static ref SCRIPT_SETTINGS: HashMap<String, ScriptSettings> = match env::var("BRAID_SCRIPT_SETTINGS") {
+        Ok(path) => match load_script_settings(&path[..]) {
+            Ok(settings) => settings,
+            Err(err) => panic!("Could not load script settings from `{}`: {}", path, err),
+        },
+        Err(_) => HashMap::new(),
+    };
+}
+
+/// The settings that a script is run with.
+#[derive(Clone, Debug)]
+pub struct ScriptSettings {
+    pub timeout: Duration,
+    pub max_instructions: Option<u64>,
+    pub max_memory: Option<usize>,
+    pub sandbox: bool,
+
+    /// Standard lua globals, such as `io` or `loadstring`, that are exposed
+    /// in full even though the script is sandboxed
+    pub libraries: Vec<String>,
+}
+
+impl ScriptSettings {
+    /// Gets the settings for a script, falling back to the defaults for
+    /// anything that isn't overridden.
+    pub fn for_script(name: &str) -> ScriptSettings {
+        match SCRIPT_SETTINGS.get(name) {
+            Some(settings) => settings.clone(),
+            None => ScriptSettings::default(),
+        }
+    }
+
+    /// Gets the settings of every script that has settings of its own. This
+    /// loads the defaults and the settings file, if that hasn't happened yet,
+    /// so it panics if either is invalid.
+    pub fn all() -> &'static HashMap<String, ScriptSettings> {
+        ScriptSettings::default();
+        &*SCRIPT_SETTINGS
+    }
+
+    fn from_json(value: &JsonValue) -> Result<ScriptSettings, String> {
+        let mut settings = ScriptSettings::default();
+
+        let o = match value.as_object() {
+            Some(o) => o,
+            None => return Err("expected an object".to_string()),
+        };
+
+        match o.get("timeout_ms") {
+            Some(&JsonValue::Null) | None => (),
+            Some(value) => match value.as_u64() {
+                Some(ms) => settings.timeout = Duration::from_millis(ms),
+                None => return Err("`timeout_ms` must be a u64".to_string()),
+            },
+        }
+
+        // Unlike the timeout, the instruction and memory limits can be
+        // lifted with an explicit `null`.
+        match o.get("max_instructions") {
+            None => (),
+            Some(&JsonValue::Null) => settings.max_instructions = None,
+            Some(value) => match value.as_u64() {
+                Some(max) => settings.max_instructions = Some(max),
+                None => return Err("`max_instructions` must be a u64 or null".to_string()),
+            },
+        }
+
+        match o.get("max_memory") {
+            None => (),
+            Some(&JsonValue::Null) => settings.max_memory = None,
+            Some(value) => match value.as_u64() {
+                Some(max) => settings.max_memory = Some(max as usize),
+                None => return Err("`max_memory` must be a u64 or null".to_string()),
+            },
+        }
+
+        match o.get("libraries") {
+            Some(&JsonValue::Null) | None => (),
+            Some(&JsonValue::Array(ref libraries)) => {
+                for library in libraries {
+                    match library.as_str() {
+                        Some(library) => settings.libraries.push(library.to_string()),
+                        None => return Err("`libraries` must be an array of strings".to_string()),
+                    }
+                }
+            }
+            Some(_) => return Err("`libraries` must be an array of strings".to_string()),
+        }
+
+        Ok(settings)
+    }
+}
+
+impl Default for ScriptSettings {
+    fn default() -> ScriptSettings {
+        ScriptSettings {
+            timeout: *DEFAULT_TIMEOUT,
+            max_instructions: *DEFAULT_MAX_INSTRUCTIONS,
+            max_memory: *DEFAULT_MAX_MEMORY,
+            sandbox: *SANDBOX,
+            libraries: Vec::new(),
+        }
+    }
+}
+
+fn load_script_settings(path: &str) -> Result<HashMap<String, ScriptSettings>, String> {
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let value: JsonValue = serde_json::from_reader(file).map_err(|err| err.to_string())?;
+
+    let o = match value {
+        JsonValue::Object(o) => o,
+        _ => return Err("expected an object mapping script names to settings".to_string()),
+    };
+
+    let mut settings = HashMap::new();
+
+    for (name, value) in o {
+        let script_settings = ScriptSettings::from_json(&value).map_err(|err| format!("invalid settings for `{}`: {}", name, err))?;
+        settings.insert(name, script_settings);
+    }
+
+    Ok(settings)
+}
@@ -0,0 +1,232 @@
+//! Graph traversals that are exposed to scripts. They're done here rather
+//! than in lua so that each step of a traversal is a single datastore call
+//! for the whole frontier, rather than one per vertex.
+//!
+//! Traversals are bounded: `max_depth` and `limit` are capped, and a
+//! traversal fails rather than fetch more than `MAX_EDGES` edges, so a script
+//! can't load a large part of the graph into memory with a single call.
+
+use braid::{Edge, EdgeQuery, QueryTypeConverter, Transaction, Type, Vertex, VertexQuery};
+use datastore::ProxyTransaction;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+use super::errors::LuaError;
+use super::limits;
+
+/// The `max_depth` of traversals when none is given.
+pub const DEFAULT_MAX_DEPTH: u32 = 10;
+
+/// The largest `max_depth` a traversal can have.
+pub const MAX_MAX_DEPTH: u32 = 100;
+
+/// The number of vertices returned by `bfs` when no `limit` is given.
+pub const DEFAULT_LIMIT: u32 = 1000;
+
+/// The maximum number of vertices returned by `bfs`.
+pub const MAX_LIMIT: u32 = 10000;
+
+/// The maximum number of edges a single traversal can fetch.
+const MAX_EDGES: u32 = 100000;
+
+/// Which edges a traversal follows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Outbound,
+    Inbound,
+    Both,
+}
+
+impl Direction {
+    fn converters(&self) -> Vec<QueryTypeConverter> {
+        match *self {
+            Direction::Outbound => vec![QueryTypeConverter::Outbound],
+            Direction::Inbound => vec![QueryTypeConverter::Inbound],
+            Direction::Both => vec![QueryTypeConverter::Outbound, QueryTypeConverter::Inbound],
+        }
+    }
+}
+
+/// Gets the vertices adjacent to a vertex.
+pub fn neighbors(trans: &ProxyTransaction, id: Uuid, t: &Option<Type>, direction: Direction) -> Result<Vec<Vertex>, LuaError> {
+    let mut seen: HashSet<Uuid> = HashSet::new();
+    let mut ids: Vec<Uuid> = Vec::new();
+    let mut budget = MAX_EDGES;
+
+    for converter in direction.converters() {
+        for (_, _, to) in adjacent_edges(trans, &[id], t, converter, &mut budget)? {
+            if seen.insert(to) {
+                ids.push(to);
+            }
+        }
+    }
+
+    get_vertices_in_order(trans, &ids)
+}
+
+/// Walks the graph breadth-first from a vertex, up to `max_depth` edges
+/// away. Returns the vertices that were reached, excluding the start, in the
+/// order they were reached, along with the edge that each was reached by.
+/// Stops fetching edges once `limit` vertices have been reached.
+pub fn bfs(trans: &ProxyTransaction,
+           start_id: Uuid,
+           t: &Option<Type>,
+           direction: Direction,
+           max_depth: u32,
+           limit: u32)
+           -> Result<(Vec<Vertex>, Vec<Edge>), LuaError> {
+    let mut visited: HashSet<Uuid> = HashSet::new();
+    visited.insert(start_id);
+    let mut frontier = vec![start_id];
+    let mut ids: Vec<Uuid> = Vec::new();
+    let mut edges: Vec<Edge> = Vec::new();
+    let mut budget = MAX_EDGES;
+
+    'steps: for _ in 0..max_depth {
+        if frontier.is_empty() {
+            break;
+        }
+
+        let mut next_frontier = Vec::new();
+
+        for converter in direction.converters() {
+            if ids.len() >= limit as usize {
+                break 'steps;
+            }
+
+            limits::check_deadline().map_err(|message| LuaError::Generic(message.to_string()))?;
+
+            for (edge, _, to) in adjacent_edges(trans, &frontier, t, converter, &mut budget)? {
+                if ids.len() >= limit as usize {
+                    break 'steps;
+                }
+
+                if visited.insert(to) {
+                    ids.push(to);
+                    next_frontier.push(to);
+                    edges.push(edge);
+                }
+            }
+        }
+
+        frontier = next_frontier;
+    }
+
+    Ok((get_vertices_in_order(trans, &ids)?, edges))
+}
+
+/// Finds a path with the fewest edges between two vertices, searching at
+/// most `max_depth` edges away. Returns the edges of the path in order, or
+/// `None` if there isn't one.
+pub fn shortest_path(trans: &ProxyTransaction,
+                     from_id: Uuid,
+                     to_id: Uuid,
+                     t: &Option<Type>,
+                     direction: Direction,
+                     max_depth: u32)
+                     -> Result<Option<Vec<Edge>>, LuaError> {
+    if from_id == to_id {
+        return Ok(Some(Vec::new()));
+    }
+
+    // Maps each reached vertex to the edge it was reached by, and the vertex
+    // at the other end of that edge.
+    let mut parents: HashMap<Uuid, (Edge, Uuid)> = HashMap::new();
+    let mut frontier = vec![from_id];
+    let mut budget = MAX_EDGES;
+
+    for _ in 0..max_depth {
+        if frontier.is_empty() {
+            break;
+        }
+
+        let mut next_frontier = Vec::new();
+
+        for converter in direction.converters() {
+            limits::check_deadline().map_err(|message| LuaError::Generic(message.to_string()))?;
+
+            for (edge, from, to) in adjacent_edges(trans, &frontier, t, converter, &mut budget)? {
+                if to == from_id || parents.contains_key(&to) {
+                    continue;
+                }
+
+                parents.insert(to, (edge, from));
+
+                if to == to_id {
+                    let mut path = Vec::new();
+                    let mut current = to_id;
+
+                    while current != from_id {
+                        let (edge, previous) = parents.remove(&current).unwrap();
+                        path.push(edge);
+                        current = previous;
+                    }
+
+                    path.reverse();
+                    return Ok(Some(path));
+                }
+
+                next_frontier.push(to);
+            }
+        }
+
+        frontier = next_frontier;
+    }
+
+    Ok(None)
+}
+
+/// Gets the edges adjacent to some vertices in one direction, with the
+/// vertex on the near side and the vertex on the far side of each. Takes the
+/// number of edges fetched out of `budget`.
+///
+/// # Errors
+/// Returns an error if there are more edges than are left in `budget`.
+fn adjacent_edges(trans: &ProxyTransaction,
+                  ids: &[Uuid],
+                  t: &Option<Type>,
+                  converter: QueryTypeConverter,
+                  budget: &mut u32)
+                  -> Result<Vec<(Edge, Uuid, Uuid)>, LuaError> {
+    let outbound = match converter {
+        QueryTypeConverter::Outbound => true,
+        QueryTypeConverter::Inbound => false,
+    };
+
+    // One more edge than the budget allows is fetched, to tell whether the
+    // budget was exceeded.
+    let vertex_query = VertexQuery::Vertices(ids.to_vec());
+    let edge_query = EdgeQuery::Pipe(Box::new(vertex_query), converter, t.clone(), None, None, *budget + 1);
+    let edges = trans.get_edges(edge_query)?;
+
+    if edges.len() > *budget as usize {
+        return Err(LuaError::Generic(format!("Traversal exceeded its limit of {} edges", MAX_EDGES)));
+    }
+
+    *budget -= edges.len() as u32;
+
+    Ok(edges.into_iter()
+        .map(|edge| {
+            let (from, to) = if outbound {
+                (edge.key.outbound_id, edge.key.inbound_id)
+            } else {
+                (edge.key.inbound_id, edge.key.outbound_id)
+            };
+
+            (edge, from, to)
+        })
+        .collect())
+}
+
+/// Gets vertices by their IDs, in the same order as the IDs.
+fn get_vertices_in_order(trans: &ProxyTransaction, ids: &[Uuid]) -> Result<Vec<Vertex>, LuaError> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut vertices: HashMap<Uuid, Vertex> = trans.get_vertices(VertexQuery::Vertices(ids.to_vec()))?
+        .into_iter()
+        .map(|vertex| (vertex.id, vertex))
+        .collect();
+
+    Ok(ids.iter().filter_map(|id| vertices.remove(id)).collect())
+}
@@ -3,9 +3,11 @@ use serde_json::value::Value as JsonValue;
 use serde_json::{Map, Number};
 use braid::{Vertex, Edge, Type, Weight, VertexQuery, EdgeQuery};
 use uuid::Uuid;
-use std::{isize, i32};
-use core::str::FromStr;
+use std::{isize, i32, u32};
+use std::str::FromStr;
 use super::errors::LuaError;
+use super::traversal::Direction;
+use accounts::is_reserved_metadata_key;
 use serde_json;
 use std::collections::BTreeMap;
 
@@ -218,12 +220,73 @@ pub unsafe fn get_string_param(l: &mut lua::ExternState, narg: i32) -> Result<St
     }
 }
 
+/// Gets a global or account metadata key from lua by its offset, rejecting
+/// keys that are reserved for internal use
+pub unsafe fn get_metadata_key_param(l: &mut lua::ExternState, narg: i32) -> Result<String, LuaError> {
+    let key = get_string_param(l, narg)?;
+
+    if is_reserved_metadata_key(&key[..]) {
+        Err(LuaError::Arg(narg, "Metadata key is reserved".to_string()))
+    } else {
+        Ok(key)
+    }
+}
+
 /// Gets a type value from lua by its offset
 pub unsafe fn get_type_param(l: &mut lua::ExternState, narg: i32) -> Result<Type, LuaError> {
     let s = get_string_param(l, narg)?;
     Ok(Type::new(s)?)
 }
 
+/// Gets an optional type value from lua by its offset. Like other optional
+/// strings, the type may be given as an empty string as well as nil.
+pub unsafe fn get_optional_type_param(l: &mut lua::ExternState, narg: i32) -> Result<Option<Type>, LuaError> {
+    match l.type_(narg) {
+        Some(lua::Type::Nil) | None => Ok(None),
+        _ => {
+            let s = get_string_param(l, narg)?;
+
+            if s == "" {
+                Ok(None)
+            } else {
+                Ok(Some(Type::new(s)?))
+            }
+        }
+    }
+}
+
+/// Gets an optional traversal direction from lua by its offset, defaulting
+/// to outbound
+pub unsafe fn get_direction_param(l: &mut lua::ExternState, narg: i32) -> Result<Direction, LuaError> {
+    match l.type_(narg) {
+        Some(lua::Type::Nil) | None => Ok(Direction::Outbound),
+        _ => {
+            match &get_string_param(l, narg)?[..] {
+                "outbound" => Ok(Direction::Outbound),
+                "inbound" => Ok(Direction::Inbound),
+                "both" => Ok(Direction::Both),
+                _ => Err(LuaError::Arg(narg, "Expected `outbound`, `inbound` or `both`".to_string())),
+            }
+        }
+    }
+}
+
+/// Gets an optional non-negative integer from lua by its offset
+pub unsafe fn get_optional_u32_param(l: &mut lua::ExternState, narg: i32) -> Result<Option<u32>, LuaError> {
+    match l.type_(narg) {
+        Some(lua::Type::Nil) | None => Ok(None),
+        _ => {
+            let n = l.checknumber(narg);
+
+            if n < 0.0 || n > u32::MAX as f64 || n.fract() != 0.0 {
+                Err(LuaError::Arg(narg, "Expected a non-negative integer".to_string()))
+            } else {
+                Ok(Some(n as u32))
+            }
+        }
+    }
+}
+
 /// Gets a string value that represents a uuid from lua by its offset
 pub unsafe fn get_uuid_param(l: &mut lua::ExternState, narg: i32) -> Result<Uuid, LuaError> {
     match get_optional_uuid_param(l, narg)? {
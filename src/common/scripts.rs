@@ -0,0 +1,476 @@
+//! This module stores scripts in the datastore, so that they don't have to
+//! be deployed as files on every server. A script is stored either for a
+//! single account, in its account metadata, or for every account, in global
+//! metadata. Each scope also has an index entry listing the names of its
+//! scripts, since metadata cannot be enumerated.
+//!
+//! Stored scripts are versioned. Every upload adds an immutable version, and
+//! the bare name of a script refers to whichever version was last promoted,
+//! so callers that need stable behavior can pin a version instead. Version
+//! numbers are never reused, even after a script is deleted, so a pinned
+//! version can't come to refer to different code.
+//!
+//! Changes to a scope's scripts take a datastore lock for the scope before
+//! reading what they update, so that concurrent changes, including ones from
+//! other servers, don't overwrite each other's index entries or histories.
+
+use accounts::sha256_hex;
+use braid::{Transaction, Error};
+use chrono::{DateTime, UTC};
+use datastore::{ProxyTransaction, TransactionLock};
+use regex::Regex;
+use serde_json::Value as JsonValue;
+use serde_json;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// The prefix of the metadata keys that scripts are stored under. A script's
+/// history is stored under `<prefix><name>`, and each of its versions under
+/// `<prefix><name>@<version>`. The last version number that was given out
+/// is stored under `<prefix><name>#last_version`, which is kept when the
+/// script is deleted.
+const SCRIPT_KEY_PREFIX: &'static str = "_braid_script:";
+
+/// The metadata key listing the names of the scripts in a scope.
+const SCRIPT_INDEX_KEY: &'static str = "_braid_scripts";
+
+lazy_static! {
+    static ref SCRIPT_NAME_VALIDATOR: Regex = Regex::new(r"^[\w-_]+(\.lua)?$").unwrap();
+}
+
+/// Who a stored script is available to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScriptScope {
+    Account(Uuid),
+    Global,
+}
+
+impl ScriptScope {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ScriptScope::Account(_) => "account",
+            ScriptScope::Global => "global",
+        }
+    }
+
+    /// Returns an identifier for the script with the given name in this
+    /// scope, which is unique across scopes.
+    pub fn script_id(&self, name: &str) -> String {
+        match *self {
+            ScriptScope::Account(account_id) => format!("account/{}/{}", account_id.hyphenated(), name),
+            ScriptScope::Global => format!("global/{}", name),
+        }
+    }
+}
+
+/// A version of a script stored in the datastore.
+#[derive(Clone, Debug)]
+pub struct StoredScript {
+    pub name: String,
+    pub scope: ScriptScope,
+    pub version: u32,
+    pub source: String,
+
+    /// The hex-encoded SHA-256 digest of the source
+    pub digest: String,
+    pub created_at: DateTime<UTC>,
+}
+
+impl StoredScript {
+    /// Returns an identifier for this version of the script that is unique
+    /// across scopes.
+    pub fn id(&self) -> String {
+        format!("{}@{}", self.scope.script_id(&self.name[..]), self.version)
+    }
+
+    /// Serializes the script. The source is only included if requested,
+    /// since listings don't need it.
+    pub fn to_json(&self, include_source: bool) -> JsonValue {
+        let mut o: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+        o.insert("name".to_string(), JsonValue::String(self.name.clone()));
+        o.insert("scope".to_string(), JsonValue::String(self.scope.name().to_string()));
+        o.insert("version".to_string(), JsonValue::from(self.version));
+        o.insert("digest".to_string(), JsonValue::String(self.digest.clone()));
+        o.insert("created_at".to_string(), JsonValue::String(self.created_at.to_rfc3339()));
+
+        if include_source {
+            o.insert("source".to_string(), JsonValue::String(self.source.clone()));
+        }
+
+        JsonValue::Object(o)
+    }
+
+    fn to_metadata(&self) -> JsonValue {
+        let mut o: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+        o.insert("source".to_string(), JsonValue::String(self.source.clone()));
+        o.insert("digest".to_string(), JsonValue::String(self.digest.clone()));
+        o.insert("created_at".to_string(), JsonValue::String(self.created_at.to_rfc3339()));
+        JsonValue::Object(o)
+    }
+
+    fn from_metadata(name: &str, scope: ScriptScope, version: u32, value: &JsonValue) -> Result<StoredScript, Error> {
+        let field = |field: &str| value.get(field).and_then(|v| v.as_str()).map(|v| v.to_string());
+
+        match (field("source"), field("digest"), field("created_at").and_then(|s| parse_datetime(&s[..]))) {
+            (Some(source), Some(digest), Some(created_at)) => {
+                Ok(StoredScript {
+                    name: name.to_string(),
+                    scope: scope,
+                    version: version,
+                    source: source,
+                    digest: digest,
+                    created_at: created_at,
+                })
+            }
+            _ => Err(Error::Unexpected(format!("Invalid metadata for script `{}@{}`", name, version))),
+        }
+    }
+}
+
+/// A summary of a version of a script, without its source.
+#[derive(Clone, Debug)]
+pub struct ScriptVersion {
+    pub version: u32,
+    pub digest: String,
+    pub created_at: DateTime<UTC>,
+}
+
+/// A change of the version that the bare name of a script refers to.
+#[derive(Clone, Debug)]
+pub struct ScriptPromotion {
+    pub version: u32,
+    pub promoted_at: DateTime<UTC>,
+}
+
+/// The versions of a stored script, and which of them is current.
+#[derive(Clone, Debug)]
+pub struct ScriptHistory {
+    pub name: String,
+    pub scope: ScriptScope,
+    pub current_version: u32,
+
+    /// Every version of the script, oldest first
+    pub versions: Vec<ScriptVersion>,
+
+    /// Every promotion of a version, oldest first
+    pub promotions: Vec<ScriptPromotion>,
+}
+
+impl ScriptHistory {
+    /// Returns whether the script has a version.
+    pub fn has_version(&self, version: u32) -> bool {
+        self.versions.iter().any(|v| v.version == version)
+    }
+
+    /// Returns the version that rolling back would promote: the newest
+    /// version that is older than the current one.
+    pub fn previous_version(&self) -> Option<u32> {
+        self.versions.iter().map(|v| v.version).filter(|&v| v < self.current_version).max()
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let versions: Vec<JsonValue> = self.versions
+            .iter()
+            .map(|v| {
+                let mut o: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+                o.insert("version".to_string(), JsonValue::from(v.version));
+                o.insert("digest".to_string(), JsonValue::String(v.digest.clone()));
+                o.insert("created_at".to_string(), JsonValue::String(v.created_at.to_rfc3339()));
+                JsonValue::Object(o)
+            })
+            .collect();
+
+        let promotions: Vec<JsonValue> = self.promotions
+            .iter()
+            .map(|p| {
+                let mut o: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+                o.insert("version".to_string(), JsonValue::from(p.version));
+                o.insert("promoted_at".to_string(), JsonValue::String(p.promoted_at.to_rfc3339()));
+                JsonValue::Object(o)
+            })
+            .collect();
+
+        let mut o: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+        o.insert("name".to_string(), JsonValue::String(self.name.clone()));
+        o.insert("scope".to_string(), JsonValue::String(self.scope.name().to_string()));
+        o.insert("current_version".to_string(), JsonValue::from(self.current_version));
+        o.insert("versions".to_string(), JsonValue::Array(versions));
+        o.insert("promotions".to_string(), JsonValue::Array(promotions));
+        JsonValue::Object(o)
+    }
+
+    fn to_metadata(&self) -> JsonValue {
+        let mut o = match self.to_json() {
+            JsonValue::Object(o) => o,
+            _ => unreachable!(),
+        };
+
+        o.remove("name");
+        o.remove("scope");
+        JsonValue::Object(o)
+    }
+
+    fn from_metadata(name: &str, scope: ScriptScope, value: &JsonValue) -> Result<ScriptHistory, Error> {
+        let invalid = || Error::Unexpected(format!("Invalid metadata for script `{}`", name));
+        let get_u32 = |value: &JsonValue, field: &str| value.get(field).and_then(|v| v.as_u64()).map(|v| v as u32);
+        let get_datetime = |value: &JsonValue, field: &str| value.get(field).and_then(|v| v.as_str()).and_then(parse_datetime);
+
+        let current_version = get_u32(value, "current_version").ok_or_else(&invalid)?;
+        let mut versions = Vec::new();
+        let mut promotions = Vec::new();
+
+        for v in value.get("versions").and_then(|v| v.as_array()).ok_or_else(&invalid)? {
+            versions.push(ScriptVersion {
+                version: get_u32(v, "version").ok_or_else(&invalid)?,
+                digest: v.get("digest").and_then(|v| v.as_str()).map(|v| v.to_string()).ok_or_else(&invalid)?,
+                created_at: get_datetime(v, "created_at").ok_or_else(&invalid)?,
+            });
+        }
+
+        for p in value.get("promotions").and_then(|v| v.as_array()).ok_or_else(&invalid)? {
+            promotions.push(ScriptPromotion {
+                version: get_u32(p, "version").ok_or_else(&invalid)?,
+                promoted_at: get_datetime(p, "promoted_at").ok_or_else(&invalid)?,
+            });
+        }
+
+        Ok(ScriptHistory {
+            name: name.to_string(),
+            scope: scope,
+            current_version: current_version,
+            versions: versions,
+            promotions: promotions,
+        })
+    }
+}
+
+/// Checks whether a name can be used for a script. Names can't contain path
+/// separators, so a name can't refer to a file outside of the script root.
+pub fn is_valid_script_name(name: &str) -> bool {
+    SCRIPT_NAME_VALIDATOR.is_match(name)
+}
+
+/// Gets the history of a script stored in a scope.
+pub fn get_script_history(trans: &ProxyTransaction, scope: ScriptScope, name: &str) -> Result<Option<ScriptHistory>, Error> {
+    match get_metadata(trans, scope, history_key(name)) {
+        Ok(value) => Ok(Some(ScriptHistory::from_metadata(name, scope, &value)?)),
+        Err(Error::MetadataNotFound) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Gets a version of a script stored in a scope, or the current version if
+/// none is given.
+pub fn get_stored_script(trans: &ProxyTransaction, scope: ScriptScope, name: &str, version: Option<u32>) -> Result<Option<StoredScript>, Error> {
+    match get_script_history(trans, scope, name)? {
+        Some(history) => get_script_version(trans, &history, version),
+        None => Ok(None),
+    }
+}
+
+/// Finds the script that a name refers to for an account: the account's own
+/// script if there is one, or otherwise the global script. A pinned version
+/// is only looked up in the scope that the name resolves to.
+pub fn resolve_stored_script(trans: &ProxyTransaction, account_id: Uuid, name: &str, version: Option<u32>) -> Result<Option<StoredScript>, Error> {
+    match resolve_script_history(trans, account_id, name)? {
+        Some(history) => get_script_version(trans, &history, version),
+        None => Ok(None),
+    }
+}
+
+/// Gets the history of the script that a name refers to for an account.
+pub fn resolve_script_history(trans: &ProxyTransaction, account_id: Uuid, name: &str) -> Result<Option<ScriptHistory>, Error> {
+    match get_script_history(trans, ScriptScope::Account(account_id), name)? {
+        Some(history) => Ok(Some(history)),
+        None => get_script_history(trans, ScriptScope::Global, name),
+    }
+}
+
+/// Adds a new version of a script to a scope. The new version is promoted
+/// if requested, or if it is the first version of the script.
+pub fn put_stored_script(trans: &ProxyTransaction, scope: ScriptScope, name: &str, source: String, promote: bool) -> Result<StoredScript, Error> {
+    let _lock = lock_scope(trans, scope)?;
+    let now = UTC::now();
+
+    let mut history = match get_script_history(trans, scope, name)? {
+        Some(history) => history,
+        None => {
+            let mut names = list_stored_scripts(trans, scope)?;
+            names.push(name.to_string());
+            set_script_index(trans, scope, &names)?;
+
+            ScriptHistory {
+                name: name.to_string(),
+                scope: scope,
+                current_version: 0,
+                versions: Vec::new(),
+                promotions: Vec::new(),
+            }
+        }
+    };
+
+    let script = StoredScript {
+        name: name.to_string(),
+        scope: scope,
+        version: next_version(trans, scope, &history)?,
+        digest: sha256_hex(source.as_bytes())?,
+        source: source,
+        created_at: now,
+    };
+
+    set_metadata(trans, scope, version_key(name, script.version), script.to_metadata())?;
+
+    history.versions.push(ScriptVersion {
+        version: script.version,
+        digest: script.digest.clone(),
+        created_at: now,
+    });
+
+    if promote || history.promotions.is_empty() {
+        history.current_version = script.version;
+        history.promotions.push(ScriptPromotion {
+            version: script.version,
+            promoted_at: now,
+        });
+    }
+
+    set_metadata(trans, scope, history_key(name), history.to_metadata())?;
+    Ok(script)
+}
+
+/// Makes the bare name of a script refer to one of its versions.
+///
+/// # Errors
+/// Returns `Error::MetadataNotFound` if the script or the version does not
+/// exist.
+pub fn promote_stored_script(trans: &ProxyTransaction, scope: ScriptScope, name: &str, version: u32) -> Result<ScriptHistory, Error> {
+    let _lock = lock_scope(trans, scope)?;
+
+    let mut history = match get_script_history(trans, scope, name)? {
+        Some(ref history) if history.has_version(version) => history.clone(),
+        _ => return Err(Error::MetadataNotFound),
+    };
+
+    history.current_version = version;
+    history.promotions.push(ScriptPromotion {
+        version: version,
+        promoted_at: UTC::now(),
+    });
+
+    set_metadata(trans, scope, history_key(name), history.to_metadata())?;
+    Ok(history)
+}
+
+/// Deletes every version of a script from a scope. Its version numbers
+/// aren't reused if a script is stored under the same name again.
+///
+/// # Errors
+/// Returns `Error::MetadataNotFound` if the script does not exist.
+pub fn delete_stored_script(trans: &ProxyTransaction, scope: ScriptScope, name: &str) -> Result<(), Error> {
+    let _lock = lock_scope(trans, scope)?;
+
+    let history = match get_script_history(trans, scope, name)? {
+        Some(history) => history,
+        None => return Err(Error::MetadataNotFound),
+    };
+
+    for v in &history.versions {
+        delete_metadata(trans, scope, version_key(name, v.version))?;
+    }
+
+    delete_metadata(trans, scope, history_key(name))?;
+    let names: Vec<String> = list_stored_scripts(trans, scope)?.into_iter().filter(|n| n != name).collect();
+    set_script_index(trans, scope, &names)
+}
+
+/// Lists the names of the scripts stored in a scope.
+pub fn list_stored_scripts(trans: &ProxyTransaction, scope: ScriptScope) -> Result<Vec<String>, Error> {
+    let value = match get_metadata(trans, scope, SCRIPT_INDEX_KEY.to_string()) {
+        Ok(value) => value,
+        Err(Error::MetadataNotFound) => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    match value {
+        JsonValue::Array(names) => Ok(names.iter().filter_map(|name| name.as_str().map(|name| name.to_string())).collect()),
+        _ => Err(Error::Unexpected(format!("Invalid `{}` metadata", SCRIPT_INDEX_KEY))),
+    }
+}
+
+fn get_script_version(trans: &ProxyTransaction, history: &ScriptHistory, version: Option<u32>) -> Result<Option<StoredScript>, Error> {
+    let version = version.unwrap_or(history.current_version);
+
+    if !history.has_version(version) {
+        return Ok(None);
+    }
+
+    let value = get_metadata(trans, history.scope, version_key(&history.name[..], version))?;
+    Ok(Some(StoredScript::from_metadata(&history.name[..], history.scope, version, &value)?))
+}
+
+/// Takes the lock on changes to a scope's scripts.
+fn lock_scope(trans: &ProxyTransaction, scope: ScriptScope) -> Result<TransactionLock, Error> {
+    match scope {
+        ScriptScope::Account(account_id) => trans.lock(&format!("{}:{}", SCRIPT_INDEX_KEY, account_id.hyphenated())),
+        ScriptScope::Global => trans.lock(SCRIPT_INDEX_KEY),
+    }
+}
+
+fn set_script_index(trans: &ProxyTransaction, scope: ScriptScope, names: &[String]) -> Result<(), Error> {
+    let names: Vec<JsonValue> = names.iter().map(|name| JsonValue::String(name.clone())).collect();
+    set_metadata(trans, scope, SCRIPT_INDEX_KEY.to_string(), JsonValue::Array(names))
+}
+
+/// Gives out the number of a new version of a script. Scripts stored before
+/// the counter was kept start from their newest version.
+fn next_version(trans: &ProxyTransaction, scope: ScriptScope, history: &ScriptHistory) -> Result<u32, Error> {
+    let key = last_version_key(&history.name[..]);
+
+    let last_version = match get_metadata(trans, scope, key.clone()) {
+        Ok(value) => value.as_u64().map(|v| v as u32).ok_or_else(|| Error::Unexpected(format!("Invalid `{}` metadata", key)))?,
+        Err(Error::MetadataNotFound) => 0,
+        Err(err) => return Err(err),
+    };
+
+    let newest_version = history.versions.iter().map(|v| v.version).max().unwrap_or(0);
+    let version = last_version.max(newest_version) + 1;
+    set_metadata(trans, scope, key, JsonValue::from(version))?;
+    Ok(version)
+}
+
+fn history_key(name: &str) -> String {
+    format!("{}{}", SCRIPT_KEY_PREFIX, name)
+}
+
+fn last_version_key(name: &str) -> String {
+    format!("{}{}#last_version", SCRIPT_KEY_PREFIX, name)
+}
+
+fn version_key(name: &str, version: u32) -> String {
+    format!("{}{}@{}", SCRIPT_KEY_PREFIX, name, version)
+}
+
+fn parse_datetime(s: &str) -> Option<DateTime<UTC>> {
+    DateTime::<UTC>::from_str(s).ok()
+}
+
+fn get_metadata(trans: &ProxyTransaction, scope: ScriptScope, key: String) -> Result<JsonValue, Error> {
+    match scope {
+        ScriptScope::Account(account_id) => trans.get_account_metadata(account_id, key),
+        ScriptScope::Global => trans.get_global_metadata(key),
+    }
+}
+
+fn set_metadata(trans: &ProxyTransaction, scope: ScriptScope, key: String, value: JsonValue) -> Result<(), Error> {
+    match scope {
+        ScriptScope::Account(account_id) => trans.set_account_metadata(account_id, key, value),
+        ScriptScope::Global => trans.set_global_metadata(key, value),
+    }
+}
+
+fn delete_metadata(trans: &ProxyTransaction, scope: ScriptScope, key: String) -> Result<(), Error> {
+    match scope {
+        ScriptScope::Account(account_id) => trans.delete_account_metadata(account_id, key),
+        ScriptScope::Global => trans.delete_global_metadata(key),
+    }
+}
@@ -0,0 +1,80 @@
+//! Writes test results as JUnit-style XML, which CI systems know how to
+//! display. Each test file is a `testsuite`, and each case a `testcase`.
+
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::time::Duration;
+use test::{Outcome, SuiteResult};
+
+pub fn write_report(path: &str, results: &[SuiteResult]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(render_report(results).as_bytes())
+}
+
+fn render_report(results: &[SuiteResult]) -> String {
+    let mut out = String::new();
+    let count = |suite: &SuiteResult, f: &Fn(&Outcome) -> bool| suite.cases.iter().filter(|case| f(&case.outcome)).count();
+    let is_failure = |outcome: &Outcome| match *outcome { Outcome::Failed(_) => true, _ => false };
+    let is_error = |outcome: &Outcome| match *outcome { Outcome::Errored(_) => true, _ => false };
+
+    let tests: usize = results.iter().map(|suite| suite.cases.len()).sum();
+    let failures: usize = results.iter().map(|suite| count(suite, &is_failure)).sum();
+    let errors: usize = results.iter().map(|suite| count(suite, &is_error)).sum();
+
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str(&format!("<testsuites tests=\"{}\" failures=\"{}\" errors=\"{}\">\n", tests, failures, errors)[..]);
+
+    for suite in results {
+        let time: Duration = suite.cases.iter().fold(Duration::from_secs(0), |total, case| total + case.duration);
+
+        out.push_str(&format!("  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{}\">\n",
+                              escape(&suite.name[..]),
+                              suite.cases.len(),
+                              count(suite, &is_failure),
+                              count(suite, &is_error),
+                              format_seconds(time))[..]);
+
+        for case in &suite.cases {
+            let attributes = format!("classname=\"{}\" name=\"{}\" time=\"{}\"",
+                                     escape(&suite.name[..]),
+                                     escape(&case.name[..]),
+                                     format_seconds(case.duration));
+
+            match case.outcome {
+                Outcome::Passed => out.push_str(&format!("    <testcase {}/>\n", attributes)[..]),
+                Outcome::Failed(ref message) => {
+                    out.push_str(&format!("    <testcase {}>\n", attributes)[..]);
+                    out.push_str(&format!("      <failure message=\"{}\">{}</failure>\n", escape(first_line(message)), escape(message))[..]);
+                    out.push_str("    </testcase>\n");
+                }
+                Outcome::Errored(ref message) => {
+                    out.push_str(&format!("    <testcase {}>\n", attributes)[..]);
+                    out.push_str(&format!("      <error message=\"{}\">{}</error>\n", escape(first_line(message)), escape(message))[..]);
+                    out.push_str("    </testcase>\n");
+                }
+            }
+        }
+
+        out.push_str("  </testsuite>\n");
+    }
+
+    out.push_str("</testsuites>\n");
+    out
+}
+
+fn format_seconds(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0)
+}
+
+fn first_line(message: &str) -> &str {
+    message.lines().next().unwrap_or("")
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
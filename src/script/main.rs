@@ -0,0 +1,67 @@
+#[macro_use]
+extern crate clap;
+extern crate braid;
+#[macro_use]
+extern crate common;
+extern crate serde_json;
+extern crate uuid;
+
+mod junit;
+mod run;
+mod test;
+
+use clap::{Arg, App, SubCommand};
+use serde_json::value::Value as JsonValue;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// App for working with scripts outside of the server
+fn main() {
+    let matches = App::new("braid-script")
+        .version("0.1")
+        .about("Runs and tests braid scripts without a server")
+        .subcommand(SubCommand::with_name("run")
+            .about("Runs a script once against the datastore")
+            .arg(Arg::with_name("NAME").help("Name of the script").required(true).index(1))
+            .arg(Arg::with_name("account").long("account").takes_value(true).value_name("ID").required(true)
+                .help("ID of the account to run the script as"))
+            .arg(Arg::with_name("arg").long("arg").takes_value(true).value_name("JSON")
+                .help("JSON value passed to the script as `arg`"))
+            .arg(Arg::with_name("dry-run").long("dry-run")
+                .help("Rolls back the script's changes instead of committing them")))
+        .subcommand(SubCommand::with_name("test")
+            .about("Runs script tests against a throwaway datastore")
+            .arg(Arg::with_name("FILE").help("Test file").required(true).multiple(true).index(1))
+            .arg(Arg::with_name("junit").long("junit").takes_value(true).value_name("PATH")
+                .help("Writes a JUnit-style XML report to the given path")))
+        .get_matches();
+
+    if let Some(matches) = matches.subcommand_matches("run") {
+        let account_id = value_t!(matches, "account", Uuid).unwrap_or_else(|err| err.exit());
+
+        let arg: JsonValue = match matches.value_of("arg") {
+            Some(arg) => match serde_json::from_str(arg) {
+                Ok(arg) => arg,
+                Err(err) => exit_with_err!("Could not parse `--arg`: {}", err),
+            },
+            None => JsonValue::Null,
+        };
+
+        run::run_script(matches.value_of("NAME").unwrap(), account_id, &arg, matches.is_present("dry-run"));
+    } else if let Some(matches) = matches.subcommand_matches("test") {
+        let paths: Vec<PathBuf> = matches.values_of("FILE").unwrap().map(PathBuf::from).collect();
+        let results = test::run_test_files(&paths);
+
+        if let Some(junit_path) = matches.value_of("junit") {
+            if let Err(err) = junit::write_report(junit_path, &results) {
+                exit_with_err!("Could not write the JUnit report: {}", err);
+            }
+        }
+
+        if !results.iter().all(|suite| suite.passed()) {
+            exit_with_err!("Some script tests failed");
+        }
+    } else {
+        exit_with_err!("No action specified");
+    }
+}
@@ -0,0 +1,69 @@
+//! Runs a script once against the configured datastore, e.g. for
+//! maintenance. The script is found and run the same way as through
+//! `POST /script/:name`, with the same `trans`, `account_id` and `arg`
+//! globals.
+
+use braid::{Datastore, Transaction};
+use common::{AuditEntry, datastore, is_valid_script_name, record_audit_entry};
+use common::script::{self, ScriptLogEntry};
+use serde_json::value::Value as JsonValue;
+use std::io;
+use std::io::Write;
+use uuid::Uuid;
+
+/// Runs a script as an account, printing its JSON result. What the script
+/// prints or logs is written to stderr, so stdout only has the result. The
+/// transaction is committed, unless this is a dry run, in which case it's
+/// rolled back.
+pub fn run_script(name: &str, account_id: Uuid, arg: &JsonValue, dry_run: bool) {
+    if !is_valid_script_name(name) {
+        exit_with_err!("Invalid script name: `{}`", name);
+    }
+
+    let datastore = datastore();
+
+    match datastore.has_account(account_id) {
+        Ok(true) => (),
+        Ok(false) => exit_with_err!("Account `{}` does not exist", account_id),
+        Err(err) => exit_with_err!("Could not look up the account: {:?}", err),
+    }
+
+    let trans = match datastore.transaction(account_id) {
+        Ok(trans) => trans,
+        Err(err) => exit_with_err!("Could not start a transaction: {:?}", err),
+    };
+
+    let source = match script::find_source(&trans, account_id, name, None) {
+        Ok(Some(source)) => source,
+        Ok(None) => exit_with_err!("Script not found"),
+        Err(err) => exit_with_err!("Could not find the script: {:?}", err),
+    };
+
+    let mut log: Vec<ScriptLogEntry> = Vec::new();
+    let result = script::run(&trans, account_id, &source, arg, &source.settings(name), &mut log);
+
+    for entry in log {
+        let _ = writeln!(io::stderr(), "[{}] {}", entry.level.as_str(), entry.message);
+    }
+
+    let result = match result {
+        Ok(result) => result,
+        Err(err) => exit_with_err!("Script failed: {:?}", err),
+    };
+
+    if dry_run {
+        if let Err(err) = trans.rollback() {
+            exit_with_err!("Could not roll back the transaction: {:?}", err);
+        }
+    } else {
+        let committed = trans.commit();
+        let entry = AuditEntry::new("run_script").with_account_id(Some(account_id)).with_target(name.to_string());
+        record_audit_entry(&entry.with_success(committed.is_ok()));
+
+        if let Err(err) = committed {
+            exit_with_err!("Could not commit the transaction: {:?}", err);
+        }
+    }
+
+    println!("{}", result);
+}
@@ -0,0 +1,565 @@
+//! Runs script tests. A test file is a JSON document that names the script
+//! under test, relative to the test file, and describes cases to run it
+//! with:
+//!
+//! ```json
+//! {
+//!     "script": "follow.lua",
+//!     "fixtures": {"vertices": {"alice": "user", "bob": "user"}},
+//!     "cases": [
+//!         {
+//!             "name": "follows a user",
+//!             "arg": {"from": "$alice", "to": "$bob"},
+//!             "result": true,
+//!             "graph": {"edges": [{"outbound": "$alice", "type": "follows", "inbound": "$bob"}]}
+//!         },
+//!         {
+//!             "name": "rejects following yourself",
+//!             "arg": {"from": "$alice", "to": "$alice"},
+//!             "error": "Cannot follow yourself"
+//!         }
+//!     ]
+//! }
+//! ```
+//!
+//! Every case runs against its own throwaway datastore, so cases only see
+//! the fixtures of the file and of the case itself. Since nothing is rolled
+//! back, `graph` can also be checked for cases that expect an error, e.g. to
+//! check that a script validates its input before changing anything.
+
+use braid::{Datastore, EdgeKey, EdgeQuery, QueryTypeConverter, Transaction, Type, VertexQuery, Weight};
+use common::{ProxyDatastore, ProxyTransaction, create_account, rocksdb_datastore};
+use common::script::{self, ScriptLogEntry, ScriptSettings, ScriptSource};
+use serde_json;
+use serde_json::value::Value as JsonValue;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::u32;
+use uuid::Uuid;
+
+/// The endpoint of an expected edge that matches any vertex.
+const ANY_VERTEX: &'static str = "*";
+
+/// Vertices and data that are created before a case runs. Vertices are
+/// named, and can be referred to as `$name` in edges, args and expectations.
+#[derive(Default)]
+struct Fixtures {
+    vertices: Vec<(String, Type)>,
+    edges: Vec<(String, Type, String, Weight)>,
+    account_metadata: Vec<(String, JsonValue)>,
+    global_metadata: Vec<(String, JsonValue)>,
+}
+
+/// What a case expects the script to do.
+enum Expectation {
+    /// The script should succeed, returning the value if one is given
+    Success(Option<JsonValue>),
+
+    /// The script should fail with an error containing the message
+    Error(String),
+}
+
+/// What a case expects the graph to look like after the script has run.
+struct GraphExpectation {
+    /// The number of vertices of each type
+    vertices: Option<BTreeMap<String, u64>>,
+
+    /// Every edge, as `(outbound, type, inbound)`
+    edges: Option<Vec<(String, String, String)>>,
+}
+
+struct TestCase {
+    name: String,
+    fixtures: Fixtures,
+    arg: JsonValue,
+    expectation: Expectation,
+    graph: Option<GraphExpectation>,
+}
+
+struct TestFile {
+    script: PathBuf,
+    fixtures: Fixtures,
+    cases: Vec<TestCase>,
+}
+
+/// How a case turned out.
+pub enum Outcome {
+    Passed,
+
+    /// The script didn't do what the case expected
+    Failed(String),
+
+    /// The case could not be run, e.g. because its fixtures are invalid
+    Errored(String),
+}
+
+pub struct CaseResult {
+    pub name: String,
+    pub outcome: Outcome,
+    pub duration: Duration,
+}
+
+/// The results of the cases in a test file.
+pub struct SuiteResult {
+    pub name: String,
+    pub cases: Vec<CaseResult>,
+}
+
+impl SuiteResult {
+    pub fn passed(&self) -> bool {
+        self.cases.iter().all(|case| match case.outcome {
+            Outcome::Passed => true,
+            _ => false,
+        })
+    }
+}
+
+/// Runs the cases in some test files, printing the outcome of each as it
+/// goes.
+pub fn run_test_files(paths: &[PathBuf]) -> Vec<SuiteResult> {
+    let results = paths.iter().map(|path| run_test_file(path)).collect();
+    print_summary(&results);
+    results
+}
+
+fn run_test_file(path: &Path) -> SuiteResult {
+    let name = path.to_string_lossy().into_owned();
+    println!("\nrunning {}", name);
+
+    let file = match load_test_file(path) {
+        Ok(file) => file,
+        Err(err) => {
+            println!("test {} ... ERROR", name);
+
+            return SuiteResult {
+                name: name,
+                cases: vec![CaseResult {
+                    name: "load".to_string(),
+                    outcome: Outcome::Errored(err),
+                    duration: Duration::from_secs(0),
+                }],
+            };
+        }
+    };
+
+    let mut cases = Vec::new();
+
+    for case in &file.cases {
+        let start = Instant::now();
+        let outcome = run_case(&file, case);
+
+        println!("test {} ... {}", case.name, match outcome {
+            Outcome::Passed => "ok",
+            Outcome::Failed(_) => "FAILED",
+            Outcome::Errored(_) => "ERROR",
+        });
+
+        cases.push(CaseResult {
+            name: case.name.clone(),
+            outcome: outcome,
+            duration: start.elapsed(),
+        });
+    }
+
+    SuiteResult {
+        name: name,
+        cases: cases,
+    }
+}
+
+/// Runs a case against its own throwaway rocksdb datastore, which is
+/// removed afterwards. Rocksdb transactions can't be rolled back, so cases
+/// can't share a datastore without seeing each other's changes.
+fn run_case(file: &TestFile, case: &TestCase) -> Outcome {
+    let datastore_path = env::temp_dir().join(format!("braid-script-test-{}", Uuid::new_v4().hyphenated()));
+
+    let outcome = {
+        let datastore = rocksdb_datastore(&datastore_path.to_string_lossy()[..]);
+        run_case_in_datastore(&datastore, file, case)
+    };
+
+    if let Err(err) = fs::remove_dir_all(&datastore_path) {
+        println!("Could not remove the test datastore at `{}`: {}", datastore_path.display(), err);
+    }
+
+    outcome
+}
+
+fn run_case_in_datastore(datastore: &ProxyDatastore, file: &TestFile, case: &TestCase) -> Outcome {
+    let account_id = match create_account(datastore) {
+        Ok((account_id, _)) => account_id,
+        Err(err) => return Outcome::Errored(format!("Could not create an account: {:?}", err)),
+    };
+
+    let trans = match datastore.transaction(account_id) {
+        Ok(trans) => trans,
+        Err(err) => return Outcome::Errored(format!("Could not start a transaction: {:?}", err)),
+    };
+
+    let outcome = match run_case_in_transaction(&trans, account_id, file, case) {
+        Ok(outcome) => outcome,
+        Err(err) => Outcome::Errored(err),
+    };
+
+    // The datastore is thrown away, so it doesn't matter what's kept, but the
+    // transaction is still finished properly.
+    if let Err(err) = trans.commit() {
+        return Outcome::Errored(format!("Could not commit the transaction: {:?}", err));
+    }
+
+    outcome
+}
+
+fn run_case_in_transaction(trans: &ProxyTransaction, account_id: Uuid, file: &TestFile, case: &TestCase) -> Result<Outcome, String> {
+    let mut names: HashMap<String, Uuid> = HashMap::new();
+    create_fixtures(trans, account_id, &file.fixtures, &mut names)?;
+    create_fixtures(trans, account_id, &case.fixtures, &mut names)?;
+
+    let script_name = file.script.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let settings = ScriptSettings::for_script(&script_name[..]);
+    let source = ScriptSource::File(file.script.clone());
+    let mut log: Vec<ScriptLogEntry> = Vec::new();
+    let result = script::run(trans, account_id, &source, &substitute_names(&case.arg, &names), &settings, &mut log);
+    let mut failures: Vec<String> = Vec::new();
+
+    match (&case.expectation, result) {
+        (&Expectation::Success(Some(ref expected)), Ok(actual)) => {
+            let expected = substitute_names(expected, &names);
+
+            if expected != actual {
+                failures.push(format!("Expected the script to return {}, but it returned {}", expected, actual));
+            }
+        }
+        (&Expectation::Success(None), Ok(_)) => (),
+        (&Expectation::Success(_), Err(err)) => {
+            failures.push(format!("Expected the script to succeed, but it failed: {:?}", err));
+        }
+        (&Expectation::Error(ref message), Ok(actual)) => {
+            failures.push(format!("Expected the script to fail with `{}`, but it returned {}", message, actual));
+        }
+        (&Expectation::Error(ref message), Err(err)) => {
+            let err = format!("{:?}", err);
+
+            if !err.contains(&message[..]) {
+                failures.push(format!("Expected the script to fail with `{}`, but it failed with: {}", message, err));
+            }
+        }
+    }
+
+    if let Some(ref graph) = case.graph {
+        failures.extend(check_graph(trans, graph, &names)?);
+    }
+
+    if failures.is_empty() {
+        return Ok(Outcome::Passed);
+    }
+
+    // What the script logged is included, since it's often the quickest way
+    // to see why a case failed.
+    if !log.is_empty() {
+        failures.push("Script output:".to_string());
+        failures.extend(log.iter().map(|entry| format!("  [{}] {}", entry.level.as_str(), entry.message)));
+    }
+
+    Ok(Outcome::Failed(failures.join("\n")))
+}
+
+fn create_fixtures(trans: &ProxyTransaction, account_id: Uuid, fixtures: &Fixtures, names: &mut HashMap<String, Uuid>) -> Result<(), String> {
+    for &(ref name, ref t) in &fixtures.vertices {
+        let id = trans.create_vertex(t.clone()).map_err(|err| format!("Could not create vertex `{}`: {:?}", name, err))?;
+        names.insert(name.clone(), id);
+    }
+
+    for &(ref outbound, ref t, ref inbound, weight) in &fixtures.edges {
+        let key = EdgeKey::new(resolve_name(outbound, names)?, t.clone(), resolve_name(inbound, names)?);
+        trans.create_edge(key, weight).map_err(|err| format!("Could not create edge `{}`: {:?}", t.0, err))?;
+    }
+
+    for &(ref key, ref value) in &fixtures.account_metadata {
+        trans.set_account_metadata(account_id, key.clone(), substitute_names(value, names))
+            .map_err(|err| format!("Could not set account metadata `{}`: {:?}", key, err))?;
+    }
+
+    for &(ref key, ref value) in &fixtures.global_metadata {
+        trans.set_global_metadata(key.clone(), substitute_names(value, names))
+            .map_err(|err| format!("Could not set global metadata `{}`: {:?}", key, err))?;
+    }
+
+    Ok(())
+}
+
+/// Compares the graph against what a case expects, returning a description
+/// of each difference.
+fn check_graph(trans: &ProxyTransaction, graph: &GraphExpectation, names: &HashMap<String, Uuid>) -> Result<Vec<String>, String> {
+    let mut failures = Vec::new();
+
+    if let Some(ref expected) = graph.vertices {
+        let vertices = trans.get_vertices(VertexQuery::All(None, u32::MAX)).map_err(|err| format!("Could not get vertices: {:?}", err))?;
+        let mut actual: BTreeMap<String, u64> = BTreeMap::new();
+
+        for vertex in vertices {
+            *actual.entry(vertex.t.0).or_insert(0) += 1;
+        }
+
+        if *expected != actual {
+            failures.push(format!("Expected vertices {:?}, but found {:?}", expected, actual));
+        }
+    }
+
+    if let Some(ref expected) = graph.edges {
+        let query = EdgeQuery::Pipe(Box::new(VertexQuery::All(None, u32::MAX)), QueryTypeConverter::Outbound, None, None, None, u32::MAX);
+        let edges = trans.get_edges(query).map_err(|err| format!("Could not get edges: {:?}", err))?;
+        let ids: HashMap<Uuid, String> = names.iter().map(|(name, id)| (*id, format!("${}", name))).collect();
+        let describe = |id: Uuid| ids.get(&id).cloned().unwrap_or_else(|| id.hyphenated().to_string());
+        let mut unmatched: Vec<(String, String, String)> = edges.into_iter()
+            .map(|edge| (describe(edge.key.outbound_id), edge.key.t.0, describe(edge.key.inbound_id)))
+            .collect();
+
+        // Match the most specific expectations first, so that wildcards
+        // don't take edges that a named expectation needs.
+        let mut expected: Vec<&(String, String, String)> = expected.iter().collect();
+        expected.sort_by_key(|&&(ref outbound, _, ref inbound)| (outbound == ANY_VERTEX) as u8 + (inbound == ANY_VERTEX) as u8);
+
+        for &&(ref outbound, ref t, ref inbound) in &expected {
+            let position = unmatched.iter().position(|&(ref o, ref ty, ref i)| {
+                ty == t && (outbound == ANY_VERTEX || o == outbound) && (inbound == ANY_VERTEX || i == inbound)
+            });
+
+            match position {
+                Some(position) => {
+                    unmatched.remove(position);
+                }
+                None => failures.push(format!("Expected an edge {} -[{}]-> {}, but there wasn't one", outbound, t, inbound)),
+            }
+        }
+
+        for (outbound, t, inbound) in unmatched {
+            failures.push(format!("Found an unexpected edge {} -[{}]-> {}", outbound, t, inbound));
+        }
+    }
+
+    Ok(failures)
+}
+
+/// Replaces strings of the form `$name` with the ID of the named fixture
+/// vertex. Other strings are left as they are.
+fn substitute_names(value: &JsonValue, names: &HashMap<String, Uuid>) -> JsonValue {
+    match *value {
+        JsonValue::String(ref s) if s.starts_with('$') => {
+            match names.get(&s[1..]) {
+                Some(id) => JsonValue::String(id.hyphenated().to_string()),
+                None => value.clone(),
+            }
+        }
+        JsonValue::Array(ref values) => JsonValue::Array(values.iter().map(|v| substitute_names(v, names)).collect()),
+        JsonValue::Object(ref o) => {
+            JsonValue::Object(o.iter().map(|(k, v)| (k.clone(), substitute_names(v, names))).collect())
+        }
+        _ => value.clone(),
+    }
+}
+
+fn resolve_name(reference: &str, names: &HashMap<String, Uuid>) -> Result<Uuid, String> {
+    if reference.starts_with('$') {
+        if let Some(id) = names.get(&reference[1..]) {
+            return Ok(*id);
+        }
+    }
+
+    Err(format!("Unknown fixture vertex `{}`", reference))
+}
+
+fn print_summary(results: &[SuiteResult]) {
+    let (mut passed, mut failed, mut errored) = (0, 0, 0);
+    let mut details = Vec::new();
+
+    for suite in results {
+        for case in &suite.cases {
+            match case.outcome {
+                Outcome::Passed => passed += 1,
+                Outcome::Failed(ref message) => {
+                    failed += 1;
+                    details.push(format!("---- {}: {} ----\n{}", suite.name, case.name, message));
+                }
+                Outcome::Errored(ref message) => {
+                    errored += 1;
+                    details.push(format!("---- {}: {} ----\n{}", suite.name, case.name, message));
+                }
+            }
+        }
+    }
+
+    if !details.is_empty() {
+        println!("\nfailures:\n\n{}", details.join("\n\n"));
+    }
+
+    let status = if failed + errored == 0 { "ok" } else { "FAILED" };
+    println!("\ntest result: {}. {} passed; {} failed; {} errored", status, passed, failed, errored);
+}
+
+fn load_test_file(path: &Path) -> Result<TestFile, String> {
+    let file = File::open(path).map_err(|err| format!("Could not open the test file: {}", err))?;
+    let value: JsonValue = serde_json::from_reader(file).map_err(|err| format!("Could not parse the test file: {}", err))?;
+
+    let script = match value.get("script").and_then(|v| v.as_str()) {
+        Some(script) => path.parent().unwrap_or_else(|| Path::new(".")).join(script),
+        None => return Err("`script` must be a string".to_string()),
+    };
+
+    let mut cases = Vec::new();
+
+    match value.get("cases") {
+        Some(&JsonValue::Array(ref values)) => {
+            for (i, value) in values.iter().enumerate() {
+                cases.push(parse_case(value).map_err(|err| format!("Invalid case #{}: {}", i, err))?);
+            }
+        }
+        _ => return Err("`cases` must be an array".to_string()),
+    }
+
+    Ok(TestFile {
+        script: script,
+        fixtures: parse_fixtures(value.get("fixtures"))?,
+        cases: cases,
+    })
+}
+
+fn parse_case(value: &JsonValue) -> Result<TestCase, String> {
+    let name = match value.get("name").and_then(|v| v.as_str()) {
+        Some(name) => name.to_string(),
+        None => return Err("`name` must be a string".to_string()),
+    };
+
+    let expectation = match (value.get("result"), value.get("error")) {
+        (Some(_), Some(_)) => return Err("only one of `result` and `error` can be set".to_string()),
+        (Some(result), None) => Expectation::Success(Some(result.clone())),
+        (None, Some(&JsonValue::String(ref message))) => Expectation::Error(message.clone()),
+        (None, Some(_)) => return Err("`error` must be a string".to_string()),
+        (None, None) => Expectation::Success(None),
+    };
+
+    let graph = match value.get("graph") {
+        Some(graph) => Some(parse_graph(graph)?),
+        None => None,
+    };
+
+    Ok(TestCase {
+        name: name,
+        fixtures: parse_fixtures(value.get("fixtures"))?,
+        arg: value.get("arg").cloned().unwrap_or(JsonValue::Null),
+        expectation: expectation,
+        graph: graph,
+    })
+}
+
+fn parse_fixtures(value: Option<&JsonValue>) -> Result<Fixtures, String> {
+    let mut fixtures = Fixtures::default();
+
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(fixtures),
+    };
+
+    match value.get("vertices") {
+        Some(&JsonValue::Object(ref o)) => {
+            for (name, t) in o {
+                let t = t.as_str().and_then(|t| Type::new(t.to_string()).ok());
+
+                match t {
+                    Some(t) => fixtures.vertices.push((name.clone(), t)),
+                    None => return Err(format!("invalid type for fixture vertex `{}`", name)),
+                }
+            }
+        }
+        Some(_) => return Err("fixture `vertices` must map names to types".to_string()),
+        None => (),
+    }
+
+    match value.get("edges") {
+        Some(&JsonValue::Array(ref edges)) => {
+            for edge in edges {
+                let (outbound, t, inbound) = parse_edge(edge).map_err(|err| format!("invalid fixture edge: {}", err))?;
+
+                let t = match Type::new(t) {
+                    Ok(t) => t,
+                    Err(_) => return Err("invalid fixture edge: invalid `type`".to_string()),
+                };
+
+                let weight = match edge.get("weight").map(|w| w.as_f64()) {
+                    Some(Some(w)) => Weight::new(w as f32).ok(),
+                    Some(None) => None,
+                    None => Weight::new(1.0).ok(),
+                };
+
+                match weight {
+                    Some(weight) => fixtures.edges.push((outbound, t, inbound, weight)),
+                    None => return Err("invalid fixture edge: `weight` must be between -1.0 and 1.0".to_string()),
+                }
+            }
+        }
+        Some(_) => return Err("fixture `edges` must be an array".to_string()),
+        None => (),
+    }
+
+    fixtures.account_metadata = parse_metadata(value.get("account_metadata"), "account_metadata")?;
+    fixtures.global_metadata = parse_metadata(value.get("global_metadata"), "global_metadata")?;
+    Ok(fixtures)
+}
+
+fn parse_metadata(value: Option<&JsonValue>, field: &str) -> Result<Vec<(String, JsonValue)>, String> {
+    match value {
+        Some(&JsonValue::Object(ref o)) => Ok(o.iter().map(|(k, v)| (k.clone(), v.clone())).collect()),
+        Some(_) => Err(format!("fixture `{}` must be an object", field)),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn parse_graph(value: &JsonValue) -> Result<GraphExpectation, String> {
+    let vertices = match value.get("vertices") {
+        Some(&JsonValue::Object(ref o)) => {
+            let mut counts = BTreeMap::new();
+
+            for (t, count) in o {
+                match count.as_u64() {
+                    Some(count) => counts.insert(t.clone(), count),
+                    None => return Err("graph `vertices` must map types to counts".to_string()),
+                };
+            }
+
+            Some(counts)
+        }
+        Some(_) => return Err("graph `vertices` must map types to counts".to_string()),
+        None => None,
+    };
+
+    let edges = match value.get("edges") {
+        Some(&JsonValue::Array(ref values)) => {
+            let mut edges = Vec::new();
+
+            for value in values {
+                edges.push(parse_edge(value).map_err(|err| format!("invalid graph edge: {}", err))?);
+            }
+
+            Some(edges)
+        }
+        Some(_) => return Err("graph `edges` must be an array".to_string()),
+        None => None,
+    };
+
+    Ok(GraphExpectation {
+        vertices: vertices,
+        edges: edges,
+    })
+}
+
+fn parse_edge(value: &JsonValue) -> Result<(String, String, String), String> {
+    let field = |name: &str| match value.get(name).and_then(|v| v.as_str()) {
+        Some(s) => Ok(s.to_string()),
+        None => Err(format!("`{}` must be a string", name)),
+    };
+
+    Ok((field("outbound")?, field("type")?, field("inbound")?))
+}
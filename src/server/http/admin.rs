@@ -0,0 +1,258 @@
+use iron::prelude::*;
+use iron::status;
+use iron::headers::{Authorization, Basic};
+use chrono::{DateTime, UTC};
+use braid::{Datastore, Transaction, Error};
+use common;
+use common::{AccountState, AuditFilter, ScriptScope, audit_log_enabled, get_script_history, get_stored_script,
+             query_audit_log, record_audit_entry};
+use serde_json::value::Value as JsonValue;
+use serde_json;
+use statics;
+use std::cmp::min;
+use std::env;
+use uuid::Uuid;
+use super::scripts::{respond_to_script_deletion, respond_to_script_promotion, respond_to_script_rollback,
+                     respond_to_script_upload, respond_with_script, respond_with_script_history, respond_with_script_list};
+use super::util::*;
+
+/// The number of audit log entries returned when no `limit` is given.
+const DEFAULT_AUDIT_LIMIT: usize = 100;
+
+/// The maximum number of audit log entries returned by a single request.
+const MAX_AUDIT_LIMIT: usize = 1000;
+
+lazy_static! {
+    /// The secret that must be supplied as the basic auth password to use
+    /// admin routes. Admin routes are disabled if it is not set.
+    static ref ADMIN_SECRET: Option<String> = env::var("BRAID_ADMIN_SECRET").ok();
+}
+
+/// Checks that a request is authorized to use admin routes.
+///
+/// # Errors
+/// Returns an `IronError` if admin routes are disabled, or the request does
+/// not have the admin secret.
+fn check_admin_auth(req: &Request) -> Result<(), IronError> {
+    let secret = match *ADMIN_SECRET {
+        Some(ref secret) => secret,
+        None => {
+            return Err(create_iron_error(status::Forbidden, "admin_disabled", "Admin routes are disabled".to_string()));
+        }
+    };
+
+    let password = req.headers.get::<Authorization<Basic>>().and_then(|auth| auth.password.clone());
+
+    if !password.map_or(false, |password| common::secrets_match(&secret[..], &password[..])) {
+        return Err(create_iron_error(status::Unauthorized, "authentication_failed", "Authentication failed".to_string()));
+    }
+
+    Ok(())
+}
+
+pub fn audit(req: &mut Request) -> IronResult<Response> {
+    check_admin_auth(req)?;
+
+    if !audit_log_enabled() {
+        return Err(create_iron_error(status::NotFound, "audit_log_disabled", "The audit log is not enabled".to_string()));
+    }
+
+    let (filter, limit) = {
+        let query_params = get_query_params(req)?;
+        let filter = AuditFilter {
+            account_id: get_query_param::<Uuid>(query_params, "account_id", false)?,
+            from: get_query_param::<DateTime<UTC>>(query_params, "from", false)?,
+            to: get_query_param::<DateTime<UTC>>(query_params, "to", false)?,
+        };
+        let limit = get_query_param::<usize>(query_params, "limit", false)?.unwrap_or(DEFAULT_AUDIT_LIMIT);
+        (filter, min(limit, MAX_AUDIT_LIMIT))
+    };
+
+    let entries = match query_audit_log(&filter, limit) {
+        Ok(entries) => entries,
+        Err(err) => return Err(create_iron_error(status::InternalServerError, "internal_error", err)),
+    };
+
+    let entries: Vec<JsonValue> = entries.iter().map(|entry| entry.to_json()).collect();
+    Ok(to_response(req, status::Ok, &entries))
+}
+
+pub fn create_account(req: &mut Request) -> IronResult<Response> {
+    check_admin_auth(req)?;
+    let (account_id, secret) = datastore_request(common::create_account(&statics::DATASTORE))?;
+    record_audit_entry(&create_audit_entry(req, "create_account").with_account_id(Some(account_id)));
+    Ok(to_response(req, status::Ok, &account_credentials_to_json(account_id, secret)))
+}
+
+pub fn list_accounts(req: &mut Request) -> IronResult<Response> {
+    check_admin_auth(req)?;
+    let account_ids = datastore_request(common::list_accounts(&statics::DATASTORE))?;
+    Ok(to_response(req, status::Ok, &account_ids))
+}
+
+pub fn get_account(req: &mut Request) -> IronResult<Response> {
+    check_admin_auth(req)?;
+    let account_id: Uuid = get_url_param(req, "id")?;
+    let state = datastore_request(common::get_account_state(&statics::DATASTORE, account_id))?;
+    Ok(to_response(req, status::Ok, &account_to_json(account_id, &state)))
+}
+
+pub fn delete_account(req: &mut Request) -> IronResult<Response> {
+    check_admin_auth(req)?;
+    let account_id: Uuid = get_url_param(req, "id")?;
+    ensure_account_exists(account_id)?;
+    datastore_request(common::delete_account(&statics::DATASTORE, account_id))?;
+    record_audit_entry(&create_audit_entry(req, "delete_account").with_account_id(Some(account_id)));
+    Ok(to_response(req, status::Ok, &()))
+}
+
+pub fn disable_account(req: &mut Request) -> IronResult<Response> {
+    respond_to_account_state_change(req, true)
+}
+
+pub fn enable_account(req: &mut Request) -> IronResult<Response> {
+    respond_to_account_state_change(req, false)
+}
+
+pub fn rotate_account_secret(req: &mut Request) -> IronResult<Response> {
+    check_admin_auth(req)?;
+    let account_id: Uuid = get_url_param(req, "id")?;
+    let secret = datastore_request(common::rotate_account_secret(&statics::DATASTORE, account_id))?;
+    record_audit_entry(&create_audit_entry(req, "rotate_account_secret").with_account_id(Some(account_id)));
+    Ok(to_response(req, status::Ok, &account_credentials_to_json(account_id, secret)))
+}
+
+pub fn get_account_metadata(req: &mut Request) -> IronResult<Response> {
+    check_admin_auth(req)?;
+    let (account_id, key) = get_account_metadata_url_params(req)?;
+    let trans = datastore_request(statics::DATASTORE.transaction(account_id))?;
+    let value = datastore_request(trans.get_account_metadata(account_id, key))?;
+    datastore_request(trans.rollback())?;
+    Ok(to_response(req, status::Ok, &value))
+}
+
+pub fn set_account_metadata(req: &mut Request) -> IronResult<Response> {
+    check_admin_auth(req)?;
+    let (account_id, key) = get_account_metadata_url_params(req)?;
+    let value = read_required_json(req)?;
+    let trans = datastore_request(statics::DATASTORE.transaction(account_id))?;
+    datastore_request(trans.set_account_metadata(account_id, key.clone(), value))?;
+    datastore_request(trans.commit())?;
+    record_audit_entry(&create_audit_entry(req, "set_account_metadata").with_account_id(Some(account_id)).with_target(key));
+    Ok(to_response(req, status::Ok, &()))
+}
+
+pub fn delete_account_metadata(req: &mut Request) -> IronResult<Response> {
+    check_admin_auth(req)?;
+    let (account_id, key) = get_account_metadata_url_params(req)?;
+    let trans = datastore_request(statics::DATASTORE.transaction(account_id))?;
+    datastore_request(trans.delete_account_metadata(account_id, key.clone()))?;
+    datastore_request(trans.commit())?;
+    record_audit_entry(&create_audit_entry(req, "delete_account_metadata").with_account_id(Some(account_id)).with_target(key));
+    Ok(to_response(req, status::Ok, &()))
+}
+
+pub fn list_global_scripts(req: &mut Request) -> IronResult<Response> {
+    check_admin_auth(req)?;
+    let trans = datastore_request(statics::DATASTORE.transaction(Uuid::nil()))?;
+    respond_with_script_list(req, trans, &[ScriptScope::Global])
+}
+
+pub fn get_global_script(req: &mut Request) -> IronResult<Response> {
+    check_admin_auth(req)?;
+    let reference: String = get_url_param(req, "name")?;
+    let (name, version) = parse_script_reference(&reference[..])?;
+    let trans = datastore_request(statics::DATASTORE.transaction(Uuid::nil()))?;
+    let script = datastore_request(get_stored_script(&trans, ScriptScope::Global, &name[..], version))?;
+    datastore_request(trans.rollback())?;
+    respond_with_script(req, script)
+}
+
+pub fn get_global_script_versions(req: &mut Request) -> IronResult<Response> {
+    check_admin_auth(req)?;
+    let name: String = get_url_param(req, "name")?;
+    let trans = datastore_request(statics::DATASTORE.transaction(Uuid::nil()))?;
+    let history = datastore_request(get_script_history(&trans, ScriptScope::Global, &name[..]))?;
+    datastore_request(trans.rollback())?;
+    respond_with_script_history(req, history)
+}
+
+pub fn put_global_script(req: &mut Request) -> IronResult<Response> {
+    check_admin_auth(req)?;
+    let trans = datastore_request(statics::DATASTORE.transaction(Uuid::nil()))?;
+    respond_to_script_upload(req, trans, ScriptScope::Global)
+}
+
+pub fn promote_global_script(req: &mut Request) -> IronResult<Response> {
+    check_admin_auth(req)?;
+    let trans = datastore_request(statics::DATASTORE.transaction(Uuid::nil()))?;
+    respond_to_script_promotion(req, trans, ScriptScope::Global)
+}
+
+pub fn rollback_global_script(req: &mut Request) -> IronResult<Response> {
+    check_admin_auth(req)?;
+    let trans = datastore_request(statics::DATASTORE.transaction(Uuid::nil()))?;
+    respond_to_script_rollback(req, trans, ScriptScope::Global)
+}
+
+pub fn delete_global_script(req: &mut Request) -> IronResult<Response> {
+    check_admin_auth(req)?;
+    let trans = datastore_request(statics::DATASTORE.transaction(Uuid::nil()))?;
+    respond_to_script_deletion(req, trans, ScriptScope::Global)
+}
+
+fn respond_to_account_state_change(req: &mut Request, disabled: bool) -> IronResult<Response> {
+    check_admin_auth(req)?;
+    let account_id: Uuid = get_url_param(req, "id")?;
+    datastore_request(common::set_account_disabled(&statics::DATASTORE, account_id, disabled))?;
+    let action = if disabled { "disable_account" } else { "enable_account" };
+    record_audit_entry(&create_audit_entry(req, action).with_account_id(Some(account_id)));
+    let state = datastore_request(common::get_account_state(&statics::DATASTORE, account_id))?;
+    Ok(to_response(req, status::Ok, &account_to_json(account_id, &state)))
+}
+
+/// Gets the account ID and metadata key URL params of account metadata
+/// routes.
+///
+/// # Errors
+/// Returns an `IronError` if the account does not exist, or the key is
+/// reserved for internal use.
+fn get_account_metadata_url_params(req: &Request) -> Result<(Uuid, String), IronError> {
+    let account_id: Uuid = get_url_param(req, "id")?;
+    let key: String = get_url_param(req, "key")?;
+
+    if common::is_reserved_metadata_key(&key[..]) {
+        return Err(create_field_error(
+            status::BadRequest,
+            "reserved_metadata_key",
+            "key",
+            format!("Metadata key `{}` is reserved", key)
+        ));
+    }
+
+    ensure_account_exists(account_id)?;
+    Ok((account_id, key))
+}
+
+fn ensure_account_exists(account_id: Uuid) -> Result<(), IronError> {
+    if datastore_request(statics::DATASTORE.has_account(account_id))? {
+        Ok(())
+    } else {
+        Err(convert_to_iron_error(Error::AccountNotFound))
+    }
+}
+
+fn account_to_json(account_id: Uuid, state: &AccountState) -> JsonValue {
+    let mut o: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+    o.insert("id".to_string(), JsonValue::String(account_id.hyphenated().to_string()));
+    o.insert("disabled".to_string(), JsonValue::Bool(state.disabled));
+    o.insert("secret_rotated".to_string(), JsonValue::Bool(state.secret_rotated()));
+    JsonValue::Object(o)
+}
+
+fn account_credentials_to_json(account_id: Uuid, secret: String) -> JsonValue {
+    let mut o: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+    o.insert("id".to_string(), JsonValue::String(account_id.hyphenated().to_string()));
+    o.insert("secret".to_string(), JsonValue::String(secret));
+    JsonValue::Object(o)
+}
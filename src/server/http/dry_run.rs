@@ -0,0 +1,173 @@
+use iron::prelude::*;
+use iron::status;
+use braid::{Error, Transaction, EdgeKey, VertexQuery, EdgeQuery, QueryTypeConverter};
+use common::ProxyTransaction;
+use serde_json::value::Value as JsonValue;
+use serde_json;
+use std::cmp::min;
+use std::collections::HashSet;
+use std::u32;
+use uuid::Uuid;
+use super::util::*;
+
+/// The maximum number of vertex IDs and edge keys listed in a report.
+const SAMPLE_SIZE: u32 = 20;
+
+/// Describes what a deletion removed, from running it in a transaction that
+/// the caller then rolls back.
+///
+/// Metadata cannot be enumerated, so it's only counted under the keys that
+/// the client asks about. Otherwise, it's reported as unknown.
+pub struct DryRunReport {
+    vertices: u64,
+    edges: u64,
+    metadata: Option<u64>,
+
+    /// Up to `SAMPLE_SIZE` of the removed vertices
+    vertex_ids: Vec<Uuid>,
+
+    /// Up to `SAMPLE_SIZE` of the removed edges
+    edge_keys: Vec<EdgeKey>,
+}
+
+impl DryRunReport {
+    /// Deletes the vertices matched by a query, along with the edges going
+    /// into and out of them, and reports what was removed. The transaction
+    /// must be rolled back afterwards.
+    ///
+    /// The query is resolved to vertex IDs first, and those are what's
+    /// deleted, since deleting the outbound edges first could change what a
+    /// piped query matches. Outbound edges are deleted separately so that
+    /// edges between two of the vertices aren't counted twice.
+    pub fn for_vertex_deletion(trans: &ProxyTransaction, q: VertexQuery, metadata_keys: &[String]) -> Result<DryRunReport, Error> {
+        let ids: Vec<Uuid> = trans.get_vertices(q)?.into_iter().map(|vertex| vertex.id).collect();
+
+        if ids.is_empty() {
+            return Ok(DryRunReport::empty(metadata_keys));
+        }
+
+        let vertices = || VertexQuery::Vertices(ids.clone());
+        let edges = |converter: QueryTypeConverter, limit: u32| EdgeQuery::Pipe(Box::new(vertices()), converter, None, None, None, limit);
+        let mut metadata = if metadata_keys.is_empty() { None } else { Some(0) };
+
+        if let Some(ref mut metadata) = metadata {
+            for key in metadata_keys {
+                *metadata += trans.get_vertex_metadata(vertices(), key.clone())?.len() as u64;
+                *metadata += trans.get_edge_metadata(edges(QueryTypeConverter::Outbound, u32::MAX), key.clone())?.len() as u64;
+            }
+        }
+
+        let mut edge_keys = sample_edge_keys(trans, edges(QueryTypeConverter::Outbound, SAMPLE_SIZE))?;
+        let outbound_count = trans.get_edge_count(edges(QueryTypeConverter::Outbound, u32::MAX))?;
+        trans.delete_edges(edges(QueryTypeConverter::Outbound, u32::MAX))?;
+        let outbound_removed = outbound_count.saturating_sub(trans.get_edge_count(edges(QueryTypeConverter::Outbound, u32::MAX))?);
+
+        if let Some(ref mut metadata) = metadata {
+            for key in metadata_keys {
+                *metadata += trans.get_edge_metadata(edges(QueryTypeConverter::Inbound, u32::MAX), key.clone())?.len() as u64;
+            }
+        }
+
+        if (edge_keys.len() as u32) < SAMPLE_SIZE {
+            let remaining = SAMPLE_SIZE - edge_keys.len() as u32;
+            edge_keys.extend(sample_edge_keys(trans, edges(QueryTypeConverter::Inbound, remaining))?);
+        }
+
+        let inbound_count = trans.get_edge_count(edges(QueryTypeConverter::Inbound, u32::MAX))?;
+        trans.delete_vertices(vertices())?;
+        let inbound_removed = inbound_count.saturating_sub(trans.get_edge_count(edges(QueryTypeConverter::Inbound, u32::MAX))?);
+        let remaining: HashSet<Uuid> = trans.get_vertices(vertices())?.into_iter().map(|vertex| vertex.id).collect();
+        let removed: Vec<Uuid> = ids.iter().filter(|id| !remaining.contains(id)).cloned().collect();
+
+        Ok(DryRunReport {
+            vertices: removed.len() as u64,
+            edges: outbound_removed + inbound_removed,
+            metadata: metadata,
+            vertex_ids: removed.into_iter().take(SAMPLE_SIZE as usize).collect(),
+            edge_keys: edge_keys,
+        })
+    }
+
+    /// Deletes the edges matched by a query, and reports what was removed.
+    /// The transaction must be rolled back afterwards.
+    pub fn for_edge_deletion(trans: &ProxyTransaction, q: EdgeQuery, metadata_keys: &[String]) -> Result<DryRunReport, Error> {
+        let mut metadata = if metadata_keys.is_empty() { None } else { Some(0) };
+
+        if let Some(ref mut metadata) = metadata {
+            for key in metadata_keys {
+                *metadata += trans.get_edge_metadata(q.clone(), key.clone())?.len() as u64;
+            }
+        }
+
+        let edge_keys = sample_edge_keys(trans, limit_edge_query(q.clone(), SAMPLE_SIZE))?;
+        let count = trans.get_edge_count(q.clone())?;
+        trans.delete_edges(q.clone())?;
+        let removed = count.saturating_sub(trans.get_edge_count(q)?);
+
+        Ok(DryRunReport {
+            vertices: 0,
+            edges: removed,
+            metadata: metadata,
+            vertex_ids: Vec::new(),
+            edge_keys: edge_keys,
+        })
+    }
+
+    fn empty(metadata_keys: &[String]) -> DryRunReport {
+        DryRunReport {
+            vertices: 0,
+            edges: 0,
+            metadata: if metadata_keys.is_empty() { None } else { Some(0) },
+            vertex_ids: Vec::new(),
+            edge_keys: Vec::new(),
+        }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let vertex_ids: Vec<JsonValue> = self.vertex_ids.iter()
+            .map(|id| JsonValue::String(id.hyphenated().to_string()))
+            .collect();
+
+        let edge_keys: Vec<JsonValue> = self.edge_keys.iter()
+            .map(|key| serde_json::to_value(key).unwrap())
+            .collect();
+
+        let mut o: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+        o.insert("dry_run".to_string(), JsonValue::Bool(true));
+        o.insert("vertices".to_string(), JsonValue::from(self.vertices));
+        o.insert("edges".to_string(), JsonValue::from(self.edges));
+        o.insert("metadata".to_string(), self.metadata.map_or(JsonValue::Null, JsonValue::from));
+        o.insert("vertex_ids".to_string(), JsonValue::Array(vertex_ids));
+        o.insert("edge_keys".to_string(), JsonValue::Array(edge_keys));
+        JsonValue::Object(o)
+    }
+}
+
+/// Checks that a transaction can be used for a dry run, i.e. that rolling it
+/// back undoes the deletion.
+///
+/// # Errors
+/// Returns a `dry_run_unsupported` error if the datastore can't roll back
+/// transactions.
+pub fn check_dry_run_supported(trans: &ProxyTransaction) -> Result<(), IronError> {
+    if trans.can_roll_back() {
+        Ok(())
+    } else {
+        Err(create_iron_error(status::NotImplemented, "dry_run_unsupported", "Dry runs need a datastore that can roll back transactions".to_string()))
+    }
+}
+
+fn sample_edge_keys(trans: &ProxyTransaction, q: EdgeQuery) -> Result<Vec<EdgeKey>, Error> {
+    Ok(trans.get_edges(q)?.into_iter().map(|edge| edge.key).collect())
+}
+
+/// Narrows an edge query so that it matches at most `limit` edges.
+fn limit_edge_query(q: EdgeQuery, limit: u32) -> EdgeQuery {
+    match q {
+        EdgeQuery::Edge(key) => EdgeQuery::Edge(key),
+        EdgeQuery::Edges(keys) => EdgeQuery::Edges(keys.into_iter().take(limit as usize).collect()),
+        EdgeQuery::Pipe(vertex_query, converter, t, high, low, pipe_limit) => {
+            EdgeQuery::Pipe(vertex_query, converter, t, high, low, min(pipe_limit, limit))
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::error::Error;
+use std::fmt;
+use serde_json::value::Value as JsonValue;
+use serde_json;
+
+/// An error returned by the HTTP API.
+///
+/// Besides a human-readable message, each error has a stable,
+/// machine-readable code that clients can match on. Errors caused by a
+/// specific parameter or JSON field name it, and errors in batch requests
+/// carry the index of the item that failed. Errors from scripts that were run
+/// in debug mode carry what the script logged before it failed.
+#[derive(Clone, Debug)]
+pub struct ApiError {
+    pub code: &'static str,
+    pub message: String,
+    pub field: Option<String>,
+    pub index: Option<u16>,
+    pub logs: Option<Vec<JsonValue>>,
+}
+
+impl ApiError {
+    pub fn new(code: &'static str, message: String) -> ApiError {
+        ApiError {
+            code: code,
+            message: message,
+            field: None,
+            index: None,
+            logs: None,
+        }
+    }
+
+    /// Sets the parameter or field that caused the error.
+    pub fn with_field(mut self, field: &str) -> ApiError {
+        self.field = Some(field.to_string());
+        self
+    }
+
+    /// Sets the index of the batch item that caused the error.
+    pub fn with_index(mut self, index: u16) -> ApiError {
+        self.index = Some(index);
+        self
+    }
+
+    /// Sets the entries a script logged before it failed.
+    pub fn with_logs(mut self, logs: Vec<JsonValue>) -> ApiError {
+        self.logs = Some(logs);
+        self
+    }
+
+    /// Serializes the error into the JSON body sent to clients.
+    pub fn to_json(&self, request_id: Option<String>) -> JsonValue {
+        let mut o: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+        o.insert("error".to_string(), JsonValue::String(self.to_string()));
+        o.insert("code".to_string(), JsonValue::String(self.code.to_string()));
+        o.insert("message".to_string(), JsonValue::String(self.message.clone()));
+
+        if let Some(request_id) = request_id {
+            o.insert("request_id".to_string(), JsonValue::String(request_id));
+        }
+
+        if let Some(ref field) = self.field {
+            o.insert("field".to_string(), JsonValue::String(field.clone()));
+        }
+
+        if let Some(index) = self.index {
+            o.insert("index".to_string(), JsonValue::from(index));
+        }
+
+        if let Some(ref logs) = self.logs {
+            o.insert("logs".to_string(), JsonValue::Array(logs.clone()));
+        }
+
+        JsonValue::Object(o)
+    }
+}
+
+impl Error for ApiError {
+    fn description(&self) -> &str {
+        &self.message[..]
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
+
+impl fmt::Display for ApiError {
+    /// Formats the error the way it has historically appeared in the `error`
+    /// field, i.e. prefixed with the batch item index where there is one.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.index {
+            Some(index) => write!(f, "Item #{}: {}", index, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use iron::prelude::*;
+use iron::headers::{Accept, ContentType, QualityItem};
+use iron::mime::{Mime, TopLevel, SubLevel, Attr, Value};
+use rmp_serde;
+use rmp_serde::encode::StructMapWriter;
+use serde::{Serialize, Deserialize};
+use serde_cbor;
+use serde_json;
+
+/// A serialization format for request and response bodies. JSON is used
+/// unless the client asks for another format through the `Accept` or
+/// `Content-Type` headers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Format {
+    /// Gets the format described by a mime type, if it is supported.
+    /// Wildcards are treated as JSON.
+    fn from_mime(mime: &Mime) -> Option<Format> {
+        match (&mime.0, &mime.1) {
+            (&TopLevel::Application, &SubLevel::Json) => Some(Format::Json),
+            (&TopLevel::Application, &SubLevel::Ext(ref ext)) if ext == "msgpack" || ext == "x-msgpack" => Some(Format::MessagePack),
+            (&TopLevel::Application, &SubLevel::Ext(ref ext)) if ext == "cbor" => Some(Format::Cbor),
+            (&TopLevel::Application, &SubLevel::Star) |
+            (&TopLevel::Star, _) => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    /// Gets the format that a response should be serialized to, based on the
+    /// request's `Accept` header.
+    pub fn from_accept(req: &Request) -> Format {
+        let mut items: Vec<&QualityItem<Mime>> = match req.headers.get::<Accept>() {
+            Some(&Accept(ref items)) => items.iter().collect(),
+            None => return Format::Json,
+        };
+
+        // Sort by descending quality; the sort is stable, so mime types of
+        // equal quality keep the order the client listed them in
+        items.sort_by(|a, b| b.quality.cmp(&a.quality));
+
+        items.iter()
+            .filter(|item| item.quality.0 > 0)
+            .filter_map(|item| Format::from_mime(&item.item))
+            .next()
+            .unwrap_or(Format::Json)
+    }
+
+    /// Gets the format that a request body is serialized in, based on the
+    /// request's `Content-Type` header.
+    pub fn from_content_type(req: &Request) -> Format {
+        match req.headers.get::<ContentType>() {
+            Some(&ContentType(ref mime)) => Format::from_mime(mime).unwrap_or(Format::Json),
+            None => Format::Json,
+        }
+    }
+
+    /// The name of the format, for error messages
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Format::Json => "JSON",
+            Format::MessagePack => "MessagePack",
+            Format::Cbor => "CBOR",
+        }
+    }
+
+    pub fn mime(&self) -> Mime {
+        match *self {
+            Format::Json => Mime(TopLevel::Application, SubLevel::Json, vec![(Attr::Charset, Value::Utf8)]),
+            Format::MessagePack => Mime(TopLevel::Application, SubLevel::Ext("msgpack".to_string()), vec![]),
+            Format::Cbor => Mime(TopLevel::Application, SubLevel::Ext("cbor".to_string()), vec![]),
+        }
+    }
+
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        match *self {
+            Format::Json => serde_json::to_vec(value).map_err(|err| err.to_string()),
+            Format::MessagePack => {
+                // Structs are written as maps rather than arrays, so that
+                // they have the same shape as in JSON
+                let mut buf: Vec<u8> = Vec::new();
+                value.serialize(&mut rmp_serde::Serializer::with(&mut buf, StructMapWriter))
+                    .map_err(|err| err.to_string())?;
+                Ok(buf)
+            }
+            Format::Cbor => serde_cbor::to_vec(value).map_err(|err| err.to_string()),
+        }
+    }
+
+    pub fn deserialize<T: Deserialize>(&self, body: &[u8]) -> Result<T, String> {
+        match *self {
+            Format::Json => serde_json::from_slice(body).map_err(|err| err.to_string()),
+            Format::MessagePack => {
+                let mut de = rmp_serde::Deserializer::new(body);
+                T::deserialize(&mut de).map_err(|err| err.to_string())
+            }
+            Format::Cbor => serde_cbor::from_slice(body).map_err(|err| err.to_string()),
+        }
+    }
+}
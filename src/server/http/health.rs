@@ -0,0 +1,74 @@
+use iron::prelude::*;
+use iron::status;
+use braid::{Datastore, Transaction};
+use serde_json::value::Value as JsonValue;
+use serde_json;
+use common::script;
+use statics;
+use std::fs;
+use uuid::Uuid;
+use super::util::*;
+
+/// Reports that the process is alive. This does no other checks, so that
+/// orchestrators do not restart the server when a dependency is down.
+pub fn healthz(req: &mut Request) -> IronResult<Response> {
+    let mut body: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+    body.insert("status".to_string(), JsonValue::String("ok".to_string()));
+    Ok(to_response(req, status::Ok, &body))
+}
+
+/// Reports whether the server is able to handle requests, with a breakdown
+/// of each check that was made.
+pub fn readyz(req: &mut Request) -> IronResult<Response> {
+    let checks = vec![
+        ("datastore", check_datastore()),
+        ("script_root", check_script_root()),
+    ];
+
+    let mut ready = true;
+    let mut checks_body: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+
+    for (name, result) in checks {
+        let mut check_body: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+
+        match result {
+            Ok(_) => {
+                check_body.insert("ok".to_string(), JsonValue::Bool(true));
+            }
+            Err(err) => {
+                ready = false;
+                check_body.insert("ok".to_string(), JsonValue::Bool(false));
+                check_body.insert("error".to_string(), JsonValue::String(err));
+            }
+        }
+
+        checks_body.insert(name.to_string(), JsonValue::Object(check_body));
+    }
+
+    let mut body: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+    body.insert("ready".to_string(), JsonValue::Bool(ready));
+    body.insert("checks".to_string(), JsonValue::Object(checks_body));
+
+    if ready {
+        Ok(to_response(req, status::Ok, &body))
+    } else {
+        Ok(to_response(req, status::ServiceUnavailable, &body))
+    }
+}
+
+/// Checks that a transaction can be opened and rolled back. No account is
+/// needed for this, since nothing is read or written.
+fn check_datastore() -> Result<(), String> {
+    match statics::DATASTORE.transaction(Uuid::nil()) {
+        Ok(trans) => trans.rollback().map_err(|err| format!("Could not roll back transaction: {}", err)),
+        Err(err) => Err(format!("Could not create datastore transaction: {}", err)),
+    }
+}
+
+/// Checks that the script root directory can be listed.
+fn check_script_root() -> Result<(), String> {
+    match fs::read_dir(&script::SCRIPT_ROOT[..]) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Could not read script root `{}`: {}", *script::SCRIPT_ROOT, err)),
+    }
+}
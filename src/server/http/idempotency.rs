@@ -0,0 +1,260 @@
+use iron::prelude::*;
+use iron::status;
+use iron::method::Method;
+use iron::headers::Headers;
+use iron::middleware::Handler;
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use super::util::*;
+
+lazy_static! {
+    /// How long responses are kept for replay, in seconds
+    static ref IDEMPOTENCY_WINDOW: Duration = match env::var("BRAID_IDEMPOTENCY_WINDOW") {
+        Ok(s) => Duration::from_secs(s.parse().expect("Could not parse environment variable `BRAID_IDEMPOTENCY_WINDOW`: must be a u64")),
+        Err(_) => Duration::from_secs(24 * 60 * 60),
+    };
+
+    /// The most keys that are kept at once. The oldest keys are dropped to
+    /// make room for new ones, even if they haven't expired yet.
+    static ref IDEMPOTENCY_MAX_KEYS: usize = match env::var("BRAID_IDEMPOTENCY_MAX_KEYS") {
+        Ok(s) => s.parse().expect("Could not parse environment variable `BRAID_IDEMPOTENCY_MAX_KEYS`: must be a usize"),
+        Err(_) => 100000,
+    };
+
+    static ref STORE: Mutex<Store> = Mutex::new(Store {
+        requests: HashMap::new(),
+        expiry: VecDeque::new(),
+    });
+}
+
+type StoreKey = (Uuid, String);
+
+/// A response that was produced for an idempotency key.
+#[derive(Clone)]
+struct StoredResponse {
+    status: status::Status,
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+/// The state of a request made with an idempotency key.
+struct StoredRequest {
+    /// Hash of the method, URL and body of the original request, to detect
+    /// keys reused for different requests
+    fingerprint: u64,
+    created: Instant,
+
+    /// The response, or `None` if the original request is still in flight
+    response: Option<StoredResponse>,
+}
+
+/// The requests made with idempotency keys, along with the order they were
+/// made in, so that expired keys can be found without a scan.
+struct Store {
+    requests: HashMap<StoreKey, StoredRequest>,
+
+    /// Keys in the order they were stored, with when they were stored. A key
+    /// that was removed and stored again is in here twice; only the entry
+    /// that matches the `created` of the request counts.
+    expiry: VecDeque<(Instant, StoreKey)>,
+}
+
+impl Store {
+    fn insert(&mut self, key: StoreKey, fingerprint: u64) {
+        let created = Instant::now();
+
+        // The queue holds at least as many entries as the map, so bounding
+        // it bounds both.
+        while self.expiry.len() >= *IDEMPOTENCY_MAX_KEYS && self.pop_oldest() {}
+
+        self.expiry.push_back((created, key.clone()));
+        self.requests.insert(key, StoredRequest {
+            fingerprint: fingerprint,
+            created: created,
+            response: None,
+        });
+    }
+
+    fn remove_expired(&mut self) {
+        loop {
+            match self.expiry.front() {
+                Some(&(created, _)) if created.elapsed() > *IDEMPOTENCY_WINDOW => (),
+                _ => return,
+            }
+
+            self.pop_oldest();
+        }
+    }
+
+    /// Removes the key that was stored first. Returns `false` if there are
+    /// no keys left.
+    fn pop_oldest(&mut self) -> bool {
+        let (created, key) = match self.expiry.pop_front() {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        if self.requests.get(&key).map_or(false, |stored| stored.created == created) {
+            self.requests.remove(&key);
+        }
+
+        true
+    }
+}
+
+/// Removes the key of a request that is in flight when dropped, unless a
+/// response was stored for it. This keeps a failed or panicked request from
+/// leaving its key in progress.
+struct InProgress {
+    key: StoreKey,
+    created: Instant,
+}
+
+impl Drop for InProgress {
+    fn drop(&mut self) {
+        let mut store = lock_store();
+
+        let in_progress = match store.requests.get(&self.key) {
+            Some(stored) => stored.created == self.created && stored.response.is_none(),
+            None => false,
+        };
+
+        if in_progress {
+            store.requests.remove(&self.key);
+        }
+    }
+}
+
+/// Wraps a route handler, so that mutating requests sent with an
+/// `Idempotency-Key` header are only executed once per account and key.
+///
+/// Repeated requests get the original response replayed, as long as they
+/// come within the idempotency window. Only successful responses are kept;
+/// if the original request failed, nothing was committed, and a retry with
+/// the same key is executed again.
+pub struct IdempotentHandler<H: Handler> {
+    handler: H,
+}
+
+impl<H: Handler> IdempotentHandler<H> {
+    pub fn new(handler: H) -> IdempotentHandler<H> {
+        IdempotentHandler { handler: handler }
+    }
+}
+
+impl<H: Handler> Handler for IdempotentHandler<H> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let key = match get_header_string(req, "Idempotency-Key") {
+            Some(key) => key,
+            None => return self.handler.handle(req),
+        };
+
+        let account_id = match req.extensions.get::<AccountKey>() {
+            Some(account) => account.account_id,
+            None => return self.handler.handle(req),
+        };
+
+        if req.method == Method::Get || req.method == Method::Head || req.method == Method::Options {
+            return self.handler.handle(req);
+        }
+
+        let store_key = (account_id, key);
+        let fingerprint = get_fingerprint(req)?;
+
+        let in_progress = {
+            let mut store = lock_store();
+            store.remove_expired();
+
+            if let Some(stored) = store.requests.get(&store_key) {
+                if stored.fingerprint != fingerprint {
+                    return Err(create_field_error(
+                        status::UnprocessableEntity,
+                        "idempotency_key_reused",
+                        "Idempotency-Key",
+                        "Idempotency key was already used for a different request".to_string()
+                    ));
+                }
+
+                return match stored.response {
+                    Some(ref response) => Ok(replay(response)),
+                    None => {
+                        Err(create_field_error(
+                            status::Conflict,
+                            "idempotency_key_in_use",
+                            "Idempotency-Key",
+                            "A request with this idempotency key is still in progress".to_string()
+                        ))
+                    }
+                };
+            }
+
+            store.insert(store_key.clone(), fingerprint);
+
+            InProgress {
+                created: store.requests[&store_key].created,
+                key: store_key,
+            }
+        };
+
+        let (res, body) = self.handler.handle(req).and_then(|mut res| {
+            match take_response_body(&mut res) {
+                Ok(body) => {
+                    res.body = Some(Box::new(body.clone()));
+                    Ok((res, body))
+                }
+                Err(err) => {
+                    Err(create_iron_error(status::InternalServerError, "internal_error", format!("Could not read response body: {}", err)))
+                }
+            }
+        })?;
+
+        // The lock is released before `in_progress` is dropped, since that
+        // takes it again.
+        {
+            let mut store = lock_store();
+
+            if let Some(stored) = store.requests.get_mut(&in_progress.key) {
+                if stored.created == in_progress.created {
+                    stored.response = Some(StoredResponse {
+                        status: res.status.unwrap_or(status::Ok),
+                        headers: res.headers.clone(),
+                        body: body,
+                    });
+                }
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+/// Locks the store. A panic while the store is locked can't leave it in an
+/// inconsistent state, so a poisoned lock is used as is, rather than failing
+/// every later request.
+fn lock_store() -> MutexGuard<'static, Store> {
+    match STORE.lock() {
+        Ok(store) => store,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn get_fingerprint(req: &mut Request) -> Result<u64, IronError> {
+    let body = read_body(req)?;
+    let mut hasher = DefaultHasher::new();
+    req.method.to_string().hash(&mut hasher);
+    req.url.to_string().hash(&mut hasher);
+    body.hash(&mut hasher);
+    Ok(hasher.finish())
+}
+
+fn replay(stored: &StoredResponse) -> Response {
+    let mut res = Response::with((stored.status, stored.body.clone()));
+    res.headers = stored.headers.clone();
+    res.headers.set_raw("Idempotent-Replayed", vec![b"true".to_vec()]);
+    res
+}
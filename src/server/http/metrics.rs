@@ -0,0 +1,34 @@
+use iron::prelude::*;
+use iron::status;
+use iron::headers::{Authorization, Basic, ContentType};
+use iron::mime::{Mime, TopLevel, SubLevel, Attr, Value};
+use common;
+use metrics::METRICS;
+use std::env;
+use super::util::*;
+
+lazy_static! {
+    /// An optional secret that must be supplied as the basic auth password
+    /// to read metrics. Metrics are not protected by account credentials, so
+    /// that scrapers do not need an account.
+    static ref METRICS_SECRET: Option<String> = env::var("BRAID_METRICS_SECRET").ok();
+}
+
+pub fn metrics(req: &mut Request) -> IronResult<Response> {
+    if let Some(ref secret) = *METRICS_SECRET {
+        let password = req.headers.get::<Authorization<Basic>>().and_then(|auth| auth.password.clone());
+        let authorized = match password {
+            Some(ref password) => common::secrets_match(secret, password),
+            None => false,
+        };
+
+        if !authorized {
+            return Err(create_iron_error(status::Unauthorized, "authentication_failed", "Authentication failed".to_string()));
+        }
+    }
+
+    let mime = Mime(TopLevel::Text, SubLevel::Plain, vec![(Attr::Charset, Value::Utf8)]);
+    let mut response = Response::with((status::Ok, METRICS.render()));
+    response.headers.set(ContentType(mime));
+    Ok(response)
+}
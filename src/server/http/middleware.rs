@@ -1,25 +1,83 @@
 use iron::prelude::*;
-use iron::headers::{Authorization, Basic};
-use braid::Datastore;
-use std::collections::BTreeMap;
+use iron::headers::{Authorization, Basic, Connection, ContentType};
 use statics;
 use uuid::Uuid;
-use iron::middleware::{BeforeMiddleware, AfterMiddleware};
+use iron::middleware::{BeforeMiddleware, AfterMiddleware, Handler};
 use serde_json;
+use serde_json::value::Value as JsonValue;
 use iron::status;
 use router::NoRoute;
-use util::SimpleError;
+use logging::Level;
+use metrics::METRICS;
+use shutdown;
+use shutdown::InFlightGuard;
+use common::{authenticate, record_audit_entry};
 use super::util::*;
+use super::errors::ApiError;
+use super::format::Format;
 use core::str::FromStr;
-use iron::headers::ContentType;
+use iron::headers::{AcceptEncoding, ContentEncoding, Encoding};
+use iron::method::Method;
+use flate2::Compression;
+use flate2::write::{GzEncoder, DeflateEncoder};
+use std::collections::HashMap;
+use std::env;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Paths that are served without account authentication.
+const PUBLIC_PATHS: &'static [&'static str] = &["metrics", "healthz", "readyz"];
+
+/// The prefix of admin paths, which are authenticated with the admin secret
+/// instead of account credentials.
+const ADMIN_PATH_PREFIX: &'static str = "admin/";
+
+/// The maximum length of request IDs given in the `X-Request-Id` header.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+lazy_static! {
+    /// How often successful authentications are recorded in the audit log,
+    /// per account. Every authenticated request would add an entry
+    /// otherwise. If zero, every successful authentication is recorded.
+    static ref AUDIT_AUTHENTICATION_INTERVAL: Duration = match env::var("BRAID_AUDIT_AUTHENTICATION_INTERVAL") {
+        Ok(s) => Duration::from_secs(s.parse().expect("Could not parse environment variable `BRAID_AUDIT_AUTHENTICATION_INTERVAL`: must be a u64")),
+        Err(_) => Duration::from_secs(60),
+    };
+}
 
 /// Basic HTTP auth middleware.
 pub struct BasicAuthMiddleware {
+    /// When a successful authentication was last recorded in the audit log,
+    /// by account
+    last_audited: Mutex<HashMap<Uuid, Instant>>,
 }
 
 impl BasicAuthMiddleware {
     pub fn new() -> BasicAuthMiddleware {
-        BasicAuthMiddleware {}
+        BasicAuthMiddleware {
+            last_audited: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns whether a successful authentication of an account should be
+    /// recorded in the audit log, i.e. whether none has been recorded
+    /// within the interval.
+    fn should_audit_success(&self, account_id: Uuid) -> bool {
+        let now = Instant::now();
+        let interval = *AUDIT_AUTHENTICATION_INTERVAL;
+        let mut last_audited = self.last_audited.lock().unwrap();
+
+        if let Some(&last) = last_audited.get(&account_id) {
+            if now.duration_since(last) < interval {
+                return false;
+            }
+        }
+
+        let recent: HashMap<Uuid, Instant> = last_audited.drain().filter(|&(_, last)| now.duration_since(last) < interval).collect();
+        *last_audited = recent;
+        last_audited.insert(account_id, now);
+        true
     }
 
     fn get_account_id(&self, auth: Option<&Authorization<Basic>>) -> Option<Uuid> {
@@ -43,40 +101,33 @@ impl BasicAuthMiddleware {
 
 impl BeforeMiddleware for BasicAuthMiddleware {
     fn before(&self, req: &mut Request) -> IronResult<()> {
+        let path = req.url.path().join("/");
+
+        // CORS preflight requests never carry credentials, so they have to
+        // be let through for `CorsMiddleware` to answer them.
+        if PUBLIC_PATHS.contains(&&path[..]) || path.starts_with(ADMIN_PATH_PREFIX) || is_cors_preflight(req) {
+            return Ok(());
+        }
+
         let auth = req.headers.get::<Authorization<Basic>>();
         let account_id = self.get_account_id(auth);
         let secret = self.get_secret(auth);
 
         if account_id.is_some() && secret.is_some() &&
-           statics::DATASTORE.auth(account_id.unwrap(), secret.unwrap()).unwrap_or(false) {
+           authenticate(&statics::DATASTORE, account_id.unwrap(), secret.unwrap()).unwrap_or(false) {
             req.extensions.insert::<AccountKey>(AccountKey { account_id: account_id.unwrap() });
 
+            if self.should_audit_success(account_id.unwrap()) {
+                record_audit_entry(&create_audit_entry(req, "authenticate"));
+            }
+
             return Ok(());
         }
 
-        let error_message = "Authentication failed".to_string();
-
-        let mut d: BTreeMap<String, String> = BTreeMap::new();
-        d.insert("error".to_string(), error_message.clone());
-        let body = serde_json::to_string(&d).unwrap();
-
-        // NOTE: Right now we're manually constructing the `Response` and
-        // `IronError`. Ideally this would not happen, however otherwise we'd
-        // need to resort to the use of `Modifier`. Because we're setting a
-        // custom header, that requires a lot of plumbing at the moment. There
-        // should be a more terse way to do this - at least in future versions
-        // of iron.
-        let mut response = Response::new();
-        response.status = Some(status::Unauthorized);
-        response.headers.set(ContentType(get_json_mime()));
-        response.headers.set_raw("WWW-Authenticate", vec!("Basic realm=\"main\"".as_bytes().to_vec()));
-        response.body = Some(Box::new(body));
-
-        let error = IronError{
-            error: Box::new(SimpleError::new(error_message)),
-            response: response
-        };
-
+        METRICS.auth_failures.inc(&[]);
+        record_audit_entry(&create_audit_entry(req, "authenticate").with_account_id(account_id).with_success(false));
+        let mut error = create_iron_error(status::Unauthorized, "authentication_failed", "Authentication failed".to_string());
+        error.response.headers.set_raw("WWW-Authenticate", vec!("Basic realm=\"main\"".as_bytes().to_vec()));
         Err(error)
     }
 }
@@ -84,7 +135,8 @@ impl BeforeMiddleware for BasicAuthMiddleware {
 /// Error middleware
 ///
 /// This produces a standard JSON body if an error occurred, and no JSON
-/// body has been specified yet.
+/// body has been specified yet. API errors get the request ID added to
+/// their bodies.
 pub struct ErrorMiddleware {
 }
 
@@ -99,11 +151,474 @@ impl AfterMiddleware for ErrorMiddleware {
         Ok(res)
     }
 
-    fn catch(&self, _: &mut Request, err: IronError) -> IronResult<Response> {
-        if err.error.is::<NoRoute>() {
-            Err(create_iron_error(status::Status::NotFound, "No route found".to_string()))
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        let mut err = if err.error.is::<NoRoute>() {
+            create_iron_error(status::Status::NotFound, "no_route", "No route found".to_string())
+        } else {
+            err
+        };
+
+        let format = Format::from_accept(req);
+
+        let body = match err.error.downcast_ref::<ApiError>() {
+            Some(api_error) => Some(format.serialize(&api_error.to_json(get_request_id(req))).unwrap()),
+            None => None,
+        };
+
+        if let Some(body) = body {
+            err.response.headers.set(ContentType(format.mime()));
+            err.response.body = Some(Box::new(body));
+        }
+
+        Err(err)
+    }
+}
+
+/// Wraps a route handler, so that the name of the route is available to
+/// middleware through the `RouteKey` extension.
+pub struct NamedHandler<H: Handler> {
+    name: &'static str,
+    handler: H,
+}
+
+impl<H: Handler> NamedHandler<H> {
+    pub fn new(name: &'static str, handler: H) -> NamedHandler<H> {
+        NamedHandler {
+            name: name,
+            handler: handler,
+        }
+    }
+}
+
+impl<H: Handler> Handler for NamedHandler<H> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        req.extensions.insert::<RouteKey>(RouteKey { name: self.name });
+        self.handler.handle(req)
+    }
+}
+
+/// Access log middleware
+///
+/// This assigns each request an ID - either the one given in the
+/// `X-Request-Id` header, or a newly generated one - and logs one JSON line
+/// per request once a response has been produced. It runs after
+/// `CompressionMiddleware`, so the logged size is that of the body as sent,
+/// after any compression. Given IDs end up in logs
+/// and response headers, so they're only used if they're short and made of
+/// characters that are safe there.
+pub struct RequestLogMiddleware {
+}
+
+impl RequestLogMiddleware {
+    pub fn new() -> RequestLogMiddleware {
+        RequestLogMiddleware {}
+    }
+
+    fn log(&self, req: &mut Request, res: &mut Response, error: Option<String>) {
+        let status = res.status.unwrap_or(status::Ok).to_u16();
+
+        // The body has to be buffered to figure out its size. This is a
+        // no-op for most responses, since they're already in-memory strings.
+        let size = match take_response_body(res) {
+            Ok(body) => {
+                let size = body.len();
+                res.body = Some(Box::new(body));
+                JsonValue::from(size as u64)
+            }
+            Err(_) => JsonValue::Null,
+        };
+
+        let (request_id, latency) = match req.extensions.get::<RequestInfoKey>() {
+            Some(info) => {
+                let elapsed = info.start.elapsed();
+                let latency = elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_nanos() as f64 / 1_000_000.0;
+                (JsonValue::String(info.request_id.clone()), JsonValue::from(latency))
+            }
+            None => (JsonValue::Null, JsonValue::Null),
+        };
+
+        let account_id = match req.extensions.get::<AccountKey>() {
+            Some(key) => JsonValue::String(key.account_id.to_string()),
+            None => JsonValue::Null,
+        };
+
+        let route = match get_route_name(req) {
+            Some(name) => JsonValue::String(name.to_string()),
+            None => JsonValue::Null,
+        };
+
+        let mut entry: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+        entry.insert("request_id".to_string(), request_id);
+        entry.insert("account_id".to_string(), account_id);
+        entry.insert("method".to_string(), JsonValue::String(req.method.to_string()));
+        entry.insert("route".to_string(), route);
+        entry.insert("status".to_string(), JsonValue::from(status));
+        entry.insert("latency_ms".to_string(), latency);
+        entry.insert("size".to_string(), size);
+
+        if let Some(error) = error {
+            entry.insert("error".to_string(), JsonValue::String(error));
+        }
+
+        let level = if status >= 500 {
+            Level::Error
+        } else if status >= 400 {
+            Level::Warn
+        } else {
+            Level::Info
+        };
+
+        statics::LOGGER.log(level, entry);
+    }
+}
+
+impl BeforeMiddleware for RequestLogMiddleware {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let request_id = match get_header_string(req, "X-Request-Id") {
+            Some(ref request_id) if is_valid_request_id(request_id) => request_id.clone(),
+            _ => Uuid::new_v4().hyphenated().to_string(),
+        };
+
+        req.extensions.insert::<RequestInfoKey>(RequestInfoKey {
+            request_id: request_id,
+            start: Instant::now(),
+        });
+
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for RequestLogMiddleware {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        if let Some(request_id) = get_request_id(req) {
+            res.headers.set_raw("X-Request-Id", vec![request_id.into_bytes()]);
+        }
+
+        self.log(req, &mut res, None);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        if let Some(request_id) = get_request_id(req) {
+            err.response.headers.set_raw("X-Request-Id", vec![request_id.into_bytes()]);
+        }
+
+        let message = format!("{}", err.error);
+        self.log(req, &mut err.response, Some(message));
+        Err(err)
+    }
+}
+
+/// Drain middleware
+///
+/// This tracks which requests are in flight, so that shutdown can wait for
+/// them to finish. Once the server is draining, new requests are rejected,
+/// and responses ask clients to close their connections.
+pub struct DrainMiddleware {
+}
+
+impl DrainMiddleware {
+    pub fn new() -> DrainMiddleware {
+        DrainMiddleware {}
+    }
+}
+
+impl BeforeMiddleware for DrainMiddleware {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if shutdown::is_draining() {
+            let mut err = create_iron_error(status::ServiceUnavailable, "shutting_down", "The server is shutting down".to_string());
+            err.response.headers.set(Connection::close());
+            return Err(err);
+        }
+
+        req.extensions.insert::<InFlightKey>(InFlightGuard::new());
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for DrainMiddleware {
+    fn after(&self, _: &mut Request, mut res: Response) -> IronResult<Response> {
+        if shutdown::is_draining() {
+            res.headers.set(Connection::close());
+        }
+
+        Ok(res)
+    }
+
+    fn catch(&self, _: &mut Request, mut err: IronError) -> IronResult<Response> {
+        if shutdown::is_draining() {
+            err.response.headers.set(Connection::close());
+        }
+
+        Err(err)
+    }
+}
+
+/// Metrics middleware
+///
+/// This records the number of requests and their latencies by route and
+/// status. It relies on `RequestLogMiddleware` to mark when each request
+/// started.
+pub struct MetricsMiddleware {
+}
+
+impl MetricsMiddleware {
+    pub fn new() -> MetricsMiddleware {
+        MetricsMiddleware {}
+    }
+
+    fn record(&self, req: &Request, res: &Response) {
+        let route = get_route_name(req).unwrap_or("none");
+        let status = res.status.unwrap_or(status::Ok).to_u16().to_string();
+        METRICS.http_requests.inc(&[route, &status[..]]);
+
+        if let Some(info) = req.extensions.get::<RequestInfoKey>() {
+            METRICS.http_request_duration.observe_duration(&[route, &status[..]], info.start.elapsed());
+        }
+    }
+}
+
+impl AfterMiddleware for MetricsMiddleware {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        self.record(req, &res);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        self.record(req, &err.response);
+        Err(err)
+    }
+}
+
+/// CORS middleware
+///
+/// This answers preflight requests from allowed origins, and adds CORS
+/// headers to every response - including errors - for allowed origins. If
+/// any origin is allowed, responses allow `*`. Otherwise they echo the
+/// request's origin if it's on the allow-list, and vary by origin, so that
+/// caches don't serve one origin's response to another.
+pub struct CorsMiddleware {
+    allowed_origins: Vec<String>,
+    allowed_methods: String,
+    allowed_headers: String,
+}
+
+impl CorsMiddleware {
+    pub fn new(allowed_origins: Vec<String>, allowed_methods: String, allowed_headers: String) -> CorsMiddleware {
+        CorsMiddleware {
+            allowed_origins: allowed_origins,
+            allowed_methods: allowed_methods,
+            allowed_headers: allowed_headers,
+        }
+    }
+
+    fn allows_any_origin(&self) -> bool {
+        self.allowed_origins.iter().any(|allowed| allowed == "*")
+    }
+
+    /// Gets the `Access-Control-Allow-Origin` value for the request, if its
+    /// origin is allowed.
+    fn allowed_origin(&self, req: &Request) -> Option<String> {
+        let origin = match get_header_string(req, "Origin") {
+            Some(origin) => origin,
+            None => return None,
+        };
+
+        if self.allows_any_origin() {
+            Some("*".to_string())
+        } else if self.allowed_origins.contains(&origin) {
+            Some(origin)
+        } else {
+            None
+        }
+    }
+
+    fn add_headers(&self, origin: Option<String>, res: &mut Response) {
+        if !self.allows_any_origin() {
+            add_vary_header(res, "Origin");
+        }
+
+        if let Some(origin) = origin {
+            res.headers.set_raw("Access-Control-Allow-Origin", vec![origin.into_bytes()]);
+            res.headers.set_raw("Access-Control-Expose-Headers", vec![b"X-Request-Id".to_vec()]);
+        }
+    }
+
+    fn preflight_response(&self, origin: String) -> Response {
+        let mut res = Response::with(status::NoContent);
+        self.add_headers(Some(origin), &mut res);
+        res.headers.set_raw("Access-Control-Allow-Methods", vec![self.allowed_methods.clone().into_bytes()]);
+        res.headers.set_raw("Access-Control-Allow-Headers", vec![self.allowed_headers.clone().into_bytes()]);
+        res.headers.set_raw("Access-Control-Max-Age", vec![b"86400".to_vec()]);
+        res
+    }
+}
+
+impl AfterMiddleware for CorsMiddleware {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        let origin = self.allowed_origin(req);
+
+        if let Some(ref origin) = origin {
+            if is_cors_preflight(req) {
+                return Ok(self.preflight_response(origin.clone()));
+            }
+        }
+
+        self.add_headers(origin, &mut res);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        let origin = self.allowed_origin(req);
+
+        // Preflight requests to paths without routes end up here as routing
+        // errors, but should still be answered.
+        if let Some(ref origin) = origin {
+            if is_cors_preflight(req) {
+                return Ok(self.preflight_response(origin.clone()));
+            }
+        }
+
+        self.add_headers(origin, &mut err.response);
+        Err(err)
+    }
+}
+
+/// Returns whether a request ID given by a client is acceptable: non-empty,
+/// at most `MAX_REQUEST_ID_LENGTH` characters, and made of ASCII letters,
+/// digits, `.`, `_` and `-`.
+fn is_valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty() && request_id.len() <= MAX_REQUEST_ID_LENGTH &&
+    request_id.chars().all(|c| match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '.' | '_' | '-' => true,
+        _ => false,
+    })
+}
+
+/// Returns whether the request is a CORS preflight request.
+fn is_cors_preflight(req: &Request) -> bool {
+    req.method == Method::Options && req.headers.get_raw("Origin").is_some() &&
+    req.headers.get_raw("Access-Control-Request-Method").is_some()
+}
+
+/// Responses smaller than this many bytes are not compressed, since the
+/// savings would not be worth the overhead.
+const MIN_COMPRESSION_SIZE: usize = 1024;
+
+/// Compression middleware
+///
+/// This compresses response bodies, including error bodies, with gzip or
+/// deflate, depending on what the client accepts via the `Accept-Encoding`
+/// header.
+pub struct CompressionMiddleware {
+}
+
+impl CompressionMiddleware {
+    pub fn new() -> CompressionMiddleware {
+        CompressionMiddleware {}
+    }
+
+    /// Picks the encoding to use for a request, preferring gzip.
+    fn negotiate(&self, req: &Request) -> Option<Encoding> {
+        let accepted = match req.headers.get::<AcceptEncoding>() {
+            Some(&AcceptEncoding(ref accepted)) => accepted,
+            None => return None,
+        };
+
+        let accepts = |encoding: Encoding| {
+            accepted.iter().any(|item| item.item == encoding && item.quality.0 > 0)
+        };
+
+        if accepts(Encoding::Gzip) {
+            Some(Encoding::Gzip)
+        } else if accepts(Encoding::Deflate) {
+            Some(Encoding::Deflate)
         } else {
-            Err(err)
+            None
         }
     }
+
+    /// Compresses a response's body in place, if the client accepts an
+    /// encoding and the body is big enough to be worth it. If compression
+    /// fails, the body is left uncompressed.
+    ///
+    /// # Errors
+    /// Returns an error message if the body could not be read.
+    fn compress(&self, req: &Request, res: &mut Response) -> Result<(), String> {
+        if res.headers.has::<ContentEncoding>() {
+            return Ok(());
+        }
+
+        let encoding = match self.negotiate(req) {
+            Some(encoding) => encoding,
+            None => return Ok(()),
+        };
+
+        let body = match take_response_body(res) {
+            Ok(body) => body,
+            Err(err) => return Err(format!("Could not read response body: {}", err)),
+        };
+
+        add_vary_header(res, "Accept-Encoding");
+
+        if body.len() < MIN_COMPRESSION_SIZE {
+            res.body = Some(Box::new(body));
+            return Ok(());
+        }
+
+        let compressed = match encoding {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+                encoder.write_all(&body[..]).and_then(|_| encoder.finish())
+            }
+            _ => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::Default);
+                encoder.write_all(&body[..]).and_then(|_| encoder.finish())
+            }
+        };
+
+        match compressed {
+            Ok(compressed) => {
+                res.headers.set(ContentEncoding(vec![encoding]));
+                res.body = Some(Box::new(compressed));
+            }
+            Err(_) => {
+                res.body = Some(Box::new(body));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for CompressionMiddleware {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        match self.compress(req, &mut res) {
+            Ok(_) => Ok(res),
+            Err(message) => Err(create_iron_error(status::InternalServerError, "internal_error", message)),
+        }
+    }
+
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        // An error body that can't be read is passed on as is, rather than
+        // replacing the original error
+        let _ = self.compress(req, &mut err.response);
+        Err(err)
+    }
+}
+
+/// Adds a value to the `Vary` header of a response, keeping any existing
+/// values.
+fn add_vary_header(res: &mut Response, value: &str) {
+    let mut values: Vec<String> = match res.headers.get_raw("Vary") {
+        Some(raw) => {
+            raw.iter()
+                .filter_map(|v| String::from_utf8(v.clone()).ok())
+                .collect()
+        }
+        None => Vec::new(),
+    };
+
+    values.push(value.to_string());
+    res.headers.set_raw("Vary", vec![values.join(", ").into_bytes()]);
 }
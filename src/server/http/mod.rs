@@ -1,33 +1,180 @@
+mod admin;
+mod dry_run;
+mod errors;
+mod format;
+mod health;
+mod idempotency;
+mod metrics;
 mod middleware;
 mod rest;
+mod scripts;
+mod tls;
 mod transaction;
 mod util;
 
+use hyper::net::{HttpListener, HttpsListener};
 use iron::prelude::*;
+use iron::{Listening, Protocol};
 use router::Router;
+use shutdown::{self, DrainingListener};
+use std::env;
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
 use std::u16;
 
-/// Starts a new server on the given port.
-pub fn start(port: u16) {
+pub use self::tls::TlsConfig;
+
+/// Adds a route to a router, tagging matched requests with the route's name
+/// so that middleware can refer to it, and honoring idempotency keys.
+macro_rules! route {
+    ($router:ident, $method:ident, $glob:expr, $handler:expr, $name:expr) => (
+        $router.$method(
+            $glob,
+            middleware::NamedHandler::new($name, idempotency::IdempotentHandler::new($handler)),
+            $name
+        )
+    )
+}
+
+/// Starts a new server on the given address and port. If an admin port is
+/// specified, admin routes are served on a separate listener bound to it. If
+/// a TLS config is specified, the server speaks HTTPS; otherwise it speaks
+/// plain HTTP.
+pub fn start(address: &str, port: u16, admin_address: &str, admin_port: Option<u16>, tls: Option<TlsConfig>) {
     let mut router = Router::new();
 
-    router.post("/transaction", transaction::transaction, "transaction");
+    route!(router, post, "/transaction", transaction::transaction, "transaction");
 
-    router.put("/edge/:outbound_id/:t/:inbound_id", rest::create_edge, "create_edge");
-    router.get("/edge", rest::get_edges, "get_edges");
-    router.delete("/edge", rest::delete_edges, "delete_edges");
+    route!(router, put, "/edge/:outbound_id/:t/:inbound_id", rest::create_edge, "create_edge");
+    route!(router, get, "/edge/:outbound_id/:t/:inbound_id", rest::get_edge, "get_edge");
+    route!(router, get, "/edge", rest::get_edges, "get_edges");
+    route!(router, delete, "/edge", rest::delete_edges, "delete_edges");
+    route!(router, post, "/edge/query", rest::query_edges, "query_edges");
+    route!(router, post, "/edge/count", rest::count_edges, "count_edges");
+    route!(router, post, "/edge/delete", rest::delete_queried_edges, "delete_queried_edges");
 
-    router.get("/vertex", rest::get_vertices, "get_vertices");
-    router.post("/vertex", rest::create_vertex, "create_vertex");
-    router.delete("/vertex", rest::delete_vertices, "delete_vertices");
+    route!(router, get, "/vertex", rest::get_vertices, "get_vertices");
+    route!(router, post, "/vertex", rest::create_vertex, "create_vertex");
+    route!(router, delete, "/vertex", rest::delete_vertices, "delete_vertices");
+    route!(router, post, "/vertex/query", rest::query_vertices, "query_vertices");
+    route!(router, post, "/vertex/delete", rest::delete_queried_vertices, "delete_queried_vertices");
+    route!(router, get, "/vertex/:id", rest::get_vertex, "get_vertex");
+    route!(router, delete, "/vertex/:id", rest::delete_vertex, "delete_vertex");
+    route!(router, get, "/vertex/:id/edges/outbound", rest::get_outbound_edges, "get_outbound_edges");
+    route!(router, get, "/vertex/:id/edges/inbound", rest::get_inbound_edges, "get_inbound_edges");
+    route!(router, get, "/vertex/:id/neighbors/outbound", rest::get_outbound_neighbors, "get_outbound_neighbors");
+    route!(router, get, "/vertex/:id/neighbors/inbound", rest::get_inbound_neighbors, "get_inbound_neighbors");
 
-    router.post("/script/:name", rest::script, "script");
+    route!(router, post, "/script/:name", rest::script, "script");
+    route!(router, get, "/scripts", scripts::list_scripts, "list_scripts");
+    route!(router, get, "/scripts/:name", scripts::get_script, "get_script");
+    route!(router, get, "/scripts/:name/versions", scripts::get_script_versions, "get_script_versions");
+    route!(router, put, "/scripts/:name", scripts::put_script, "put_script");
+    route!(router, post, "/scripts/:name/promote", scripts::promote_script, "promote_script");
+    route!(router, post, "/scripts/:name/rollback", scripts::rollback_script, "rollback_script");
+    route!(router, delete, "/scripts/:name", scripts::delete_script, "delete_script");
 
-    let binding = format!("0.0.0.0:{}", port);
-    println!("Listening on {}", binding);
+    route!(router, get, "/metrics", metrics::metrics, "metrics");
+    route!(router, get, "/healthz", health::healthz, "healthz");
+    route!(router, get, "/readyz", health::readyz, "readyz");
+
+    // Without a separate admin listener, admin routes are served alongside
+    // the account routes
+    let _admin_listening = match admin_port {
+        Some(admin_port) => {
+            let mut admin_router = Router::new();
+            add_admin_routes(&mut admin_router);
+            let mut admin_chain = Chain::new(admin_router);
+            admin_chain.link_before(middleware::RequestLogMiddleware::new());
+            admin_chain.link_before(middleware::DrainMiddleware::new());
+            admin_chain.link_after(middleware::ErrorMiddleware::new());
+            link_common_after_middleware(&mut admin_chain);
+            Some(listen(admin_chain, admin_address, admin_port, tls.as_ref()))
+        }
+        None => {
+            add_admin_routes(&mut router);
+            None
+        }
+    };
 
     let mut chain = Chain::new(router);
+    chain.link_before(middleware::RequestLogMiddleware::new());
+    chain.link_before(middleware::DrainMiddleware::new());
     chain.link_before(middleware::BasicAuthMiddleware::new());
     chain.link_after(middleware::ErrorMiddleware::new());
-    Iron::new(chain).http(&*binding).unwrap();
+
+    if let Ok(allowed_origins) = env::var("BRAID_CORS_ALLOWED_ORIGINS") {
+        let allowed_methods = env::var("BRAID_CORS_ALLOWED_METHODS")
+            .unwrap_or_else(|_| "GET, POST, PUT, DELETE".to_string());
+        let allowed_headers = env::var("BRAID_CORS_ALLOWED_HEADERS")
+            .unwrap_or_else(|_| "Authorization, Content-Type".to_string());
+        let allowed_origins = allowed_origins.split(',').map(|s| s.trim().to_string()).collect();
+        chain.link_after(middleware::CorsMiddleware::new(allowed_origins, allowed_methods, allowed_headers));
+    }
+
+    link_common_after_middleware(&mut chain);
+
+    // Dropping a listener blocks until its server shuts down. The main
+    // listener is dropped right away, which keeps the admin listener alive.
+    listen(chain, address, port, tls.as_ref());
+}
+
+/// Adds the admin routes, which are authenticated with the admin secret
+/// rather than account credentials.
+fn add_admin_routes(router: &mut Router) {
+    route!(router, get, "/admin/audit", admin::audit, "admin_audit");
+
+    route!(router, post, "/admin/accounts", admin::create_account, "admin_create_account");
+    route!(router, get, "/admin/accounts", admin::list_accounts, "admin_list_accounts");
+    route!(router, get, "/admin/accounts/:id", admin::get_account, "admin_get_account");
+    route!(router, delete, "/admin/accounts/:id", admin::delete_account, "admin_delete_account");
+    route!(router, post, "/admin/accounts/:id/disable", admin::disable_account, "admin_disable_account");
+    route!(router, post, "/admin/accounts/:id/enable", admin::enable_account, "admin_enable_account");
+    route!(router, post, "/admin/accounts/:id/secret", admin::rotate_account_secret, "admin_rotate_account_secret");
+    route!(router, get, "/admin/accounts/:id/metadata/:key", admin::get_account_metadata, "admin_get_account_metadata");
+    route!(router, put, "/admin/accounts/:id/metadata/:key", admin::set_account_metadata, "admin_set_account_metadata");
+    route!(router, delete, "/admin/accounts/:id/metadata/:key", admin::delete_account_metadata, "admin_delete_account_metadata");
+
+    route!(router, get, "/admin/scripts", admin::list_global_scripts, "admin_list_global_scripts");
+    route!(router, get, "/admin/scripts/:name", admin::get_global_script, "admin_get_global_script");
+    route!(router, get, "/admin/scripts/:name/versions", admin::get_global_script_versions, "admin_get_global_script_versions");
+    route!(router, put, "/admin/scripts/:name", admin::put_global_script, "admin_put_global_script");
+    route!(router, post, "/admin/scripts/:name/promote", admin::promote_global_script, "admin_promote_global_script");
+    route!(router, post, "/admin/scripts/:name/rollback", admin::rollback_global_script, "admin_rollback_global_script");
+    route!(router, delete, "/admin/scripts/:name", admin::delete_global_script, "admin_delete_global_script");
+}
+
+/// Links the after middleware shared by the main and admin listeners.
+fn link_common_after_middleware(chain: &mut Chain) {
+    chain.link_after(middleware::DrainMiddleware::new());
+    chain.link_after(middleware::MetricsMiddleware::new());
+    chain.link_after(middleware::CompressionMiddleware::new());
+    chain.link_after(middleware::RequestLogMiddleware::new());
+}
+
+/// Serves a chain on the given address and port, over HTTPS if a TLS config
+/// is specified. The listener stops accepting connections once the server is
+/// draining.
+fn listen(chain: Chain, address: &str, port: u16, tls: Option<&TlsConfig>) -> Listening {
+    let binding = format!("{}:{}", address, port);
+    let listener = TcpListener::bind(&*binding).unwrap();
+    shutdown::register_listener(listener.as_raw_fd());
+    let listener = HttpListener::from(listener);
+
+    match tls {
+        Some(tls) => {
+            let ssl = match tls.build_server() {
+                Ok(ssl) => ssl,
+                Err(err) => panic!("Could not configure HTTPS: {}", err),
+            };
+
+            println!("Listening on https://{}", binding);
+            let listener = DrainingListener::new(HttpsListener::with_listener(listener, ssl));
+            Iron::new(chain).listen(listener, Protocol::https()).unwrap()
+        }
+        None => {
+            println!("Listening on http://{}", binding);
+            Iron::new(chain).listen(DrainingListener::new(listener), Protocol::http()).unwrap()
+        }
+    }
 }
@@ -1,9 +1,16 @@
 use iron::prelude::*;
 use iron::status;
-use braid::{Transaction, Type, EdgeKey, VertexQuery, EdgeQuery};
+use braid::{Error, Transaction, Type, EdgeKey, VertexQuery, EdgeQuery, QueryTypeConverter};
+use common::{ProxyTransaction, record_audit_entry};
 use serde_json::value::Value as JsonValue;
+use serde_json;
 use uuid::Uuid;
 use super::util::*;
+use super::dry_run::{DryRunReport, check_dry_run_supported};
+
+/// The maximum number of results returned by resource routes when no
+/// `limit` query parameter is given.
+const DEFAULT_RESOURCE_LIMIT: u32 = 1000;
 
 pub fn create_vertex(req: &mut Request) -> IronResult<Response> {
     let trans = get_transaction(req)?;
@@ -11,25 +18,68 @@ pub fn create_vertex(req: &mut Request) -> IronResult<Response> {
     let t = get_query_param::<Type>(query_params, "type", true)?.unwrap();
     let response = datastore_request(trans.create_vertex(t))?;
     datastore_request(trans.commit())?;
-    Ok(to_response(status::Ok, &response))
+    Ok(to_response(req, status::Ok, &response))
 }
 
 pub fn get_vertices(req: &mut Request) -> IronResult<Response> {
     let trans = get_transaction(req)?;
     let query_params = get_query_params(req)?;
-    let q = get_obj_query_param::<VertexQuery>(query_params)?;
-    let response = datastore_request(trans.get_vertices(q))?;
-    datastore_request(trans.commit())?;
-    Ok(to_response(status::Ok, &response))
+    let q = get_obj_query_param::<VertexQuery>(query_params, "vertex query")?;
+    respond_with_vertices(req, trans, q)
+}
+
+pub fn query_vertices(req: &mut Request) -> IronResult<Response> {
+    let trans = get_transaction(req)?;
+    let q = read_query_body::<VertexQuery>(req, "vertex query")?;
+    respond_with_vertices(req, trans, q)
 }
 
 pub fn delete_vertices(req: &mut Request) -> IronResult<Response> {
     let trans = get_transaction(req)?;
     let query_params = get_query_params(req)?;
-    let q = get_obj_query_param::<VertexQuery>(query_params)?;
-    datastore_request(trans.delete_vertices(q))?;
+    let q = get_obj_query_param::<VertexQuery>(query_params, "vertex query")?;
+    respond_to_vertex_deletion(req, trans, q)
+}
+
+pub fn delete_queried_vertices(req: &mut Request) -> IronResult<Response> {
+    let trans = get_transaction(req)?;
+    let q = read_query_body::<VertexQuery>(req, "vertex query")?;
+    respond_to_vertex_deletion(req, trans, q)
+}
+
+pub fn get_vertex(req: &mut Request) -> IronResult<Response> {
+    let trans = get_transaction(req)?;
+    let id: Uuid = get_url_param(req, "id")?;
+    let mut vertices = datastore_request(trans.get_vertices(VertexQuery::Vertex(id)))?;
     datastore_request(trans.commit())?;
-    Ok(to_response(status::Ok, &()))
+
+    match vertices.pop() {
+        Some(vertex) => Ok(to_response(req, status::Ok, &vertex)),
+        None => Err(convert_to_iron_error(Error::VertexNotFound)),
+    }
+}
+
+pub fn delete_vertex(req: &mut Request) -> IronResult<Response> {
+    let trans = get_transaction(req)?;
+    let id: Uuid = get_url_param(req, "id")?;
+    ensure_vertex_exists(&trans, id)?;
+    respond_to_vertex_deletion(req, trans, VertexQuery::Vertex(id))
+}
+
+pub fn get_outbound_edges(req: &mut Request) -> IronResult<Response> {
+    respond_with_vertex_edges(req, QueryTypeConverter::Outbound)
+}
+
+pub fn get_inbound_edges(req: &mut Request) -> IronResult<Response> {
+    respond_with_vertex_edges(req, QueryTypeConverter::Inbound)
+}
+
+pub fn get_outbound_neighbors(req: &mut Request) -> IronResult<Response> {
+    respond_with_neighbors(req, QueryTypeConverter::Outbound, QueryTypeConverter::Inbound)
+}
+
+pub fn get_inbound_neighbors(req: &mut Request) -> IronResult<Response> {
+    respond_with_neighbors(req, QueryTypeConverter::Inbound, QueryTypeConverter::Outbound)
 }
 
 pub fn create_edge(req: &mut Request) -> IronResult<Response> {
@@ -42,44 +92,192 @@ pub fn create_edge(req: &mut Request) -> IronResult<Response> {
     let key = EdgeKey::new(outbound_id, t, inbound_id);
     datastore_request(trans.create_edge(key, weight))?;
     datastore_request(trans.commit())?;
-    Ok(to_response(status::Ok, &()))
+    Ok(to_response(req, status::Ok, &()))
+}
+
+pub fn get_edge(req: &mut Request) -> IronResult<Response> {
+    let trans = get_transaction(req)?;
+    let outbound_id: Uuid = get_url_param(req, "outbound_id")?;
+    let t: Type = get_url_param(req, "t")?;
+    let inbound_id: Uuid = get_url_param(req, "inbound_id")?;
+    let key = EdgeKey::new(outbound_id, t, inbound_id);
+    let mut edges = datastore_request(trans.get_edges(EdgeQuery::Edge(key)))?;
+
+    match edges.pop() {
+        Some(edge) => Ok(to_response(req, status::Ok, &edge)),
+        None => Err(convert_to_iron_error(Error::EdgeNotFound)),
+    }
 }
 
 pub fn get_edges(req: &mut Request) -> IronResult<Response> {
     let trans = get_transaction(req)?;
     let query_params = get_query_params(req)?;
-    let q = get_obj_query_param::<EdgeQuery>(query_params)?;
+    let q = get_obj_query_param::<EdgeQuery>(query_params, "edge query")?;
     let action = get_query_param::<String>(query_params, "action", false)?;
 
     if action == Some("count".to_string()) {
-        let response = datastore_request(trans.get_edge_count(q))?;
-        Ok(to_response(status::Ok, &response))
+        respond_with_edge_count(req, trans, q)
     } else {
-        let response = datastore_request(trans.get_edges(q))?;
-        Ok(to_response(status::Ok, &response))
+        respond_with_edges(req, trans, q)
     }
 }
 
+pub fn query_edges(req: &mut Request) -> IronResult<Response> {
+    let trans = get_transaction(req)?;
+    let q = read_query_body::<EdgeQuery>(req, "edge query")?;
+    respond_with_edges(req, trans, q)
+}
+
+pub fn count_edges(req: &mut Request) -> IronResult<Response> {
+    let trans = get_transaction(req)?;
+    let q = read_query_body::<EdgeQuery>(req, "edge query")?;
+    respond_with_edge_count(req, trans, q)
+}
+
 pub fn delete_edges(req: &mut Request) -> IronResult<Response> {
     let trans = get_transaction(req)?;
     let query_params = get_query_params(req)?;
-    let q = get_obj_query_param::<EdgeQuery>(query_params)?;
-    datastore_request(trans.delete_edges(q))?;
-    datastore_request(trans.commit())?;
-    Ok(to_response(status::Ok, &()))
+    let q = get_obj_query_param::<EdgeQuery>(query_params, "edge query")?;
+    respond_to_edge_deletion(req, trans, q)
+}
+
+pub fn delete_queried_edges(req: &mut Request) -> IronResult<Response> {
+    let trans = get_transaction(req)?;
+    let q = read_query_body::<EdgeQuery>(req, "edge query")?;
+    respond_to_edge_deletion(req, trans, q)
 }
 
 pub fn script(req: &mut Request) -> IronResult<Response> {
-    let name: String = get_url_param(req, "name")?;
+    let reference: String = get_url_param(req, "name")?;
+    let (name, version) = parse_script_reference(&reference[..])?;
 
-    let payload = match read_optional_json(&mut req.body)? {
+    let payload = match read_optional_json(req)? {
         Some(val) => val,
         None => JsonValue::Null,
     };
 
+    let debug = get_query_param::<bool>(get_query_params(req)?, "debug", false)?.unwrap_or(false);
     let trans = get_transaction(req)?;
     let account_id = get_account_id(req);
-    let response = execute_script(name, &payload, &trans, account_id)?;
+    let response = execute_script(name, version, &payload, &trans, account_id, get_request_id(req), debug)?;
+    datastore_request(trans.commit())?;
+    record_audit_entry(&create_audit_entry(req, "run_script").with_target(reference));
+    Ok(to_response(req, status::Ok, &response))
+}
+
+fn respond_with_vertices(req: &Request, trans: ProxyTransaction, q: VertexQuery) -> IronResult<Response> {
+    let response = datastore_request(trans.get_vertices(q))?;
+    datastore_request(trans.commit())?;
+    Ok(to_response(req, status::Ok, &response))
+}
+
+/// Deletes the vertices matched by a query. If the `dry_run` query parameter
+/// is set, the deletion is rolled back, and the response describes what was
+/// removed instead.
+fn respond_to_vertex_deletion(req: &mut Request, trans: ProxyTransaction, q: VertexQuery) -> IronResult<Response> {
+    if let Some(metadata_keys) = get_dry_run_query_params(req)? {
+        check_dry_run_supported(&trans)?;
+        let report = datastore_request(DryRunReport::for_vertex_deletion(&trans, q, &metadata_keys))?;
+        datastore_request(trans.rollback())?;
+        return Ok(to_response(req, status::Ok, &report.to_json()));
+    }
+
+    let target = serde_json::to_string(&q).unwrap();
+    datastore_request(trans.delete_vertices(q))?;
+    datastore_request(trans.commit())?;
+    record_audit_entry(&create_audit_entry(req, "delete_vertices").with_target(target));
+    Ok(to_response(req, status::Ok, &()))
+}
+
+/// Responds with the edges going out of, or coming into, the vertex
+/// identified by the `id` URL param.
+fn respond_with_vertex_edges(req: &mut Request, direction: QueryTypeConverter) -> IronResult<Response> {
+    let trans = get_transaction(req)?;
+    let id: Uuid = get_url_param(req, "id")?;
+    let q = get_vertex_edge_query(req, id, direction)?;
+    ensure_vertex_exists(&trans, id)?;
+    respond_with_edges(req, trans, q)
+}
+
+/// Responds with the vertices on the other end of the edges going out of,
+/// or coming into, the vertex identified by the `id` URL param.
+fn respond_with_neighbors(req: &mut Request, direction: QueryTypeConverter, converter: QueryTypeConverter) -> IronResult<Response> {
+    let trans = get_transaction(req)?;
+    let id: Uuid = get_url_param(req, "id")?;
+    let edge_query = get_vertex_edge_query(req, id, direction)?;
+    let limit = get_limit_query_param(req)?;
+    ensure_vertex_exists(&trans, id)?;
+    respond_with_vertices(req, trans, VertexQuery::Pipe(Box::new(edge_query), converter, limit))
+}
+
+/// Builds a query for the edges of a vertex, filtered by the optional `type`
+/// and `limit` query parameters.
+fn get_vertex_edge_query(req: &mut Request, id: Uuid, direction: QueryTypeConverter) -> Result<EdgeQuery, IronError> {
+    let limit = get_limit_query_param(req)?;
+    let query_params = get_query_params(req)?;
+    let t = get_query_param::<Type>(query_params, "type", false)?;
+    Ok(EdgeQuery::Pipe(Box::new(VertexQuery::Vertex(id)), direction, t, None, None, limit))
+}
+
+fn get_limit_query_param(req: &mut Request) -> Result<u32, IronError> {
+    let query_params = get_query_params(req)?;
+    Ok(get_query_param::<u32>(query_params, "limit", false)?.unwrap_or(DEFAULT_RESOURCE_LIMIT))
+}
+
+/// Gets the metadata keys to include in a dry run report from the
+/// comma-separated `metadata_keys` query parameter, or `None` if the
+/// `dry_run` query parameter is not set.
+fn get_dry_run_query_params(req: &mut Request) -> Result<Option<Vec<String>>, IronError> {
+    let query_params = get_query_params(req)?;
+
+    if get_query_param::<bool>(query_params, "dry_run", false)? != Some(true) {
+        return Ok(None);
+    }
+
+    let metadata_keys = match get_query_param::<String>(query_params, "metadata_keys", false)? {
+        Some(keys) => keys.split(',').map(|key| key.trim().to_string()).filter(|key| !key.is_empty()).collect(),
+        None => Vec::new(),
+    };
+
+    Ok(Some(metadata_keys))
+}
+
+/// Returns a `vertex_not_found` error if the vertex does not exist, so that
+/// resource routes respond with a 404 rather than an empty result.
+fn ensure_vertex_exists(trans: &ProxyTransaction, id: Uuid) -> Result<(), IronError> {
+    let vertices = datastore_request(trans.get_vertices(VertexQuery::Vertex(id)))?;
+
+    if vertices.is_empty() {
+        Err(convert_to_iron_error(Error::VertexNotFound))
+    } else {
+        Ok(())
+    }
+}
+
+fn respond_with_edges(req: &Request, trans: ProxyTransaction, q: EdgeQuery) -> IronResult<Response> {
+    let response = datastore_request(trans.get_edges(q))?;
+    Ok(to_response(req, status::Ok, &response))
+}
+
+fn respond_with_edge_count(req: &Request, trans: ProxyTransaction, q: EdgeQuery) -> IronResult<Response> {
+    let response = datastore_request(trans.get_edge_count(q))?;
+    Ok(to_response(req, status::Ok, &response))
+}
+
+/// Deletes the edges matched by a query. If the `dry_run` query parameter is
+/// set, the deletion is rolled back, and the response describes what was
+/// removed instead.
+fn respond_to_edge_deletion(req: &mut Request, trans: ProxyTransaction, q: EdgeQuery) -> IronResult<Response> {
+    if let Some(metadata_keys) = get_dry_run_query_params(req)? {
+        check_dry_run_supported(&trans)?;
+        let report = datastore_request(DryRunReport::for_edge_deletion(&trans, q, &metadata_keys))?;
+        datastore_request(trans.rollback())?;
+        return Ok(to_response(req, status::Ok, &report.to_json()));
+    }
+
+    let target = serde_json::to_string(&q).unwrap();
+    datastore_request(trans.delete_edges(q))?;
     datastore_request(trans.commit())?;
-    Ok(to_response(status::Ok, &response))
+    record_audit_entry(&create_audit_entry(req, "delete_edges").with_target(target));
+    Ok(to_response(req, status::Ok, &()))
 }
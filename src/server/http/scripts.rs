@@ -0,0 +1,208 @@
+use iron::prelude::*;
+use iron::status;
+use braid::{Error, Transaction};
+use common::{ProxyTransaction, ScriptHistory, ScriptScope, StoredScript, delete_stored_script, get_script_history,
+             get_stored_script, list_stored_scripts, promote_stored_script, put_stored_script, record_audit_entry,
+             resolve_script_history, resolve_stored_script};
+use serde_json::value::Value as JsonValue;
+use common::script;
+use super::util::*;
+
+pub fn list_scripts(req: &mut Request) -> IronResult<Response> {
+    let account_id = get_account_id(req);
+    let trans = get_transaction(req)?;
+    respond_with_script_list(req, trans, &[ScriptScope::Account(account_id), ScriptScope::Global])
+}
+
+pub fn get_script(req: &mut Request) -> IronResult<Response> {
+    let account_id = get_account_id(req);
+    let reference: String = get_url_param(req, "name")?;
+    let (name, version) = parse_script_reference(&reference[..])?;
+    let trans = get_transaction(req)?;
+    let script = datastore_request(resolve_stored_script(&trans, account_id, &name[..], version))?;
+    datastore_request(trans.rollback())?;
+    respond_with_script(req, script)
+}
+
+pub fn get_script_versions(req: &mut Request) -> IronResult<Response> {
+    let account_id = get_account_id(req);
+    let name: String = get_url_param(req, "name")?;
+    let trans = get_transaction(req)?;
+    let history = datastore_request(resolve_script_history(&trans, account_id, &name[..]))?;
+    datastore_request(trans.rollback())?;
+    respond_with_script_history(req, history)
+}
+
+pub fn put_script(req: &mut Request) -> IronResult<Response> {
+    let account_id = get_account_id(req);
+    let trans = get_transaction(req)?;
+    respond_to_script_upload(req, trans, ScriptScope::Account(account_id))
+}
+
+pub fn promote_script(req: &mut Request) -> IronResult<Response> {
+    let account_id = get_account_id(req);
+    let trans = get_transaction(req)?;
+    respond_to_script_promotion(req, trans, ScriptScope::Account(account_id))
+}
+
+pub fn rollback_script(req: &mut Request) -> IronResult<Response> {
+    let account_id = get_account_id(req);
+    let trans = get_transaction(req)?;
+    respond_to_script_rollback(req, trans, ScriptScope::Account(account_id))
+}
+
+pub fn delete_script(req: &mut Request) -> IronResult<Response> {
+    let account_id = get_account_id(req);
+    let trans = get_transaction(req)?;
+    respond_to_script_deletion(req, trans, ScriptScope::Account(account_id))
+}
+
+/// Lists the current versions of the scripts stored in some scopes, without
+/// their source.
+pub fn respond_with_script_list(req: &Request, trans: ProxyTransaction, scopes: &[ScriptScope]) -> IronResult<Response> {
+    let mut scripts: Vec<JsonValue> = Vec::new();
+
+    for scope in scopes {
+        for name in datastore_request(list_stored_scripts(&trans, *scope))? {
+            if let Some(script) = datastore_request(get_stored_script(&trans, *scope, &name[..], None))? {
+                scripts.push(script.to_json(false));
+            }
+        }
+    }
+
+    datastore_request(trans.rollback())?;
+    Ok(to_response(req, status::Ok, &scripts))
+}
+
+/// Responds with a stored script, including its source.
+///
+/// # Errors
+/// Returns an `IronError` if there is no script.
+pub fn respond_with_script(req: &Request, script: Option<StoredScript>) -> IronResult<Response> {
+    match script {
+        Some(script) => Ok(to_response(req, status::Ok, &script.to_json(true))),
+        None => Err(create_script_not_found_error()),
+    }
+}
+
+/// Responds with the versions of a stored script, and its promotions.
+///
+/// # Errors
+/// Returns an `IronError` if there is no script.
+pub fn respond_with_script_history(req: &Request, history: Option<ScriptHistory>) -> IronResult<Response> {
+    match history {
+        Some(history) => Ok(to_response(req, status::Ok, &history.to_json())),
+        None => Err(create_script_not_found_error()),
+    }
+}
+
+/// Stores the script in the request body as a new version in a scope, after
+/// checking that it compiles. The new version is only promoted if it's the
+/// first version of the script, or the `promote` query parameter is `true`.
+pub fn respond_to_script_upload(req: &mut Request, trans: ProxyTransaction, scope: ScriptScope) -> IronResult<Response> {
+    let name: String = get_url_param(req, "name")?;
+    validate_script_name(&name[..])?;
+    let promote = get_query_param::<bool>(get_query_params(req)?, "promote", false)?.unwrap_or(false);
+    let source = read_script_source(req)?;
+
+    match script::check_syntax(&name[..], &source[..]) {
+        Ok(()) => (),
+        Err(script::ScriptError::Syntax(message)) => {
+            return Err(create_iron_error(status::BadRequest, "script_syntax_error", message));
+        }
+        Err(err) => {
+            return Err(create_iron_error(status::InternalServerError, "internal_error", format!("Could not check script: {:?}", err)));
+        }
+    }
+
+    let script = datastore_request(put_stored_script(&trans, scope, &name[..], source, promote))?;
+    datastore_request(trans.commit())?;
+    record_audit_entry(&create_audit_entry(req, "put_script").with_target(script.id()));
+    Ok(to_response(req, status::Ok, &script.to_json(false)))
+}
+
+/// Makes the bare name of a script in a scope refer to the version in the
+/// request body.
+pub fn respond_to_script_promotion(req: &mut Request, trans: ProxyTransaction, scope: ScriptScope) -> IronResult<Response> {
+    let name: String = get_url_param(req, "name")?;
+
+    let version: u32 = match read_required_json(req)? {
+        JsonValue::Object(obj) => get_required_json_obj_param(&obj, "version")?,
+        _ => return Err(create_iron_error(status::BadRequest, "invalid_body", "Request body should be an object".to_string())),
+    };
+
+    respond_with_promoted_version(req, trans, scope, &name[..], version, "promote_script")
+}
+
+/// Makes the bare name of a script in a scope refer to the newest version
+/// that is older than the current one.
+pub fn respond_to_script_rollback(req: &mut Request, trans: ProxyTransaction, scope: ScriptScope) -> IronResult<Response> {
+    let name: String = get_url_param(req, "name")?;
+
+    let version = match datastore_request(get_script_history(&trans, scope, &name[..]))? {
+        Some(history) => match history.previous_version() {
+            Some(version) => version,
+            None => {
+                return Err(create_iron_error(status::BadRequest, "no_previous_version", "Script has no version older than the current one".to_string()));
+            }
+        },
+        None => return Err(create_script_not_found_error()),
+    };
+
+    respond_with_promoted_version(req, trans, scope, &name[..], version, "rollback_script")
+}
+
+/// Deletes every version of a script from a scope.
+pub fn respond_to_script_deletion(req: &mut Request, trans: ProxyTransaction, scope: ScriptScope) -> IronResult<Response> {
+    let name: String = get_url_param(req, "name")?;
+
+    match delete_stored_script(&trans, scope, &name[..]) {
+        Ok(()) => (),
+        Err(Error::MetadataNotFound) => return Err(create_script_not_found_error()),
+        Err(err) => return Err(convert_to_iron_error(err)),
+    }
+
+    datastore_request(trans.commit())?;
+    record_audit_entry(&create_audit_entry(req, "delete_script").with_target(scope.script_id(&name[..])));
+    Ok(to_response(req, status::Ok, &()))
+}
+
+fn respond_with_promoted_version(req: &Request, trans: ProxyTransaction, scope: ScriptScope, name: &str, version: u32, action: &str) -> IronResult<Response> {
+    let history = match promote_stored_script(&trans, scope, name, version) {
+        Ok(history) => history,
+        Err(Error::MetadataNotFound) => {
+            return Err(create_iron_error(status::NotFound, "script_not_found", "Script version not found".to_string()));
+        }
+        Err(err) => return Err(convert_to_iron_error(err)),
+    };
+
+    datastore_request(trans.commit())?;
+    record_audit_entry(&create_audit_entry(req, action).with_target(format!("{}@{}", scope.script_id(name), version)));
+    Ok(to_response(req, status::Ok, &history.to_json()))
+}
+
+/// Reads the source of a script from the request body.
+///
+/// # Errors
+/// Returns an `IronError` if the body is empty or is not UTF-8.
+fn read_script_source(req: &mut Request) -> Result<String, IronError> {
+    let body = read_body(req)?;
+
+    if body.is_empty() {
+        return Err(create_iron_error(status::BadRequest, "missing_body", "Missing script source".to_string()));
+    }
+
+    // Precompiled chunks usually aren't UTF-8, so they're checked for first.
+    if script::is_bytecode(&body[..]) {
+        return Err(create_iron_error(status::BadRequest, "script_precompiled", "Precompiled scripts are not allowed".to_string()));
+    }
+
+    match String::from_utf8(body) {
+        Ok(source) => Ok(source),
+        Err(_) => Err(create_iron_error(status::BadRequest, "invalid_body", "Script source must be UTF-8".to_string())),
+    }
+}
+
+fn create_script_not_found_error() -> IronError {
+    create_iron_error(status::NotFound, "script_not_found", "Script not found".to_string())
+}
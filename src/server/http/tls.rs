@@ -0,0 +1,71 @@
+use hyper_openssl::OpensslServer;
+use openssl::ssl::{SslAcceptorBuilder, SslMethod, SSL_VERIFY_PEER, SSL_VERIFY_FAIL_IF_NO_PEER_CERT};
+use openssl::x509::X509;
+use openssl::pkey::PKey;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+/// Settings for serving HTTPS.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// Path to a PEM file with the server certificate, optionally followed
+    /// by intermediate certificates
+    pub cert_path: PathBuf,
+
+    /// Path to a PEM file with the server's private key
+    pub key_path: PathBuf,
+
+    /// Path to a PEM file with CA certificates. If set, clients must present
+    /// a certificate signed by one of these CAs.
+    pub client_ca_path: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    /// Builds the SSL server used by iron from the configured files.
+    ///
+    /// # Errors
+    /// Returns an error if the files could not be read, or did not contain
+    /// valid certificates or keys.
+    pub fn build_server(&self) -> Result<OpensslServer, String> {
+        let cert_pem = read_file(&self.cert_path)?;
+        let key_pem = read_file(&self.key_path)?;
+
+        let mut certs = X509::stack_from_pem(&cert_pem[..])
+            .map_err(|err| format!("Could not parse certificate file: {}", err))?;
+
+        if certs.is_empty() {
+            return Err("Certificate file does not contain any certificates".to_string());
+        }
+
+        let cert = certs.remove(0);
+        let key = PKey::private_key_from_pem(&key_pem[..])
+            .map_err(|err| format!("Could not parse key file: {}", err))?;
+
+        let mut builder = SslAcceptorBuilder::mozilla_intermediate(SslMethod::tls(), &key, &cert, certs)
+            .map_err(|err| format!("Could not configure TLS: {}", err))?;
+
+        if let Some(ref client_ca_path) = self.client_ca_path {
+            let context = builder.builder_mut();
+            context.set_ca_file(client_ca_path)
+                .map_err(|err| format!("Could not load client CA file: {}", err))?;
+            context.set_verify(SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT);
+        }
+
+        Ok(OpensslServer::from(builder.build()))
+    }
+}
+
+fn read_file(path: &PathBuf) -> Result<Vec<u8>, String> {
+    let mut contents: Vec<u8> = Vec::new();
+
+    match File::open(path) {
+        Ok(mut file) => {
+            match file.read_to_end(&mut contents) {
+                Ok(_) => Ok(contents),
+                Err(err) => Err(format!("Could not read `{}`: {}", path.display(), err)),
+            }
+        }
+        Err(err) => Err(format!("Could not open `{}`: {}", path.display(), err)),
+    }
+}
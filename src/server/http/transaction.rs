@@ -1,28 +1,29 @@
 use iron::prelude::*;
 use iron::status;
 use braid::{Transaction, Error, EdgeKey, VertexQuery, EdgeQuery};
-use common::ProxyTransaction;
+use common::{AuditEntry, ProxyTransaction, record_audit_entry};
 use serde_json::value::Value as JsonValue;
 use serde_json;
 use serde::ser::Serialize;
 use std::u16;
 use super::util::*;
+use super::errors::ApiError;
+use super::dry_run::{DryRunReport, check_dry_run_supported};
 use uuid::Uuid;
 
 pub fn transaction(req: &mut Request) -> IronResult<Response> {
     let trans = get_transaction(req)?;
     let mut idx: u16 = 0;
     let mut jsonable_res: Vec<JsonValue> = Vec::new();
+    let mut audit_entries: Vec<AuditEntry> = Vec::new();
+    let mut dry_run = false;
 
-    if let JsonValue::Array(items) = read_required_json(&mut req.body)? {
+    if let JsonValue::Array(items) = read_required_json(req)? {
         for item in items {
             if let JsonValue::Object(obj) = item {
                 let action = match get_required_json_string_param(&obj, "action") {
                     Ok(value) => value,
-                    Err(err) => {
-                        let message = format!("Item #{}: {}", idx, err);
-                        return Err(create_iron_error(status::BadRequest, message));
-                    }
+                    Err(err) => return Err(create_item_error(idx, err)),
                 };
 
                 let result: Result<JsonValue, IronError> = match &action[..] {
@@ -37,35 +38,53 @@ pub fn transaction(req: &mut Request) -> IronResult<Response> {
 
                     "run_script" => {
                         let account_id = get_account_id(req);
-                        run_script(&trans, &obj, account_id)
+                        run_script(&trans, &obj, account_id, get_request_id(req))
                     },
 
                     _ => {
-                        Err(create_iron_error(status::BadRequest, "Unknown action".to_string()))
+                        Err(create_field_error(status::BadRequest, "unknown_action", "action", "Unknown action".to_string()))
                     }
                 };
 
                 match result {
                     Err(err) => {
-                        let message = format!("Item #{}: {}", idx, err);
-                        return Err(create_iron_error(status::BadRequest, message));
+                        return Err(create_item_error(idx, err));
                     }
                     Ok(value) => {
+                        dry_run = dry_run || is_dry_run_item(&action, &obj);
+
+                        if let Some(target) = get_audit_target(&action, &obj) {
+                            audit_entries.push(create_audit_entry(req, &action[..]).with_target(target));
+                        }
+
                         jsonable_res.push(value);
                     }
                 }
             } else {
-                return Err(create_iron_error(status::BadRequest, format!("Item #{}: Invalid type", idx)))
+                let err = ApiError::new("invalid_item", "Invalid type".to_string()).with_index(idx);
+                return Err(create_api_error(status::BadRequest, err));
             }
 
             idx += 1;
         }
     } else {
-        return Err(create_iron_error(status::BadRequest, "Request body should be an array".to_string()))
+        return Err(create_iron_error(status::BadRequest, "invalid_body", "Request body should be an array".to_string()))
+    }
+
+    // A dry run's deletion can't be undone on its own, so batches with one
+    // are rolled back as a whole.
+    if dry_run {
+        datastore_request(trans.rollback())?;
+        return Ok(to_response(req, status::Ok, &jsonable_res));
     }
 
     datastore_request(trans.commit())?;
-    Ok(to_response(status::Ok, &jsonable_res))
+
+    for entry in audit_entries {
+        record_audit_entry(&entry);
+    }
+
+    Ok(to_response(req, status::Ok, &jsonable_res))
 }
 
 fn create_vertex(trans: &ProxyTransaction, item: &serde_json::Map<String, JsonValue>) -> Result<JsonValue, IronError> {
@@ -80,6 +99,13 @@ fn get_vertices(trans: &ProxyTransaction, item: &serde_json::Map<String, JsonVal
 
 fn delete_vertices(trans: &ProxyTransaction, item: &serde_json::Map<String, JsonValue>) -> Result<JsonValue, IronError> {
     let q = get_required_json_obj_param::<VertexQuery>(item, "query")?;
+
+    if let Some(metadata_keys) = get_dry_run_item_params(item)? {
+        check_dry_run_supported(trans)?;
+        let report = datastore_request(DryRunReport::for_vertex_deletion(trans, q, &metadata_keys))?;
+        return Ok(report.to_json());
+    }
+
     execute_item(trans.delete_vertices(q))
 }
 
@@ -96,6 +122,13 @@ fn get_edges(trans: &ProxyTransaction, item: &serde_json::Map<String, JsonValue>
 
 fn delete_edges(trans: &ProxyTransaction, item: &serde_json::Map<String, JsonValue>) -> Result<JsonValue, IronError> {
     let q = get_required_json_obj_param::<EdgeQuery>(item, "query")?;
+
+    if let Some(metadata_keys) = get_dry_run_item_params(item)? {
+        check_dry_run_supported(trans)?;
+        let report = datastore_request(DryRunReport::for_edge_deletion(trans, q, &metadata_keys))?;
+        return Ok(report.to_json());
+    }
+
     execute_item(trans.delete_edges(q))
 }
 
@@ -104,12 +137,53 @@ fn get_edge_count(trans: &ProxyTransaction, item: &serde_json::Map<String, JsonV
     execute_item(trans.get_edge_count(q))
 }
 
-fn run_script(trans: &ProxyTransaction, item: &serde_json::Map<String, JsonValue>, account_id: Uuid) -> Result<JsonValue, IronError> {
+/// Gets the metadata keys to count in a dry run report, or `None` if the
+/// item is not a dry run. Dry runs in a batch delete as usual, and the
+/// whole batch is rolled back afterwards.
+fn get_dry_run_item_params(item: &serde_json::Map<String, JsonValue>) -> Result<Option<Vec<String>>, IronError> {
+    if get_optional_json_obj_param::<bool>(item, "dry_run")? != Some(true) {
+        return Ok(None);
+    }
+
+    Ok(Some(get_optional_json_obj_param::<Vec<String>>(item, "metadata_keys")?.unwrap_or_else(Vec::new)))
+}
+
+fn is_dry_run_item(action: &str, item: &serde_json::Map<String, JsonValue>) -> bool {
+    (action == "delete_vertices" || action == "delete_edges") && item.get("dry_run") == Some(&JsonValue::Bool(true))
+}
+
+/// Gets what an item acted on, if the item should be recorded in the audit
+/// log. Deletions are audited with their query, unless they are dry runs,
+/// and script executions with the script name, including its version if pinned.
+fn get_audit_target(action: &str, item: &serde_json::Map<String, JsonValue>) -> Option<String> {
+    match action {
+        "delete_vertices" | "delete_edges" => {
+            if is_dry_run_item(action, item) {
+                None
+            } else {
+                item.get("query").map(|q| serde_json::to_string(q).unwrap())
+            }
+        }
+        "run_script" => {
+            item.get("name").and_then(|name| name.as_str()).map(|name| {
+                match item.get("version").and_then(|version| version.as_u64()) {
+                    Some(version) => format!("{}@{}", name, version),
+                    None => name.to_string(),
+                }
+            })
+        }
+        _ => None,
+    }
+}
+
+fn run_script(trans: &ProxyTransaction, item: &serde_json::Map<String, JsonValue>, account_id: Uuid, request_id: Option<String>) -> Result<JsonValue, IronError> {
     let name: String = get_required_json_string_param(item, "name")?;
+    let version: Option<u32> = get_optional_json_obj_param(item, "version")?;
+    let debug: bool = get_optional_json_obj_param(item, "debug")?.unwrap_or(false);
 
     match item.get("payload") {
-        Some(val) => execute_script(name, &val, trans, account_id),
-        None => execute_script(name, &JsonValue::Null, trans, account_id)
+        Some(val) => execute_script(name, version, &val, trans, account_id, request_id, debug),
+        None => execute_script(name, version, &JsonValue::Null, trans, account_id, request_id, debug)
     }
 }
 
@@ -118,6 +192,19 @@ fn execute_item<T: Serialize>(result: Result<T, Error>) -> Result<JsonValue, Iro
 
     match serde_json::to_value(&result) {
         Ok(val) => Ok(val),
-        Err(err) => Err(create_iron_error(status::InternalServerError, format!("Could not serialize results: {}", err)))
+        Err(err) => Err(create_iron_error(status::InternalServerError, "internal_error", format!("Could not serialize results: {}", err)))
+    }
+}
+
+/// Converts the error from a batch item into an error for the whole batch,
+/// tagged with the item's index. The item's status is kept, except that
+/// errors without an error code are reported as internal errors.
+fn create_item_error(idx: u16, err: IronError) -> IronError {
+    match err.error.downcast_ref::<ApiError>() {
+        Some(api_error) => {
+            let status_code = err.response.status.unwrap_or(status::InternalServerError);
+            create_api_error(status_code, api_error.clone().with_index(idx))
+        }
+        None => create_api_error(status::InternalServerError, ApiError::new("internal_error", format!("{}", err.error)).with_index(idx)),
     }
 }
@@ -1,32 +1,62 @@
 use iron::prelude::*;
 use iron::status;
-use iron::headers::{Headers, ContentType};
+use iron::headers::{Headers, ContentType, ContentLength};
 use iron::typemap::{Key, TypeMap};
 use router::Router;
 use braid::{Datastore, Error, Type, Weight};
-use util::SimpleError;
-use common::ProxyTransaction;
-use std::error::Error as StdError;
+use common::{AuditEntry, ProxyTransaction, is_valid_script_name};
 use core::str::FromStr;
 use iron::modifiers::Header as HeaderModifier;
 use iron::mime::{Mime, TopLevel, SubLevel, Attr, Value};
-use iron::request::Body;
 use std::io;
 use std::io::Read;
+use std::str;
 use serde_json::value::Value as JsonValue;
 use serde_json;
 use urlencoded::{UrlDecodingError, UrlEncodedQuery};
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
+use std::env;
 use statics;
 use uuid::Uuid;
-use regex;
-use std::path::Path;
-use script;
+use std::time::Instant;
+use common::script;
+use logging::Level;
+use metrics::METRICS;
+use shutdown::InFlightGuard;
+use super::errors::ApiError;
+use super::format::Format;
 
 lazy_static! {
-    static ref SCRIPT_NAME_VALIDATOR: regex::Regex = regex::Regex::new(r"^[\w-_]+(\.lua)?$").unwrap();
     static ref DEFAULT_QUERY_PARAMS: HashMap<String, Vec<String>> = HashMap::new();
+
+    /// The maximum request body size, in bytes, for routes that do not have
+    /// their own limit
+    static ref DEFAULT_MAX_BODY_SIZE: usize = match env::var("BRAID_MAX_BODY_SIZE") {
+        Ok(s) => s.parse().expect("Could not parse environment variable `BRAID_MAX_BODY_SIZE`: must be a usize"),
+        Err(_) => 1024 * 1024,
+    };
+
+    /// Per-route maximum request body sizes, in bytes, keyed by route name.
+    /// Batches get a larger limit by default.
+    static ref ROUTE_MAX_BODY_SIZES: HashMap<String, usize> = {
+        let mut sizes: HashMap<String, usize> = HashMap::new();
+        sizes.insert("transaction".to_string(), 16 * 1024 * 1024);
+
+        if let Ok(s) = env::var("BRAID_ROUTE_MAX_BODY_SIZES") {
+            for pair in s.split(',').filter(|pair| !pair.trim().is_empty()) {
+                let mut parts = pair.splitn(2, '=');
+                let name = parts.next().unwrap().trim();
+                let size = parts.next()
+                    .and_then(|size| size.trim().parse::<usize>().ok())
+                    .expect("Could not parse environment variable `BRAID_ROUTE_MAX_BODY_SIZES`: must be \
+                             a comma-separated list of `route=size` pairs");
+                sizes.insert(name.to_string(), size);
+            }
+        }
+
+        sizes
+    };
 }
 
 // Need this to avoid orphan rules
@@ -38,29 +68,77 @@ impl Key for AccountKey {
     type Value = AccountKey;
 }
 
+/// Holds the name of the route that matched a request.
+pub struct RouteKey {
+    pub name: &'static str,
+}
+
+impl Key for RouteKey {
+    type Value = RouteKey;
+}
+
+/// Holds per-request bookkeeping that is set as soon as a request comes in.
+pub struct RequestInfoKey {
+    pub request_id: String,
+    pub start: Instant,
+}
+
+impl Key for RequestInfoKey {
+    type Value = RequestInfoKey;
+}
+
+/// Holds the request body once it has been read.
+pub struct BodyKey {
+    pub body: Vec<u8>,
+}
+
+impl Key for BodyKey {
+    type Value = BodyKey;
+}
+
+/// Holds a request's `InFlightGuard`, so that the request counts as in
+/// flight until it is dropped.
+pub struct InFlightKey;
+
+impl Key for InFlightKey {
+    type Value = InFlightGuard;
+}
+
 /// Converts a braid error to an `IronError`. We need to use this strategy
 /// rather than a `From` impl because both traits are implemented outside of
 /// this crate.
 pub fn convert_to_iron_error(err: Error) -> IronError {
-    let status = match err {
-        Error::AccountNotFound | Error::VertexNotFound | Error::EdgeNotFound |
-        Error::MetadataNotFound => status::NotFound,
-        Error::OutOfRange(_) => status::BadRequest,
-        Error::Unauthorized => status::Unauthorized,
-        Error::Unexpected(_) => status::InternalServerError,
+    let (status, code) = match err {
+        Error::AccountNotFound => (status::NotFound, "account_not_found"),
+        Error::VertexNotFound => (status::NotFound, "vertex_not_found"),
+        Error::EdgeNotFound => (status::NotFound, "edge_not_found"),
+        Error::MetadataNotFound => (status::NotFound, "metadata_not_found"),
+        Error::OutOfRange(_) => (status::BadRequest, "out_of_range"),
+        Error::Unauthorized => (status::Unauthorized, "unauthorized"),
+        Error::Unexpected(_) => (status::InternalServerError, "internal_error"),
     };
 
-    create_iron_error(status, format!("{}", err))
+    create_iron_error(status, code, format!("{}", err))
 }
 
 /// Constructs an `IronError`
-pub fn create_iron_error(status_code: status::Status, err: String) -> IronError {
-    let mut o: serde_json::Map<String, JsonValue> = serde_json::Map::new();
-    o.insert("error".to_string(), JsonValue::String(err.clone()));
-    let body = serde_json::to_string(&o).unwrap();
+pub fn create_iron_error(status_code: status::Status, code: &'static str, err: String) -> IronError {
+    create_api_error(status_code, ApiError::new(code, err))
+}
+
+/// Constructs an `IronError` for a problem with a specific parameter or JSON
+/// field
+pub fn create_field_error(status_code: status::Status, code: &'static str, field: &str, err: String) -> IronError {
+    create_api_error(status_code, ApiError::new(code, err).with_field(field))
+}
+
+/// Constructs an `IronError` from an `ApiError`. The body does not include a
+/// request ID yet; `ErrorMiddleware` adds it.
+pub fn create_api_error(status_code: status::Status, err: ApiError) -> IronError {
+    let body = serde_json::to_string(&err.to_json(None)).unwrap();
     let json_content_type_modifier = HeaderModifier(ContentType(get_json_mime()));
     let modifiers = (status_code, json_content_type_modifier, body);
-    IronError::new(SimpleError::new(err), modifiers)
+    IronError::new(err, modifiers)
 }
 
 /// Returns a JSON content type specification
@@ -68,19 +146,58 @@ pub fn get_json_mime() -> Mime {
     Mime(TopLevel::Application, SubLevel::Json, vec![(Attr::Charset, Value::Utf8)])
 }
 
-/// Serializes a given body and status code into a response
-pub fn to_response<T: Serialize>(status_code: status::Status, body: &T) -> Response {
+/// Serializes a given body and status code into a response, in the format
+/// requested by the `Accept` header
+pub fn to_response<T: Serialize>(req: &Request, status_code: status::Status, body: &T) -> Response {
+    let format = Format::from_accept(req);
     let mut hs = Headers::new();
-    hs.set(ContentType(get_json_mime()));
+    hs.set(ContentType(format.mime()));
+    hs.set_raw("Vary", vec![b"Accept".to_vec()]);
 
     Response {
         status: Some(status_code),
         headers: hs,
         extensions: TypeMap::new(),
-        body: Some(Box::new(serde_json::to_string(&body).unwrap())),
+        body: Some(Box::new(format.serialize(body).unwrap())),
+    }
+}
+
+/// Buffers a response body into memory, so that it can be measured or
+/// transformed. The response is left without a body.
+pub fn take_response_body(res: &mut Response) -> io::Result<Vec<u8>> {
+    let mut buf: Vec<u8> = Vec::new();
+
+    if let Some(mut body) = res.body.take() {
+        body.write_body(&mut buf)?;
+    }
+
+    Ok(buf)
+}
+
+/// Gets the value of a header that is expected to be specified once, as a
+/// string
+pub fn get_header_string(req: &Request, name: &str) -> Option<String> {
+    match req.headers.get_raw(name) {
+        Some(values) if values.len() == 1 => {
+            match str::from_utf8(&values[0][..]) {
+                Ok(value) => Some(value.to_string()),
+                Err(_) => None,
+            }
+        }
+        _ => None,
     }
 }
 
+/// Gets the name of the route that matched the request, if any
+pub fn get_route_name(req: &Request) -> Option<&'static str> {
+    req.extensions.get::<RouteKey>().map(|route| route.name)
+}
+
+/// Gets the ID assigned to the request by `RequestLogMiddleware`, if any
+pub fn get_request_id(req: &Request) -> Option<String> {
+    req.extensions.get::<RequestInfoKey>().map(|info| info.request_id.clone())
+}
+
 /// Converts a URL parameter to a given type
 ///
 /// # Errors
@@ -91,8 +208,10 @@ pub fn get_url_param<T: FromStr>(req: &Request, name: &str) -> Result<T, IronErr
     match T::from_str(s) {
         Ok(val) => Ok(val),
         Err(_) => {
-            Err(create_iron_error(
+            Err(create_field_error(
                 status::BadRequest,
+                "invalid_parameter",
+                name,
                 format!("Invalid value for URL param {}", name)
             ))
         }
@@ -108,10 +227,8 @@ pub fn get_required_json_string_param(json: &serde_json::Map<String, JsonValue>,
     match json.get(name) {
         Some(&JsonValue::String(ref val)) => Ok(val.clone()),
         None |
-        Some(&JsonValue::Null) => {
-            Err(create_iron_error(status::BadRequest, format!("Missing `{}`", name)))
-        }
-        _ => Err(create_iron_error(status::BadRequest, format!("Invalid type for `{}`", name))),
+        Some(&JsonValue::Null) => Err(create_missing_field_error(name)),
+        _ => Err(create_invalid_field_error(name)),
     }
 }
 
@@ -126,13 +243,11 @@ pub fn get_required_json_f64_param(json: &serde_json::Map<String, JsonValue>, na
             if val.is_f64() {
                 Ok(val.as_f64().unwrap())
             } else {
-                Err(create_iron_error(status::BadRequest, format!("Invalid type for `{}`", name)))
+                Err(create_invalid_field_error(name))
             }
         },
-        None | Some(&JsonValue::Null) => {
-            Err(create_iron_error(status::BadRequest, format!("Missing `{}`", name)))
-        }
-        _ => Err(create_iron_error(status::BadRequest, format!("Invalid type for `{}`", name))),
+        None | Some(&JsonValue::Null) => Err(create_missing_field_error(name)),
+        _ => Err(create_invalid_field_error(name)),
     }
 }
 
@@ -145,10 +260,26 @@ pub fn get_required_json_obj_param<T: Deserialize>(json: &serde_json::Map<String
     if let Some(obj) = json.get(name) {
         match serde_json::from_value::<T>(obj.clone()) {
             Ok(val) => Ok(val),
-            Err(_) => Err(create_iron_error(status::BadRequest, format!("Invalid type for `{}`", name)))
+            Err(_) => Err(create_invalid_field_error(name))
         }
     } else {
-        Err(create_iron_error(status::BadRequest, format!("Missing `{}`", name)))
+        Err(create_missing_field_error(name))
+    }
+}
+
+/// Gets an optional JSON value, deserialized to the given type
+///
+/// # Errors
+/// Returns an `IronError` if the value has an unexpected type.
+pub fn get_optional_json_obj_param<T: Deserialize>(json: &serde_json::Map<String, JsonValue>, name: &str) -> Result<Option<T>, IronError> {
+    match json.get(name) {
+        None | Some(&JsonValue::Null) => Ok(None),
+        Some(obj) => {
+            match serde_json::from_value::<T>(obj.clone()) {
+                Ok(val) => Ok(Some(val)),
+                Err(_) => Err(create_invalid_field_error(name))
+            }
+        }
     }
 }
 
@@ -163,8 +294,10 @@ pub fn get_required_json_type_param(json: &serde_json::Map<String, JsonValue>, n
     match Type::from_str(&s[..]) {
         Ok(u) => Ok(u),
         Err(_) => {
-            Err(create_iron_error(
+            Err(create_field_error(
                 status::BadRequest,
+                "invalid_field",
+                name,
                 format!("Invalid type format for `{}`", name)
             ))
         }
@@ -182,14 +315,24 @@ pub fn get_required_json_weight_param(json: &serde_json::Map<String, JsonValue>,
     match Weight::new(w as f32) {
         Ok(w) => Ok(w),
         Err(_) => {
-            Err(create_iron_error(
+            Err(create_field_error(
                 status::BadRequest,
+                "invalid_field",
+                name,
                 format!("Invalid weight format for `{}`: it should be a float between -1.0 and 1.0 inclusive.", name)
             ))
         }
     }
 }
 
+fn create_missing_field_error(name: &str) -> IronError {
+    create_field_error(status::BadRequest, "missing_field", name, format!("Missing `{}`", name))
+}
+
+fn create_invalid_field_error(name: &str) -> IronError {
+    create_field_error(status::BadRequest, "invalid_field", name, format!("Invalid type for `{}`", name))
+}
+
 /// Parses a response from the datastore into a specified type
 ///
 /// # Errors
@@ -207,6 +350,15 @@ pub fn get_account_id(req: &Request) -> Uuid {
     ext.account_id
 }
 
+/// Creates an audit log entry for an action taken through a request, with
+/// the request's account, source IP and request ID filled in
+pub fn create_audit_entry(req: &Request, action: &str) -> AuditEntry {
+    AuditEntry::new(action)
+        .with_account_id(req.extensions.get::<AccountKey>().map(|account| account.account_id))
+        .with_source_ip(Some(req.remote_addr.ip().to_string()))
+        .with_request_id(get_request_id(req))
+}
+
 /// Gets a new transaction, tied to the request's account UUID
 ///
 /// # Errors
@@ -218,37 +370,89 @@ pub fn get_transaction(req: &Request) -> Result<ProxyTransaction, IronError> {
         Err(err) => {
             Err(create_iron_error(
                 status::InternalServerError,
+                "transaction_failed",
                 format!("Could not create datastore transaction: {}", err)
             ))
         }
     }
 }
 
-// Reads the request body into an optional `JsonValue`
+/// Gets the maximum request body size for the route that matched the
+/// request
+pub fn get_max_body_size(req: &Request) -> usize {
+    match get_route_name(req).and_then(|name| ROUTE_MAX_BODY_SIZES.get(name)) {
+        Some(size) => *size,
+        None => *DEFAULT_MAX_BODY_SIZE,
+    }
+}
+
+/// Reads the raw request body. The body is cached in the request's
+/// extensions, so this can be called more than once per request.
 ///
 /// # Errors
-/// Returns an `IronError` if the body could not be read, or if a body was
-/// specified but is not valid JSON.
-pub fn read_optional_json(body: &mut Body) -> Result<Option<JsonValue>, IronError> {
-    let mut payload = String::new();
-    let read_result: Result<usize, io::Error> = body.read_to_string(&mut payload);
+/// Returns an `IronError` if the body could not be read, or is larger than
+/// the route's maximum body size.
+pub fn read_body(req: &mut Request) -> Result<Vec<u8>, IronError> {
+    if let Some(body) = req.extensions.get::<BodyKey>() {
+        return Ok(body.body.clone());
+    }
+
+    let max_size = get_max_body_size(req);
+
+    if let Some(&ContentLength(length)) = req.headers.get::<ContentLength>() {
+        if length > max_size as u64 {
+            return Err(create_body_too_large_error(max_size));
+        }
+    }
+
+    // Read at most one byte more than the limit, so that bodies without a
+    // content length that are too large can be detected without reading
+    // them entirely.
+    let mut body: Vec<u8> = Vec::new();
+    let read_result: Result<usize, io::Error> = Read::by_ref(&mut req.body)
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut body);
 
     if let Err(err) = read_result {
         return Err(create_iron_error(
             status::BadRequest,
-            format!("Could not read JSON body: {}", err)
+            "invalid_body",
+            format!("Could not read body: {}", err)
         ));
     }
 
-    if payload.is_empty() {
+    if body.len() > max_size {
+        return Err(create_body_too_large_error(max_size));
+    }
+
+    req.extensions.insert::<BodyKey>(BodyKey { body: body.clone() });
+    Ok(body)
+}
+
+/// Reads the request body into an optional `JsonValue`. The body is decoded
+/// according to the `Content-Type` header, and is expected to be JSON if
+/// there is none.
+///
+/// # Errors
+/// Returns an `IronError` if the body could not be read, is larger than the
+/// route's maximum body size, or if a body was specified but could not be
+/// decoded.
+pub fn read_optional_json(req: &mut Request) -> Result<Option<JsonValue>, IronError> {
+    let body = read_body(req)?;
+    let format = Format::from_content_type(req);
+
+    if body.is_empty() {
         Ok(None)
     } else {
-        match serde_json::from_str(&payload[..]) {
+        match format.deserialize(&body[..]) {
             Ok(json) => Ok(Some(json)),
             Err(err) => {
+                let code = if format == Format::Json { "invalid_json" } else { "invalid_body" };
+
                 Err(create_iron_error(
                     status::BadRequest,
-                    format!("Could not parse JSON payload: {}", err.description())
+                    code,
+                    format!("Could not parse {} payload: {}", format.name(), err)
                 ))
             }
         }
@@ -258,14 +462,23 @@ pub fn read_optional_json(body: &mut Body) -> Result<Option<JsonValue>, IronErro
 /// Reads the request body into a `JsonValue`.
 ///
 /// # Errors
-/// Returns an `IronError` if the body could not be read, or is not valid JSON.
-pub fn read_required_json(mut body: &mut Body) -> Result<JsonValue, IronError> {
-    match read_optional_json(&mut body)? {
+/// Returns an `IronError` if the body could not be read, is larger than the
+/// route's maximum body size, or is not valid JSON.
+pub fn read_required_json(req: &mut Request) -> Result<JsonValue, IronError> {
+    match read_optional_json(req)? {
         Some(value) => Ok(value),
-        None => Err(create_iron_error(status::BadRequest, "Missing JSON payload".to_string())),
+        None => Err(create_iron_error(status::BadRequest, "missing_body", "Missing JSON payload".to_string())),
     }
 }
 
+fn create_body_too_large_error(max_size: usize) -> IronError {
+    create_iron_error(
+        status::PayloadTooLarge,
+        "body_too_large",
+        format!("Request body is too large: the maximum size is {} bytes", max_size)
+    )
+}
+
 /// Parses the and returns the request query parameters.
 ///
 /// # Errors
@@ -277,6 +490,7 @@ pub fn get_query_params<'a>(req: &'a mut Request) -> Result<&'a HashMap<String,
         Err(_) => {
             Err(create_iron_error(
                 status::BadRequest,
+                "invalid_parameter",
                 "Could not parse query parameters".to_string()
             ))
         }
@@ -293,8 +507,10 @@ pub fn get_query_param<T: FromStr>(params: &HashMap<String, Vec<String>>, key: &
             match first_value.parse::<T>() {
                 Ok(value) => return Ok(Some(value)),
                 Err(_) => {
-                    return Err(create_iron_error(
+                    return Err(create_field_error(
                         status::BadRequest,
+                        "invalid_parameter",
+                        key,
                         format!("Could not parse query parameter `{}`", key)
                     ))
                 }
@@ -303,8 +519,10 @@ pub fn get_query_param<T: FromStr>(params: &HashMap<String, Vec<String>>, key: &
     }
 
     if required {
-        Err(create_iron_error(
+        Err(create_field_error(
             status::BadRequest,
+            "missing_parameter",
+            key,
             format!("Missing required query parameter `{}`", key)
         ))
     } else {
@@ -312,17 +530,37 @@ pub fn get_query_param<T: FromStr>(params: &HashMap<String, Vec<String>>, key: &
     }
 }
 
-/// Gets a required object from the query parameters.
+/// Gets a required query object from the `q` query parameter. `kind`
+/// describes the expected query, e.g. `edge query`, for error messages.
 ///
 /// # Errors
 /// Returns an `IronError if the query could be parsed, or was not specified.
-pub fn get_obj_query_param<T: Deserialize>(query_params: &HashMap<String, Vec<String>>) -> Result<T, IronError> {
+pub fn get_obj_query_param<T: Deserialize>(query_params: &HashMap<String, Vec<String>>, kind: &str) -> Result<T, IronError> {
     let q_json = get_query_param::<JsonValue>(query_params, "q", true)?.unwrap();
-    
-    match serde_json::from_value::<T>(q_json) {
+    parse_query_json(q_json, "q", kind)
+}
+
+/// Gets a required query object from the JSON request body. `kind`
+/// describes the expected query, e.g. `edge query`, for error messages.
+///
+/// # Errors
+/// Returns an `IronError` if the body could not be read, or is not a valid
+/// query.
+pub fn read_query_body<T: Deserialize>(req: &mut Request, kind: &str) -> Result<T, IronError> {
+    let json = read_required_json(req)?;
+    parse_query_json(json, "body", kind)
+}
+
+fn parse_query_json<T: Deserialize>(json: JsonValue, field: &str, kind: &str) -> Result<T, IronError> {
+    match serde_json::from_value::<T>(json) {
         Ok(q) => Ok(q),
         Err(_) => {
-            Err(create_iron_error(status::BadRequest, "Invalid type for `q`: expected edge query".to_string()))
+            Err(create_field_error(
+                status::BadRequest,
+                "invalid_query",
+                field,
+                format!("Invalid type for `{}`: expected {}", field, kind)
+            ))
         }
     }
 }
@@ -336,34 +574,168 @@ pub fn get_weight_query_param(query_params: &HashMap<String, Vec<String>>) -> Re
 
     match Weight::new(weight_f32) {
         Ok(weight) => Ok(weight),
-        Err(_) => Err(create_iron_error(status::BadRequest, "Invalid type for `weight`: expected float between -1.0 and 1.0".to_string()))
+        Err(_) => Err(create_field_error(status::BadRequest, "invalid_parameter", "weight", "Invalid type for `weight`: expected float between -1.0 and 1.0".to_string()))
     }
 }
 
-/// Executes a script, returning its json output.
+/// Checks that a script name is valid.
+///
+/// # Errors
+/// Returns an `IronError` if the name is invalid.
+pub fn validate_script_name(name: &str) -> Result<(), IronError> {
+    if is_valid_script_name(name) {
+        Ok(())
+    } else {
+        Err(create_iron_error(status::BadRequest, "invalid_script_name", "Invalid script name".to_string()))
+    }
+}
+
+/// Splits a reference to a script, such as `report.lua@3`, into the name
+/// of the script and the version it is pinned to, if any.
+///
+/// # Errors
+/// Returns an `IronError` if the name or version is invalid.
+pub fn parse_script_reference(reference: &str) -> Result<(String, Option<u32>), IronError> {
+    let (name, version) = match reference.find('@') {
+        Some(idx) => {
+            match reference[idx + 1..].parse::<u32>() {
+                Ok(version) => (&reference[..idx], Some(version)),
+                Err(_) => return Err(create_iron_error(status::BadRequest, "invalid_script_version", "Invalid script version".to_string())),
+            }
+        }
+        None => (reference, None),
+    };
+
+    validate_script_name(name)?;
+    Ok((name.to_string(), version))
+}
+
+/// Executes a script, returning its json output. Scripts stored in the
+/// datastore take precedence over files under the script root. If a
+/// version is given, only that version of a stored script is run.
+///
+/// What the script prints or logs is written to the server log, tagged with
+/// the account, script and request. If `debug` is set, it's also returned,
+/// in an object with the script's output as `result` and the entries as
+/// `logs`.
 ///
 /// # Errors
 /// Returns an `IronError` if the script could not be loaded, or fialed to
 /// execute.
-pub fn execute_script(name: String, payload: &JsonValue, trans: &ProxyTransaction, account_id: Uuid) -> Result<JsonValue, IronError> {
-    if !SCRIPT_NAME_VALIDATOR.is_match(&name[..]) {
-        return Err(create_iron_error(status::BadRequest, "Invalid script name".to_string()));
-    }
+pub fn execute_script(name: String,
+                      version: Option<u32>,
+                      payload: &JsonValue,
+                      trans: &ProxyTransaction,
+                      account_id: Uuid,
+                      request_id: Option<String>,
+                      debug: bool)
+                      -> Result<JsonValue, IronError> {
+    validate_script_name(&name[..])?;
+
+    let source = match datastore_request(script::find_source(trans, account_id, &name[..], version))? {
+        Some(source) => source,
+        None => return Err(create_iron_error(status::NotFound, "script_not_found", "Script version not found".to_string())),
+    };
 
-    let path = Path::new(&statics::SCRIPT_ROOT[..]).join(name);
+    let settings = source.settings(&name[..]);
+    let start = Instant::now();
+    let mut log: Vec<script::ScriptLogEntry> = Vec::new();
+    let result = script::run(trans, account_id, &source, payload, &settings, &mut log);
+    let label = get_script_metric_label(&name[..], &source, &result);
+    METRICS.script_duration.observe_duration(&[label], start.elapsed());
+    METRICS.script_executions.inc(&[label, if result.is_ok() { "ok" } else { "error" }]);
+    log_script_output(&name[..], version, account_id, request_id, &log);
+
+    let logs = if debug {
+        Some(log.iter().map(|entry| entry.to_json()).collect::<Vec<JsonValue>>())
+    } else {
+        None
+    };
 
-    match script::run(trans, account_id, &path, payload) {
-        Ok(val) => Ok(val),
-        Err(err) => {
-            match err {
-                script::ScriptError::File => {
-                    Err(create_iron_error(status::NotFound, "Could not load script".to_string()))
-                },
-                _ => {
-                    let error_message = format!("Script failed: {:?}", err);
-                    Err(create_iron_error(status::InternalServerError, error_message))
+    let err = match result {
+        Ok(val) => {
+            return Ok(match logs {
+                Some(logs) => {
+                    let mut o: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+                    o.insert("result".to_string(), val);
+                    o.insert("logs".to_string(), JsonValue::Array(logs));
+                    JsonValue::Object(o)
                 }
-            }
+                None => val,
+            });
         }
+        Err(err) => err,
+    };
+
+    let code = match err {
+        script::ScriptError::File => {
+            let api_error = ApiError::new("script_not_found", "Could not load script".to_string());
+            return Err(create_script_error(status::NotFound, api_error, logs));
+        }
+        script::ScriptError::Timeout(message) => {
+            let api_error = ApiError::new("script_timeout", message);
+            return Err(create_script_error(status::ServiceUnavailable, api_error, logs));
+        }
+        script::ScriptError::Forbidden(message) => {
+            let api_error = ApiError::new("script_forbidden", message);
+            return Err(create_script_error(status::Forbidden, api_error, logs));
+        }
+        script::ScriptError::Syntax(_) => "script_syntax_error",
+        script::ScriptError::Memory(_) => "script_memory_error",
+        script::ScriptError::Runtime(_) => "script_runtime_error",
+        script::ScriptError::Panicked(_) => "script_panicked",
+    };
+
+    let api_error = ApiError::new(code, format!("Script failed: {:?}", err));
+    Err(create_script_error(status::InternalServerError, api_error, logs))
+}
+
+/// Constructs an `IronError` for a failed script, including what it logged if
+/// it was run in debug mode.
+fn create_script_error(status_code: status::Status, api_error: ApiError, logs: Option<Vec<JsonValue>>) -> IronError {
+    match logs {
+        Some(logs) => create_api_error(status_code, api_error.with_logs(logs)),
+        None => create_api_error(status_code, api_error),
+    }
+}
+
+/// Gets the `script` label of the metrics of a run. Only files under the
+/// script root are labelled with their name, since any name can be requested
+/// or stored, which would make for an unbounded number of series.
+fn get_script_metric_label<'a>(name: &'a str,
+                               source: &script::ScriptSource,
+                               result: &Result<JsonValue, script::ScriptError>)
+                               -> &'a str {
+    match (source, result) {
+        (&script::ScriptSource::Stored(_), _) => "(stored)",
+        (&script::ScriptSource::File(_), &Err(script::ScriptError::File)) => "(unknown)",
+        (&script::ScriptSource::File(_), _) => name,
+    }
+}
+
+/// Writes what a script printed or logged to the server log.
+fn log_script_output(name: &str, version: Option<u32>, account_id: Uuid, request_id: Option<String>, log: &[script::ScriptLogEntry]) {
+    let reference = match version {
+        Some(version) => format!("{}@{}", name, version),
+        None => name.to_string(),
+    };
+
+    for entry in log {
+        let level = match entry.level {
+            script::ScriptLogLevel::Debug => Level::Debug,
+            script::ScriptLogLevel::Info => Level::Info,
+            script::ScriptLogLevel::Warn => Level::Warn,
+            script::ScriptLogLevel::Error => Level::Error,
+        };
+
+        let mut o: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+        o.insert("request_id".to_string(), match request_id {
+            Some(ref request_id) => JsonValue::String(request_id.clone()),
+            None => JsonValue::Null,
+        });
+        o.insert("account_id".to_string(), JsonValue::String(account_id.to_string()));
+        o.insert("script".to_string(), JsonValue::String(reference.clone()));
+        o.insert("message".to_string(), JsonValue::String(entry.message.clone()));
+        statics::LOGGER.log(level, o);
     }
 }
@@ -0,0 +1,111 @@
+use std::io;
+use std::io::Write;
+use std::fs::OpenOptions;
+use std::sync::Mutex;
+use core::str::FromStr;
+use serde_json::value::Value as JsonValue;
+use serde_json;
+use chrono::UTC;
+
+/// The severity of a log entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Off,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+            Level::Off => "off",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Level, ()> {
+        match &s.to_lowercase()[..] {
+            "debug" => Ok(Level::Debug),
+            "info" => Ok(Level::Info),
+            "warn" => Ok(Level::Warn),
+            "error" => Ok(Level::Error),
+            "off" => Ok(Level::Off),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Writes structured log entries, one JSON object per line.
+pub struct Logger {
+    level: Level,
+    destination: Mutex<Box<Write + Send>>,
+}
+
+impl Logger {
+    pub fn new(level: Level, destination: Box<Write + Send>) -> Logger {
+        Logger {
+            level: level,
+            destination: Mutex::new(destination),
+        }
+    }
+
+    /// Creates a logger from a destination string and a level string.
+    ///
+    /// The destination can be `stdout`, `stderr`, or a path to a file that
+    /// log entries will be appended to.
+    ///
+    /// # Errors
+    /// Returns an error if the level could not be parsed, or the destination
+    /// file could not be opened.
+    pub fn from_config(destination: &str, level: &str) -> Result<Logger, String> {
+        let level = match Level::from_str(level) {
+            Ok(level) => level,
+            Err(_) => return Err(format!("Unknown log level: {}", level)),
+        };
+
+        let destination: Box<Write + Send> = match destination {
+            "stdout" => Box::new(io::stdout()),
+            "stderr" => Box::new(io::stderr()),
+            path => {
+                match OpenOptions::new().create(true).append(true).open(path) {
+                    Ok(file) => Box::new(file),
+                    Err(err) => return Err(format!("Could not open log file `{}`: {}", path, err)),
+                }
+            }
+        };
+
+        Ok(Logger::new(level, destination))
+    }
+
+    /// Returns whether entries of the given level will be written.
+    pub fn enabled(&self, level: Level) -> bool {
+        level != Level::Off && level >= self.level
+    }
+
+    /// Writes a log entry, adding the current time and the level to it.
+    pub fn log(&self, level: Level, mut entry: serde_json::Map<String, JsonValue>) {
+        if !self.enabled(level) {
+            return;
+        }
+
+        entry.insert("time".to_string(), JsonValue::String(UTC::now().to_rfc3339()));
+        entry.insert("level".to_string(), JsonValue::String(level.as_str().to_string()));
+        let line = serde_json::to_string(&entry).unwrap();
+
+        // Failing to write a log entry shouldn't take down the request, so
+        // errors are ignored here.
+        if let Ok(mut destination) = self.destination.lock() {
+            let _ = writeln!(destination, "{}", line);
+            let _ = destination.flush();
+        }
+    }
+}
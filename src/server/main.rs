@@ -9,22 +9,53 @@ extern crate libc;
 extern crate regex;
 extern crate uuid;
 extern crate braid;
-extern crate lua;
 extern crate hyper;
+extern crate hyper_openssl;
+extern crate openssl;
 extern crate common;
+extern crate flate2;
+extern crate rmp_serde;
+extern crate serde_cbor;
 #[macro_use]
 extern crate lazy_static;
 
 mod http;
-mod script;
-mod util;
+mod logging;
+mod metrics;
+mod shutdown;
 mod statics;
 
 use std::env;
+use std::path::PathBuf;
 
 /// App for exposing a `RESTful` API for a datastore
 fn main() {
+    let address = env::var("BRAID_BIND_ADDRESS").unwrap_or_else(|_| "0.0.0.0".to_string());
     let port_str = env::var("PORT").unwrap_or_else(|_| "8000".to_string());
     let port = port_str.parse::<u16>().expect("Could not parse environment variable `PORT`");
-    http::start(port);
+    let admin_address = env::var("BRAID_ADMIN_BIND_ADDRESS").unwrap_or_else(|_| "127.0.0.1".to_string());
+    let admin_port = env::var("BRAID_ADMIN_PORT").ok().map(|s| {
+        s.parse::<u16>().expect("Could not parse environment variable `BRAID_ADMIN_PORT`")
+    });
+
+    let tls = match (env::var("BRAID_TLS_CERT"), env::var("BRAID_TLS_KEY")) {
+        (Ok(cert_path), Ok(key_path)) => {
+            Some(http::TlsConfig {
+                cert_path: PathBuf::from(cert_path),
+                key_path: PathBuf::from(key_path),
+                client_ca_path: env::var("BRAID_TLS_CLIENT_CA").ok().map(PathBuf::from),
+            })
+        }
+        (Err(_), Err(_)) => None,
+        _ => panic!("Both `BRAID_TLS_CERT` and `BRAID_TLS_KEY` must be set to enable HTTPS"),
+    };
+
+    if let Err(err) = common::script::validate_settings() {
+        panic!("Could not load script settings: {}", err);
+    }
+
+    common::set_transaction_observer(metrics::observe_transaction_call);
+    common::script::set_script_observer(metrics::observe_script_event);
+    shutdown::start();
+    http::start(&address[..], port, &admin_address[..], admin_port, tls);
 }
@@ -0,0 +1,268 @@
+//! A minimal metrics registry, rendered in the prometheus text exposition
+//! format.
+
+use common::script::ScriptEvent;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Default histogram buckets, in seconds.
+const DEFAULT_BUCKETS: &'static [f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// A set of counters, partitioned by label values.
+pub struct CounterVec {
+    name: &'static str,
+    help: &'static str,
+    label_names: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl CounterVec {
+    pub fn new(name: &'static str, help: &'static str, label_names: &'static [&'static str]) -> CounterVec {
+        CounterVec {
+            name: name,
+            help: help,
+            label_names: label_names,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Increments the counter with the given label values.
+    pub fn inc(&self, label_values: &[&str]) {
+        self.inc_by(label_values, 1);
+    }
+
+    /// Increments the counter with the given label values by an amount.
+    pub fn inc_by(&self, label_values: &[&str], amount: u64) {
+        debug_assert_eq!(label_values.len(), self.label_names.len());
+        let key: Vec<String> = label_values.iter().map(|v| v.to_string()).collect();
+        let mut values = self.values.lock().unwrap();
+        *values.entry(key).or_insert(0) += amount;
+    }
+
+    /// Gets the current value of the counter with the given label values.
+    pub fn get(&self, label_values: &[&str]) -> u64 {
+        let key: Vec<String> = label_values.iter().map(|v| v.to_string()).collect();
+        *self.values.lock().unwrap().get(&key).unwrap_or(&0)
+    }
+
+    fn render(&self, out: &mut String) {
+        writeln!(out, "# HELP {} {}", self.name, self.help).unwrap();
+        writeln!(out, "# TYPE {} counter", self.name).unwrap();
+
+        for (label_values, value) in self.values.lock().unwrap().iter() {
+            let labels = format_labels(self.label_names, label_values, None);
+            writeln!(out, "{}{} {}", self.name, labels, value).unwrap();
+        }
+    }
+}
+
+struct HistogramData {
+    bucket_counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// A set of histograms, partitioned by label values.
+pub struct HistogramVec {
+    name: &'static str,
+    help: &'static str,
+    label_names: &'static [&'static str],
+    buckets: &'static [f64],
+    values: Mutex<BTreeMap<Vec<String>, HistogramData>>,
+}
+
+impl HistogramVec {
+    pub fn new(name: &'static str, help: &'static str, label_names: &'static [&'static str]) -> HistogramVec {
+        HistogramVec {
+            name: name,
+            help: help,
+            label_names: label_names,
+            buckets: DEFAULT_BUCKETS,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records an observation for the histogram with the given label values.
+    pub fn observe(&self, label_values: &[&str], value: f64) {
+        debug_assert_eq!(label_values.len(), self.label_names.len());
+        let key: Vec<String> = label_values.iter().map(|v| v.to_string()).collect();
+        let mut values = self.values.lock().unwrap();
+        let buckets = self.buckets;
+
+        let data = values.entry(key).or_insert_with(|| {
+            HistogramData {
+                bucket_counts: vec![0; buckets.len()],
+                sum: 0.0,
+                count: 0,
+            }
+        });
+
+        for (i, upper_bound) in buckets.iter().enumerate() {
+            if value <= *upper_bound {
+                data.bucket_counts[i] += 1;
+            }
+        }
+
+        data.sum += value;
+        data.count += 1;
+    }
+
+    /// Records a duration, in seconds.
+    pub fn observe_duration(&self, label_values: &[&str], duration: Duration) {
+        self.observe(label_values, duration_to_seconds(duration));
+    }
+
+    fn render(&self, out: &mut String) {
+        writeln!(out, "# HELP {} {}", self.name, self.help).unwrap();
+        writeln!(out, "# TYPE {} histogram", self.name).unwrap();
+
+        for (label_values, data) in self.values.lock().unwrap().iter() {
+            for (i, upper_bound) in self.buckets.iter().enumerate() {
+                let le = upper_bound.to_string();
+                let labels = format_labels(self.label_names, label_values, Some(&le[..]));
+                writeln!(out, "{}_bucket{} {}", self.name, labels, data.bucket_counts[i]).unwrap();
+            }
+
+            let labels = format_labels(self.label_names, label_values, Some("+Inf"));
+            writeln!(out, "{}_bucket{} {}", self.name, labels, data.count).unwrap();
+
+            let labels = format_labels(self.label_names, label_values, None);
+            writeln!(out, "{}_sum{} {}", self.name, labels, data.sum).unwrap();
+            writeln!(out, "{}_count{} {}", self.name, labels, data.count).unwrap();
+        }
+    }
+}
+
+/// All of the metrics exposed by the server.
+pub struct Metrics {
+    pub http_requests: CounterVec,
+    pub http_request_duration: HistogramVec,
+    pub datastore_call_duration: HistogramVec,
+    pub transactions: CounterVec,
+    pub script_executions: CounterVec,
+    pub script_duration: HistogramVec,
+    pub script_cache_lookups: CounterVec,
+    pub script_states_created: CounterVec,
+    pub auth_failures: CounterVec,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        Metrics {
+            http_requests: CounterVec::new(
+                "braid_http_requests_total",
+                "Number of HTTP requests handled.",
+                &["route", "status"]
+            ),
+            http_request_duration: HistogramVec::new(
+                "braid_http_request_duration_seconds",
+                "Time spent handling HTTP requests.",
+                &["route", "status"]
+            ),
+            datastore_call_duration: HistogramVec::new(
+                "braid_datastore_call_duration_seconds",
+                "Time spent in datastore transaction calls.",
+                &["method"]
+            ),
+            transactions: CounterVec::new(
+                "braid_transactions_total",
+                "Number of datastore transactions that were finished.",
+                &["outcome"]
+            ),
+            script_executions: CounterVec::new(
+                "braid_script_executions_total",
+                "Number of script executions.",
+                &["script", "result"]
+            ),
+            script_duration: HistogramVec::new(
+                "braid_script_duration_seconds",
+                "Time spent executing scripts.",
+                &["script"]
+            ),
+            script_cache_lookups: CounterVec::new(
+                "braid_script_cache_lookups_total",
+                "Number of lookups in the compiled script cache.",
+                &["result"]
+            ),
+            script_states_created: CounterVec::new(
+                "braid_script_states_created_total",
+                "Number of lua states prepared for running scripts.",
+                &[]
+            ),
+            auth_failures: CounterVec::new(
+                "braid_auth_failures_total",
+                "Number of requests that failed authentication.",
+                &[]
+            ),
+        }
+    }
+
+    /// Renders all metrics in the prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.http_requests.render(&mut out);
+        self.http_request_duration.render(&mut out);
+        self.datastore_call_duration.render(&mut out);
+        self.transactions.render(&mut out);
+        self.script_executions.render(&mut out);
+        self.script_duration.render(&mut out);
+        self.script_cache_lookups.render(&mut out);
+        self.script_states_created.render(&mut out);
+        self.auth_failures.render(&mut out);
+        out
+    }
+}
+
+lazy_static! {
+    /// The server-wide metrics registry
+    pub static ref METRICS: Metrics = Metrics::new();
+}
+
+/// Records a call made through a `ProxyTransaction`. This is registered as
+/// the transaction observer when the server starts.
+pub fn observe_transaction_call(method: &'static str, duration: Duration) {
+    METRICS.datastore_call_duration.observe_duration(&[method], duration);
+
+    match method {
+        "commit" | "rollback" => METRICS.transactions.inc(&[method]),
+        _ => (),
+    }
+}
+
+/// Converts a duration into fractional seconds.
+pub fn duration_to_seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+fn format_labels(label_names: &[&str], label_values: &[String], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = label_names.iter()
+        .zip(label_values.iter())
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+        .collect();
+
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+
+    if pairs.is_empty() {
+        "".to_string()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n")
+}
+
+/// Records something that happened while preparing or running scripts.
+/// This is registered as the script observer when the server starts.
+pub fn observe_script_event(event: ScriptEvent) {
+    match event {
+        ScriptEvent::CacheHit => METRICS.script_cache_lookups.inc(&["hit"]),
+        ScriptEvent::CacheMiss => METRICS.script_cache_lookups.inc(&["miss"]),
+        ScriptEvent::StateCreated => METRICS.script_states_created.inc(&[]),
+    }
+}
@@ -0,0 +1,195 @@
+//! This module handles graceful shutdown. On SIGTERM or SIGINT, the server
+//! starts draining: the listeners stop accepting connections, requests on
+//! connections that are already open are rejected, and in-flight requests are
+//! given until a deadline to finish before the process exits.
+//!
+//! Requests still running at the deadline are abandoned. Their transactions
+//! are aborted first, so that they're rolled back rather than committed by a
+//! request that finishes while the process exits.
+
+use common;
+use hyper;
+use hyper::net::NetworkListener;
+use libc;
+use logging::Level;
+use serde_json;
+use serde_json::value::Value as JsonValue;
+use statics;
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the shutdown thread checks for signals and finished requests.
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// The exit status when requests were still in flight at the deadline.
+const UNCLEAN_EXIT_STATUS: i32 = 1;
+
+/// How long aborted transactions are given to be rolled back before the
+/// process exits.
+const ABORT_GRACE_PERIOD_MS: u64 = 1000;
+
+/// Set by the signal handler, which can only do signal-safe things like
+/// storing to a static atomic.
+static SHUTDOWN_REQUESTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+static DRAINING: AtomicBool = ATOMIC_BOOL_INIT;
+static IN_FLIGHT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+lazy_static! {
+    /// How long in-flight requests are given to finish after a shutdown
+    /// signal, in seconds
+    static ref SHUTDOWN_DEADLINE: Duration = match env::var("BRAID_SHUTDOWN_DEADLINE") {
+        Ok(s) => Duration::from_secs(s.parse().expect("Could not parse environment variable `BRAID_SHUTDOWN_DEADLINE`: must be a u64")),
+        Err(_) => Duration::from_secs(30),
+    };
+
+    /// The file descriptors of the listening sockets, which are shut down
+    /// when draining starts.
+    static ref LISTENER_FDS: Mutex<Vec<RawFd>> = Mutex::new(Vec::new());
+}
+
+extern "C" fn handle_signal(_: libc::c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Installs the SIGTERM and SIGINT handlers, and starts the thread that
+/// drains requests and exits the process once a signal is received.
+pub fn start() {
+    let _ = *SHUTDOWN_DEADLINE;
+
+    unsafe {
+        libc::signal(libc::SIGTERM, handle_signal as libc::sighandler_t);
+        libc::signal(libc::SIGINT, handle_signal as libc::sighandler_t);
+    }
+
+    thread::spawn(|| {
+        while !SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+
+        DRAINING.store(true, Ordering::SeqCst);
+        close_listeners();
+        log(Level::Info, "Shutting down: draining in-flight requests");
+        let start = Instant::now();
+
+        while in_flight() > 0 && start.elapsed() < *SHUTDOWN_DEADLINE {
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+
+        if in_flight() == 0 {
+            log(Level::Info, "Shut down cleanly");
+            process::exit(0);
+        }
+
+        common::abort_transactions();
+        let abort_start = Instant::now();
+
+        while common::open_transactions() > 0 && abort_start.elapsed() < Duration::from_millis(ABORT_GRACE_PERIOD_MS) {
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+
+        log(Level::Error, "Shutdown deadline passed: aborted transactions and abandoned in-flight requests");
+        process::exit(UNCLEAN_EXIT_STATUS);
+    });
+}
+
+/// Registers a listening socket, so that it's shut down when draining starts.
+pub fn register_listener(fd: RawFd) {
+    LISTENER_FDS.lock().unwrap().push(fd);
+}
+
+/// Shuts down the registered listening sockets. Connection attempts are then
+/// refused, and threads blocked accepting connections are woken up.
+fn close_listeners() {
+    for fd in LISTENER_FDS.lock().unwrap().iter() {
+        unsafe {
+            libc::shutdown(*fd, libc::SHUT_RDWR);
+        }
+    }
+}
+
+/// Returns whether the server is shutting down, and should not accept new
+/// requests.
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+/// Returns the number of requests being handled.
+pub fn in_flight() -> usize {
+    IN_FLIGHT.load(Ordering::SeqCst)
+}
+
+/// Tracks a request as in flight for as long as it is held.
+pub struct InFlightGuard {}
+
+impl InFlightGuard {
+    pub fn new() -> InFlightGuard {
+        IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard {}
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Wraps a listener so that it stops accepting connections once the server
+/// is draining. A connection accepted after that is closed right away, and
+/// the thread accepting it blocks until the process exits.
+#[derive(Clone)]
+pub struct DrainingListener<L: NetworkListener> {
+    inner: L,
+}
+
+impl<L: NetworkListener> DrainingListener<L> {
+    pub fn new(inner: L) -> DrainingListener<L> {
+        DrainingListener { inner: inner }
+    }
+}
+
+impl<L: NetworkListener> NetworkListener for DrainingListener<L> {
+    type Stream = L::Stream;
+
+    fn accept(&mut self) -> hyper::Result<L::Stream> {
+        let result = self.inner.accept();
+
+        if is_draining() {
+            drop(result);
+
+            loop {
+                thread::park();
+            }
+        }
+
+        result
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn set_read_timeout(&mut self, duration: Option<Duration>) {
+        self.inner.set_read_timeout(duration)
+    }
+
+    fn set_write_timeout(&mut self, duration: Option<Duration>) {
+        self.inner.set_write_timeout(duration)
+    }
+}
+
+fn log(level: Level, message: &str) {
+    let mut entry: serde_json::Map<String, JsonValue> = serde_json::Map::new();
+    entry.insert("message".to_string(), JsonValue::String(message.to_string()));
+    entry.insert("in_flight".to_string(), JsonValue::from(in_flight()));
+    entry.insert("open_transactions".to_string(), JsonValue::from(common::open_transactions()));
+    statics::LOGGER.log(level, entry);
+}
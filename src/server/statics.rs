@@ -1,14 +1,19 @@
 use common::{ProxyDatastore, datastore};
+use logging::Logger;
 use std::env;
-use std::path::Path;
 
 lazy_static! {
     /// The underlying datastore
     pub static ref DATASTORE: ProxyDatastore = datastore();
 
-    /// The path to the script root directory
-    pub static ref SCRIPT_ROOT: String = match env::var("BRAID_SCRIPT_ROOT") {
-        Ok(s) => s,
-        Err(_) => Path::new(".").join("scripts").to_str().unwrap().to_string()
+    /// The server log, which access logs and other diagnostics are written to
+    pub static ref LOGGER: Logger = {
+        let destination = env::var("BRAID_LOG_DESTINATION").unwrap_or_else(|_| "stdout".to_string());
+        let level = env::var("BRAID_LOG_LEVEL").unwrap_or_else(|_| "info".to_string());
+
+        match Logger::from_config(&destination[..], &level[..]) {
+            Ok(logger) => logger,
+            Err(err) => panic!("Could not configure the server log: {}", err),
+        }
     };
 }
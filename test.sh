@@ -14,9 +14,14 @@ export RUST_BACKTRACE=1
 export SECRET=QkrDxgVJCT
 export DATABASE_URL="postgres://${PG_USER}@localhost:5432/braid_test"
 export BRAID_SCRIPT_ROOT=`pwd`/test_scripts
+export BRAID_AUDIT_LOG=`pwd`/target/audit.log
+export BRAID_ADMIN_SECRET=nGHlQ3TxNv
+export BRAID_SCRIPT_SETTINGS=`pwd`/test_scripts/settings.json
 
 dropdb --if-exists braid_test
 createdb --owner=$PG_USER braid_test
+rm -f $BRAID_AUDIT_LOG*
 cargo build
 ./target/debug/braid-db init
+./target/debug/braid-script test test_scripts/link.test.json --junit target/script-tests.xml
 ./support/with_server.sh cargo test $TEST_NAME
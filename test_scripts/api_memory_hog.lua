@@ -0,0 +1,18 @@
+-- What API functions allocate counts towards the memory limit, even though
+-- the limit is only checked once they return, and errors are swallowed here
+local value = {}
+
+for i = 1, 1000 do
+    value[i] = i
+end
+
+set_account_metadata(account_id, "api-memory-hog", value);
+local kept = {}
+
+for i = 1, 1000 do
+    pcall(function()
+        kept[i] = get_account_metadata(account_id, "api-memory-hog");
+    end)
+end
+
+return #kept
@@ -0,0 +1,8 @@
+-- Checks that nothing from `patch_globals.lua` leaked into this run.
+local globals = debug.getregistry()._LOADED._G
+assert(globals.leaked == nil)
+assert(debug.getinfo(globals.get_vertices).what == "C")
+assert(globals.string.format("%d", 1) == "1")
+assert(("%d"):format(1) == "1")
+assert(("a"):upper() == "A")
+return true
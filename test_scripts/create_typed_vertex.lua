@@ -0,0 +1,3 @@
+-- Creates a vertex of the type given in `arg`, for testing `braid-script run`.
+
+return create_vertex(arg.type)
@@ -0,0 +1,4 @@
+create_vertex("foo")
+
+while true do
+end
@@ -0,0 +1,7 @@
+if leaked_global ~= nil then
+    error("A global leaked from an earlier run")
+end
+
+leaked_global = true
+return leaked_global
+-- ok: true
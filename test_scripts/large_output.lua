@@ -0,0 +1,9 @@
+-- Returns, or raises if `arg` is "error", a string that's big enough for
+-- the response to be compressed
+local output = string.rep("x", 2048)
+
+if arg == "error" then
+    error(output)
+end
+
+return output
@@ -0,0 +1,8 @@
+-- Links two vertices, for testing `braid-script test`. See `link.test.json`.
+
+if arg.from == arg.to then
+    error("Cannot link a vertex to itself")
+end
+
+create_edge(arg.from, "link", arg.to, 1.0)
+return true
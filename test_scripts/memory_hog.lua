@@ -0,0 +1,7 @@
+local t = {}
+
+for i = 1, 10000000 do
+    t[i] = string.rep("x", 100) .. i
+end
+
+return #t
@@ -0,0 +1,8 @@
+-- Patches the real globals of the lua state, which `debug` gives access to,
+-- to check that the changes don't outlive the run.
+local globals = debug.getregistry()._LOADED._G
+globals.leaked = true
+globals.get_vertices = function() return "patched" end
+globals.string.format = function() return "patched" end
+debug.getmetatable("").__index = {upper = function() return "patched" end}
+return true
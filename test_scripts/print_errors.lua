@@ -0,0 +1,17 @@
+local bad = setmetatable({}, {__tostring = function() error("no string for you") end})
+local ok, err = pcall(print, "before", bad)
+
+if ok or not string.find(err, "no string for you") then
+    error("Expected the error from `__tostring` to be raised, got: " .. tostring(err))
+end
+
+local weird = setmetatable({}, {__tostring = function() return {} end})
+ok, err = pcall(log.warn, weird)
+
+if ok or not string.find(err, "`tostring` must return a string") then
+    error("Expected a non-string from `__tostring` to be an error, got: " .. tostring(err))
+end
+
+print("still", "working")
+return true
+-- ok: true
@@ -0,0 +1,2 @@
+local module = require("stored_module")
+return {io = io ~= nil, module_io = module.io}
@@ -0,0 +1,14 @@
+function test_set_reserved_global_metadata()
+    set_global_metadata("_braid_accounts", {});
+end
+
+function test_get_reserved_account_metadata()
+    get_account_metadata("00000000-0000-0000-0000-000000000000", "_braid_account_state");
+end
+
+local status, err = pcall(test_set_reserved_global_metadata);
+assert(status == false);
+assert(string.find(err, "Metadata key is reserved"));
+local status, err = pcall(test_get_reserved_account_metadata);
+assert(status == false);
+assert(string.find(err, "Metadata key is reserved"));
@@ -0,0 +1,18 @@
+assert(io == nil)
+assert(debug == nil)
+assert(package == nil)
+assert(loadfile == nil)
+assert(loadstring == nil)
+assert(os.execute == nil)
+assert(os.time() > 0)
+
+local status, err = pcall(require, "../queries")
+assert(status == false)
+assert(string.find(err, "Invalid module name"))
+
+local queries = require("queries")
+assert(type(queries) == "table")
+assert(vertex ~= nil)
+assert(type(log.info) == "function")
+return true
+-- ok: true
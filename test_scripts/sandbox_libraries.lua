@@ -0,0 +1,5 @@
+assert(io ~= nil)
+assert(io.open ~= nil)
+assert(os.execute == nil)
+return true
+-- ok: true
@@ -0,0 +1,8 @@
+-- Keeps a request in flight for a couple of seconds
+local start = os.clock()
+
+while os.clock() - start < 2 do
+end
+
+return true
+-- ok: true
@@ -0,0 +1,9 @@
+-- Keeps writing metadata for a few seconds, so that the request's
+-- transaction is still open when the server shuts down
+local start = os.clock()
+
+while os.clock() - start < 5 do
+    set_account_metadata(account_id, "slow_write", true)
+end
+
+return true
@@ -0,0 +1,10 @@
+local function count_forever()
+    local i = 0
+
+    while true do
+        i = i + 1
+    end
+end
+
+local status, err = pcall(count_forever)
+return status
@@ -0,0 +1,75 @@
+-- a -> b -> c -> d, with a shortcut a -> c of another type
+local a = create_vertex("foo");
+local b = create_vertex("foo");
+local c = create_vertex("foo");
+local d = create_vertex("bar");
+create_edge(a, "link", b, 1);
+create_edge(b, "link", c, 1);
+create_edge(c, "link", d, 1);
+create_edge(a, "shortcut", c, 1);
+
+local n = neighbors(a);
+assert(table.getn(n) == 2);
+
+n = neighbors(a, "link");
+assert(table.getn(n) == 1);
+assert(n[1].id == b);
+assert(n[1].type == "foo");
+
+n = neighbors(c, "link", "inbound");
+assert(table.getn(n) == 1);
+assert(n[1].id == b);
+
+n = neighbors(b, nil, "both");
+assert(table.getn(n) == 2);
+
+local vertices, edges = bfs(a, "link");
+assert(table.getn(vertices) == 3);
+assert(vertices[1].id == b);
+assert(vertices[2].id == c);
+assert(vertices[3].id == d);
+assert(table.getn(edges) == 3);
+assert(edges[3].key.outbound_id == c);
+assert(edges[3].key.inbound_id == d);
+
+vertices = bfs(a, "link", "outbound", 2);
+assert(table.getn(vertices) == 2);
+
+vertices = bfs(a, nil, nil, nil, 1);
+assert(table.getn(vertices) == 1);
+
+vertices = bfs(d, "", "inbound");
+assert(table.getn(vertices) == 3);
+
+local path = shortest_path(a, d);
+assert(table.getn(path) == 2);
+assert(path[1].key.type == "shortcut");
+assert(path[2].key.outbound_id == c);
+assert(path[2].key.inbound_id == d);
+
+path = shortest_path(a, d, "link");
+assert(table.getn(path) == 3);
+
+assert(shortest_path(d, a) == nil);
+assert(table.getn(shortest_path(d, a, nil, "inbound")) == 2);
+assert(shortest_path(a, d, "link", "outbound", 2) == nil);
+assert(table.getn(shortest_path(a, a)) == 0);
+
+-- Traversals go 10 edges deep unless told otherwise
+local chain = {create_vertex("foo")};
+
+for i = 2, 13 do
+    chain[i] = create_vertex("foo");
+    create_edge(chain[i - 1], "chain", chain[i], 1);
+end
+
+assert(table.getn(bfs(chain[1], "chain")) == 10);
+assert(table.getn(bfs(chain[1], "chain", "outbound", 12)) == 12);
+assert(shortest_path(chain[1], chain[13], "chain") == nil);
+assert(table.getn(shortest_path(chain[1], chain[13], "chain", "outbound", 12)) == 12);
+
+assert(not pcall(neighbors, a, "link", "sideways"));
+assert(not pcall(bfs, a, "link", "outbound", -1));
+
+return true;
+-- ok: true
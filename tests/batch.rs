@@ -21,7 +21,6 @@ use serde::Deserialize;
 use hyper::client::Client;
 use hyper::status::StatusCode;
 use serde_json::value::Value as JsonValue;
-pub use regex::Regex;
 use uuid::Uuid;
 pub use braid::*;
 pub use common::*;
@@ -29,10 +28,6 @@ use std::io::Read;
 use std::collections::HashMap;
 use serde_json::Number as JsonNumber;
 
-lazy_static! {
-    static ref ITEM_ERROR_MESSAGE_PATTERN: Regex = Regex::new(r"Item #0: (.+)").unwrap();
-}
-
 pub struct BatchTransaction {
     port: i32,
     account_id: Uuid,
@@ -76,12 +71,10 @@ impl BatchTransaction {
             _ => {
                 let o: BTreeMap<String, JsonValue> = serde_json::from_str(&payload[..]).unwrap();
 
-                match o.get("error") {
-                    Some(&JsonValue::String(ref error)) => {
-                        let cap = ITEM_ERROR_MESSAGE_PATTERN.captures(error).unwrap();
-                        let message = cap.get(1).unwrap().as_str();
-                        Err(Error::description_to_error(message))
-                    }
+                assert_eq!(o.get("index").and_then(|index| index.as_u64()), Some(0));
+
+                match o.get("message") {
+                    Some(&JsonValue::String(ref message)) => Err(Error::description_to_error(message)),
                     _ => panic!("Could not unpack error message"),
                 }
             }
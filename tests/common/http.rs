@@ -3,11 +3,13 @@ use hyper::client::{Client, RequestBuilder};
 use hyper::Url;
 use hyper::method::Method;
 use hyper::client::response::Response;
+use hyper::status::StatusCode;
 use uuid::Uuid;
 
 use serde_json;
 use serde_json::value::Value as JsonValue;
 
+use std::env;
 use std::str::FromStr;
 use std::io::Read;
 use std::str;
@@ -34,6 +36,67 @@ pub fn request<'a>(client: &'a Client, port: i32, account_id: Uuid, secret: Stri
     client.request(method, url).header(auth)
 }
 
+/// Builds a request to an admin route, authenticated with the admin secret.
+pub fn admin_request<'a>(client: &'a Client, port: i32, method_str: &str, path: String) -> RequestBuilder<'a> {
+    let method = Method::from_str(method_str).unwrap();
+    let url = format!("http://localhost:{}{}", port, path);
+
+    let auth = Authorization(Basic {
+        username: "admin".to_string(),
+        password: Some(env::var("BRAID_ADMIN_SECRET").unwrap()),
+    });
+
+    client.request(method, &url[..]).header(auth)
+}
+
+/// Sends a request, and returns its status and body.
+pub fn send(req: RequestBuilder) -> (StatusCode, String) {
+    let mut res = req.send().unwrap();
+    let mut payload = String::new();
+    res.read_to_string(&mut payload).unwrap();
+    (res.status, payload)
+}
+
+/// Sends a request, and returns its status and JSON body.
+pub fn send_json(req: RequestBuilder) -> (StatusCode, JsonValue) {
+    let (status, payload) = send(req);
+    let body = serde_json::from_str(&payload[..]).unwrap_or_else(|_| panic!("Invalid JSON body: {}", payload));
+    (status, body)
+}
+
+/// Sends requests to a server on behalf of an account.
+pub struct AccountClient {
+    client: Client,
+    port: i32,
+    pub account_id: Uuid,
+    pub secret: String,
+}
+
+impl AccountClient {
+    pub fn new(port: i32, account_id: Uuid, secret: String) -> AccountClient {
+        AccountClient {
+            client: Client::new(),
+            port: port,
+            account_id: account_id,
+            secret: secret,
+        }
+    }
+
+    pub fn request(&self, method_str: &str, path: &str, query_params: Vec<(&str, String)>) -> RequestBuilder {
+        request(&self.client, self.port, self.account_id, self.secret.clone(), method_str, path.to_string(), query_params)
+    }
+
+    /// Sends a request with a body, and returns its status and body.
+    pub fn send(&self, method_str: &str, path: &str, query_params: Vec<(&str, String)>, body: &str) -> (StatusCode, String) {
+        send(self.request(method_str, path, query_params).body(body))
+    }
+
+    /// Sends a request with a body, and returns its status and JSON body.
+    pub fn send_json(&self, method_str: &str, path: &str, query_params: Vec<(&str, String)>, body: &str) -> (StatusCode, JsonValue) {
+        send_json(self.request(method_str, path, query_params).body(body))
+    }
+}
+
 pub fn response_to_error_message(res: &mut Response) -> String {
     let mut payload = String::new();
     res.read_to_string(&mut payload).unwrap();
@@ -189,3 +189,102 @@ pub fn datastore() -> HttpDatastore<RestTransaction> {
 }
 
 test_transaction_impl!(datastore());
+
+#[test]
+fn should_accept_queries_in_request_bodies() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+
+    let (_, id) = client.send_json("POST", "/vertex", vec![("type", "foo".to_string())], "");
+    let id = id.as_str().unwrap().to_string();
+
+    let body = format!("{{\"vertices\": [\"{}\"]}}", id);
+    let (status, vertices) = client.send_json("POST", "/vertex/query", vec![], &body[..]);
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(vertices.pointer("/0/id").and_then(|v| v.as_str()), Some(&id[..]));
+
+    let (status, body) = client.send_json("POST", "/edge/query", vec![], "{\"foo\": 1}");
+    assert_eq!(status, StatusCode::BadRequest);
+    assert_eq!(body.get("code").and_then(|v| v.as_str()), Some("invalid_query"));
+    assert_eq!(body.get("message").and_then(|v| v.as_str()), Some("Invalid type for `body`: expected edge query"));
+}
+
+#[test]
+fn should_serve_single_vertices_and_edges_as_resources() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+    let send = |method: &str, path: &str, params: Vec<(&str, String)>| client.send_json(method, path, params, "");
+
+    let (_, outbound_id) = send("POST", "/vertex", vec![("type", "foo".to_string())]);
+    let outbound_id = outbound_id.as_str().unwrap().to_string();
+    let (_, inbound_id) = send("POST", "/vertex", vec![("type", "foo".to_string())]);
+    let inbound_id = inbound_id.as_str().unwrap().to_string();
+    let edge_path = format!("/edge/{}/bar/{}", outbound_id, inbound_id);
+    send("PUT", &edge_path[..], vec![("weight", "0.5".to_string())]);
+
+    let (status, vertex) = send("GET", &format!("/vertex/{}", outbound_id)[..], vec![]);
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(vertex.get("id").and_then(|v| v.as_str()), Some(&outbound_id[..]));
+
+    let (status, edge) = send("GET", &edge_path[..], vec![]);
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(edge.pointer("/key/inbound_id").and_then(|v| v.as_str()), Some(&inbound_id[..]));
+
+    let (status, edges) = send("GET", &format!("/vertex/{}/edges/outbound", outbound_id)[..], vec![("type", "bar".to_string())]);
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(edges.as_array().map(|a| a.len()), Some(1));
+
+    let (status, neighbors) = send("GET", &format!("/vertex/{}/neighbors/inbound", inbound_id)[..], vec![]);
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(neighbors.pointer("/0/id").and_then(|v| v.as_str()), Some(&outbound_id[..]));
+
+    let (status, _) = send("DELETE", &format!("/vertex/{}", outbound_id)[..], vec![]);
+    assert_eq!(status, StatusCode::Ok);
+
+    let (status, body) = send("GET", &format!("/vertex/{}", outbound_id)[..], vec![]);
+    assert_eq!(status, StatusCode::NotFound);
+    assert_eq!(body.get("code").and_then(|v| v.as_str()), Some("vertex_not_found"));
+
+    let (status, body) = send("GET", &edge_path[..], vec![]);
+    assert_eq!(status, StatusCode::NotFound);
+    assert_eq!(body.get("code").and_then(|v| v.as_str()), Some("edge_not_found"));
+
+    let (status, _) = send("GET", &format!("/vertex/{}/neighbors/outbound", outbound_id)[..], vec![]);
+    assert_eq!(status, StatusCode::NotFound);
+}
+
+#[test]
+fn should_report_deletions_without_deleting_in_dry_runs() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+
+    let (_, outbound_id) = client.send_json("POST", "/vertex", vec![("type", "foo".to_string())], "");
+    let outbound_id = outbound_id.as_str().unwrap().to_string();
+    let (_, inbound_id) = client.send_json("POST", "/vertex", vec![("type", "foo".to_string())], "");
+    let inbound_id = inbound_id.as_str().unwrap().to_string();
+    let edge_path = format!("/edge/{}/bar/{}", outbound_id, inbound_id);
+    client.send("PUT", &edge_path[..], vec![("weight", "0.5".to_string())], "");
+
+    let q = format!("{{\"vertex\": \"{}\"}}", outbound_id);
+    let (status, report) = client.send_json("DELETE", "/vertex", vec![("q", q.clone()), ("dry_run", "true".to_string())], "");
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(report.get("dry_run").and_then(|v| v.as_bool()), Some(true));
+    assert_eq!(report.get("vertices").and_then(|v| v.as_u64()), Some(1));
+    assert_eq!(report.get("edges").and_then(|v| v.as_u64()), Some(1));
+    assert_eq!(report.pointer("/vertex_ids/0").and_then(|v| v.as_str()), Some(&outbound_id[..]));
+    assert_eq!(report.pointer("/edge_keys/0/inbound_id").and_then(|v| v.as_str()), Some(&inbound_id[..]));
+    assert_eq!(report.get("metadata"), Some(&JsonValue::Null));
+
+    // Batches with a dry run are rolled back as a whole, including the
+    // items before it
+    let batch = format!("[{{\"action\": \"create_vertex\", \"type\": \"foo\"}}, {{\"action\": \"delete_vertices\", \"query\": {}, \"dry_run\": true}}]", q);
+    let (status, results) = client.send_json("POST", "/transaction", vec![], &batch[..]);
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(results.pointer("/1/vertices").and_then(|v| v.as_u64()), Some(1));
+    assert_eq!(results.pointer("/1/edges").and_then(|v| v.as_u64()), Some(1));
+    let created_id = results.pointer("/0").and_then(|v| v.as_str()).unwrap();
+    assert_eq!(client.send("GET", &format!("/vertex/{}", created_id)[..], vec![], "").0, StatusCode::NotFound);
+
+    assert_eq!(client.send("GET", &format!("/vertex/{}", outbound_id)[..], vec![], "").0, StatusCode::Ok);
+    assert_eq!(client.send("GET", &edge_path[..], vec![], "").0, StatusCode::Ok);
+}
@@ -13,6 +13,7 @@ mod common;
 
 use std::io::prelude::*;
 use std::fs::File;
+use std::process::Command;
 use uuid::Uuid;
 use hyper::client::Client;
 use hyper::status::StatusCode;
@@ -80,7 +81,10 @@ test_script!(get_edges_bad_limit);
 test_script!(get_edges_bad_low);
 test_script!(get_edges);
 test_script!(global_metadata);
+test_script!(isolated_globals);
+test_script!(print_errors);
 test_script!(regression_float_serialization);
+test_script!(reserved_metadata_keys);
 test_script!(return_array);
 test_script!(return_boolean);
 test_script!(return_int);
@@ -88,5 +92,55 @@ test_script!(return_nil);
 test_script!(return_number);
 test_script!(return_obj);
 test_script!(return_string);
+test_script!(sandbox);
+test_script!(sandbox_libraries);
 test_script!(set_and_get_edge);
+test_script!(traversal);
 test_script!(vertex_metadata);
+
+#[test]
+fn should_run_scripts_from_the_command_line() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = Client::new();
+
+    let run = |extra_args: &[&str]| {
+        let output = Command::new("./target/debug/braid-script")
+            .arg("run")
+            .arg("create_typed_vertex.lua")
+            .arg("--account")
+            .arg(account_id.hyphenated().to_string())
+            .arg("--arg")
+            .arg("{\"type\": \"foo\"}")
+            .args(extra_args)
+            .output()
+            .unwrap();
+
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let id: String = serde_json::from_slice(&output.stdout).unwrap();
+        id
+    };
+
+    let vertex_exists = |id: String| {
+        let req = request(&client, 8000, account_id, secret.clone(), "GET", format!("/vertex/{}", id), vec![]);
+        req.send().unwrap().status == StatusCode::Ok
+    };
+
+    assert!(!vertex_exists(run(&["--dry-run"])));
+    assert!(vertex_exists(run(&[])));
+}
+
+#[test]
+fn should_not_run_scripts_outside_of_the_script_root_from_the_command_line() {
+    let (account_id, _) = create_account().unwrap();
+
+    let output = Command::new("./target/debug/braid-script")
+        .arg("run")
+        .arg("../test_scripts/return_int.lua")
+        .arg("--account")
+        .arg(account_id.hyphenated().to_string())
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid script name"));
+}
@@ -0,0 +1,812 @@
+extern crate braid;
+#[macro_use]
+extern crate lazy_static;
+extern crate serde;
+extern crate serde_json;
+extern crate chrono;
+extern crate rand;
+extern crate regex;
+#[macro_use]
+extern crate hyper;
+extern crate uuid;
+extern crate serde_cbor;
+extern crate rmp_serde;
+extern crate openssl;
+extern crate flate2;
+
+mod common;
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::Duration;
+use hyper::client::Client;
+use hyper::status::StatusCode;
+use hyper::header::{Accept, AcceptEncoding, ContentEncoding, ContentType, Encoding, qitem};
+use hyper::mime::Mime;
+use openssl::ssl::{SslConnectorBuilder, SslMethod};
+use flate2::read::{DeflateDecoder, GzDecoder};
+use serde::{Deserialize, Serialize};
+pub use common::*;
+
+header! { (IdempotencyKey, "Idempotency-Key") => [String] }
+header! { (RequestId, "X-Request-Id") => [String] }
+header! { (RequestOrigin, "Origin") => [String] }
+header! { (RequestMethod, "Access-Control-Request-Method") => [String] }
+
+/// Another server, for tests that need one that is configured differently
+/// from the one on port 8000. It's killed when dropped, so that it doesn't
+/// outlive a failing test.
+struct TestServer {
+    process: Child,
+}
+
+impl TestServer {
+    /// Starts a server on the given port, with some extra environment
+    /// variables. Returns once it accepts connections.
+    fn start(port: u16, vars: &[(&str, &str)]) -> TestServer {
+        let mut command = Command::new("./target/debug/braid-server");
+        command.env("PORT", port.to_string()).env_remove("BRAID_ADMIN_PORT").stdout(Stdio::null());
+
+        for &(key, value) in vars {
+            command.env(key, value);
+        }
+
+        let server = TestServer { process: command.spawn().unwrap() };
+
+        let ready = (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(100));
+            TcpStream::connect(("127.0.0.1", port)).is_ok()
+        });
+
+        assert!(ready, "the server didn't start");
+        server
+    }
+
+    fn id(&self) -> u32 {
+        self.process.id()
+    }
+
+    fn wait(&mut self) -> ExitStatus {
+        self.process.wait().unwrap()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+fn get_unauthenticated(path: &str) -> (StatusCode, String) {
+    let client = Client::new();
+    send(client.get(&format!("http://localhost:8000{}", path)[..]))
+}
+
+/// Reads a response, decompressing its body according to its
+/// `Content-Encoding` header. Returns the status, the encoding and the body.
+fn read_decoded(mut res: hyper::client::Response) -> (StatusCode, Option<Encoding>, String) {
+    let encoding = res.headers.get::<ContentEncoding>().map(|&ContentEncoding(ref encodings)| encodings[0].clone());
+    let mut raw = Vec::new();
+    res.read_to_end(&mut raw).unwrap();
+    let mut body = String::new();
+
+    match encoding {
+        Some(Encoding::Gzip) => GzDecoder::new(&raw[..]).unwrap().read_to_string(&mut body).unwrap(),
+        Some(Encoding::Deflate) => DeflateDecoder::new(&raw[..]).read_to_string(&mut body).unwrap(),
+        _ => (&raw[..]).read_to_string(&mut body).unwrap(),
+    };
+
+    (res.status, encoding, body)
+}
+
+#[test]
+fn should_compress_large_responses_with_gzip_or_deflate() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+
+    for encoding in vec![Encoding::Gzip, Encoding::Deflate] {
+        let res = client.request("POST", "/script/large_output.lua", vec![])
+            .header(AcceptEncoding(vec![qitem(encoding.clone())]))
+            .body("null")
+            .send()
+            .unwrap();
+        assert!(res.headers.get_raw("Vary").map_or(false, |vary| vary.iter().any(|v| v == b"Accept-Encoding")));
+        let (status, used, body) = read_decoded(res);
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(used, Some(encoding));
+        let body: serde_json::Value = serde_json::from_str(&body[..]).unwrap();
+        assert_eq!(body.as_str().map(|s| s.len()), Some(2048));
+    }
+}
+
+#[test]
+fn should_not_compress_small_responses() {
+    let res = Client::new().get("http://localhost:8000/healthz")
+        .header(AcceptEncoding(vec![qitem(Encoding::Gzip)]))
+        .send()
+        .unwrap();
+    let (status, used, body) = read_decoded(res);
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(used, None);
+    let body: serde_json::Value = serde_json::from_str(&body[..]).unwrap();
+    assert_eq!(body.get("status").and_then(|v| v.as_str()), Some("ok"));
+}
+
+#[test]
+fn should_compress_large_error_responses() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+
+    let res = client.request("POST", "/script/large_output.lua", vec![])
+        .header(AcceptEncoding(vec![qitem(Encoding::Gzip)]))
+        .body("\"error\"")
+        .send()
+        .unwrap();
+    let (status, used, body) = read_decoded(res);
+    assert_eq!(status, StatusCode::InternalServerError);
+    assert_eq!(used, Some(Encoding::Gzip));
+    let body: serde_json::Value = serde_json::from_str(&body[..]).unwrap();
+    assert_eq!(body.get("code").and_then(|v| v.as_str()), Some("script_runtime_error"));
+    assert!(body.get("message").and_then(|v| v.as_str()).map_or(false, |message| message.contains(&"x".repeat(2048)[..])));
+}
+
+#[test]
+fn should_serve_metrics_without_authentication() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+    assert_eq!(client.send("GET", "/vertex", vec![], "").0, StatusCode::BadRequest);
+
+    let (status, payload) = get_unauthenticated("/metrics");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(payload.contains("braid_http_requests_total{route=\"get_vertices\",status=\"400\"}"));
+}
+
+#[test]
+fn should_not_label_script_metrics_with_unknown_names() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+    let name = format!("missing-{}.lua", account_id);
+    assert_eq!(client.send("POST", &format!("/script/{}", name)[..], vec![], "").0, StatusCode::NotFound);
+
+    let (status, payload) = get_unauthenticated("/metrics");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(payload.contains("braid_script_executions_total{script=\"(unknown)\",result=\"error\"}"));
+    assert!(!payload.contains(&name[..]));
+}
+
+#[test]
+fn should_serve_health_checks_without_authentication() {
+    let (status, payload) = get_unauthenticated("/healthz");
+    assert_eq!(status, StatusCode::Ok);
+    let body: serde_json::Value = serde_json::from_str(&payload[..]).unwrap();
+    assert_eq!(body.get("status").and_then(|v| v.as_str()), Some("ok"));
+
+    let (status, payload) = get_unauthenticated("/readyz");
+    assert_eq!(status, StatusCode::Ok);
+    let body: serde_json::Value = serde_json::from_str(&payload[..]).unwrap();
+    assert_eq!(body.get("ready").and_then(|v| v.as_bool()), Some(true));
+    assert_eq!(body.pointer("/checks/datastore/ok").and_then(|v| v.as_bool()), Some(true));
+    assert_eq!(body.pointer("/checks/script_root/ok").and_then(|v| v.as_bool()), Some(true));
+}
+
+#[test]
+fn should_reject_oversized_bodies() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+    let body = format!("\"{}\"", "a".repeat(2 * 1024 * 1024));
+    assert_eq!(client.send("POST", "/script/return_nil.lua", vec![], &body[..]).0, StatusCode::PayloadTooLarge);
+}
+
+#[test]
+fn should_return_machine_readable_errors() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+    let (status, body) = client.send_json("GET", "/vertex", vec![], "");
+    assert_eq!(status, StatusCode::BadRequest);
+    assert_eq!(body.get("code").and_then(|v| v.as_str()), Some("missing_parameter"));
+    assert_eq!(body.get("field").and_then(|v| v.as_str()), Some("q"));
+    assert_eq!(body.get("message").and_then(|v| v.as_str()), Some("Missing required query parameter `q`"));
+    assert!(body.get("request_id").and_then(|v| v.as_str()).is_some());
+
+    // Batch errors keep the failing item's status
+    let batch = "[{\"action\": \"create_vertex\", \"type\": \"foo\"}, {\"action\": \"run_script\", \"name\": \"missing.lua\", \"payload\": null}]";
+    let (status, body) = client.send_json("POST", "/transaction", vec![], batch);
+    assert_eq!(status, StatusCode::NotFound);
+    assert_eq!(body.get("code").and_then(|v| v.as_str()), Some("script_not_found"));
+    assert_eq!(body.get("index").and_then(|v| v.as_u64()), Some(1));
+}
+
+#[test]
+fn should_replay_requests_with_the_same_idempotency_key() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+    let key = uuid::Uuid::new_v4().hyphenated().to_string();
+
+    let create_vertex = |t: &str| {
+        let req = client.request("POST", "/vertex", vec![("type", t.to_string())]);
+        send(req.header(IdempotencyKey(key.clone())))
+    };
+
+    let (first_status, first_payload) = create_vertex("foo");
+    assert_eq!(first_status, StatusCode::Ok);
+    let (second_status, second_payload) = create_vertex("foo");
+    assert_eq!(second_status, StatusCode::Ok);
+    assert_eq!(first_payload, second_payload);
+
+    let (third_status, _) = create_vertex("bar");
+    assert_eq!(third_status, StatusCode::UnprocessableEntity);
+}
+
+#[test]
+fn should_reuse_compiled_scripts_without_leaking_globals() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+
+    for _ in 0..3 {
+        let (status, payload) = client.send("POST", "/script/isolated_globals.lua", vec![], "");
+        assert_eq!(status, StatusCode::Ok, "{}", payload);
+        assert_eq!(payload, "true");
+    }
+
+    let (status, payload) = get_unauthenticated("/metrics");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(payload.contains("braid_script_cache_lookups_total{result=\"hit\"}"));
+}
+
+#[test]
+fn should_restore_patched_globals_between_runs() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+
+    // Idle states are reused last in, first out, so the check almost always
+    // runs on the state that was just patched.
+    for _ in 0..5 {
+        assert_eq!(client.send("POST", "/script/patch_globals.lua", vec![], ""), (StatusCode::Ok, "true".to_string()));
+        assert_eq!(client.send("POST", "/script/check_globals.lua", vec![], ""), (StatusCode::Ok, "true".to_string()));
+    }
+}
+
+#[test]
+fn should_abort_scripts_that_exceed_their_limits() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+
+    for name in vec!["infinite_loop.lua", "swallowed_instruction_limit.lua"] {
+        let (status, body) = client.send_json("POST", &format!("/script/{}", name)[..], vec![], "");
+        assert_eq!(status, StatusCode::ServiceUnavailable);
+        assert_eq!(body.get("code").and_then(|v| v.as_str()), Some("script_timeout"));
+    }
+}
+
+#[test]
+fn should_report_scripts_that_exceed_their_memory_limit() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+
+    let (status, body) = client.send_json("POST", "/script/memory_hog.lua", vec![], "");
+    assert_eq!(status, StatusCode::InternalServerError);
+    assert_eq!(body.get("code").and_then(|v| v.as_str()), Some("script_memory_error"));
+    assert!(body.get("message").and_then(|v| v.as_str()).unwrap().contains("memory limit of 1048576 bytes"));
+
+    let (status, body) = client.send_json("POST", "/script/api_memory_hog.lua", vec![], "");
+    assert_eq!(status, StatusCode::InternalServerError);
+    assert_eq!(body.get("code").and_then(|v| v.as_str()), Some("script_memory_error"));
+}
+
+#[test]
+fn should_store_and_run_uploaded_scripts() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+
+    let (status, payload) = client.send("PUT", "/scripts/greeting.lua", vec![], "return \"hello \" .. arg.name");
+    assert_eq!(status, StatusCode::Ok, "{}", payload);
+    let (status, payload) = client.send("POST", "/script/greeting.lua", vec![], "{\"name\": \"world\"}");
+    assert_eq!(status, StatusCode::Ok, "{}", payload);
+    assert_eq!(payload, "\"hello world\"");
+
+    let (status, script) = client.send_json("GET", "/scripts/greeting.lua", vec![], "");
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(script.get("scope").and_then(|v| v.as_str()), Some("account"));
+    assert_eq!(script.get("source").and_then(|v| v.as_str()), Some("return \"hello \" .. arg.name"));
+
+    let (status, scripts) = client.send_json("GET", "/scripts", vec![], "");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(scripts.as_array().unwrap().iter().any(|script| script.get("name").and_then(|v| v.as_str()) == Some("greeting.lua")));
+
+    let (status, body) = client.send_json("PUT", "/scripts/broken.lua", vec![], "return (");
+    assert_eq!(status, StatusCode::BadRequest);
+    assert_eq!(body.get("code").and_then(|v| v.as_str()), Some("script_syntax_error"));
+
+    let (status, body) = client.send_json("PUT", "/scripts/precompiled.lua", vec![], "\x1bLuaQ\x00\x01\x04\x08\x04\x08\x00");
+    assert_eq!(status, StatusCode::BadRequest);
+    assert_eq!(body.get("code").and_then(|v| v.as_str()), Some("script_precompiled"));
+
+    assert_eq!(client.send("DELETE", "/scripts/greeting.lua", vec![], "").0, StatusCode::Ok);
+    assert_eq!(client.send("GET", "/scripts/greeting.lua", vec![], "").0, StatusCode::NotFound);
+    assert_eq!(client.send("DELETE", "/scripts/greeting.lua", vec![], "").0, StatusCode::NotFound);
+}
+
+#[test]
+fn should_not_apply_file_settings_to_stored_scripts() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+
+    // `sandbox_libraries.lua` is given `io` in `test_scripts/settings.json`
+    assert_eq!(client.send("PUT", "/scripts/sandbox_libraries.lua", vec![], "return io == nil").0, StatusCode::Ok);
+    assert_eq!(client.send("POST", "/script/sandbox_libraries.lua", vec![], ""), (StatusCode::Ok, "true".to_string()));
+
+    // Stored modules run in the default sandbox, even when they're required
+    // by a script with extra libraries
+    assert_eq!(client.send("PUT", "/scripts/stored_module.lua", vec![], "return {io = io ~= nil}").0, StatusCode::Ok);
+    let (status, body) = client.send_json("POST", "/script/require_stored.lua", vec![], "");
+    assert_eq!(status, StatusCode::Ok, "{}", body);
+    assert_eq!(body.get("io").and_then(|v| v.as_bool()), Some(true));
+    assert_eq!(body.get("module_io").and_then(|v| v.as_bool()), Some(false));
+}
+
+#[test]
+fn should_pin_and_promote_script_versions() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+    let send = |method: &str, path: &str, body: &str| client.send(method, path, vec![], body);
+
+    assert_eq!(send("PUT", "/scripts/versioned.lua", "return 1").0, StatusCode::Ok);
+    assert_eq!(send("PUT", "/scripts/versioned.lua?promote=true", "return 2").0, StatusCode::Ok);
+    assert_eq!(send("PUT", "/scripts/versioned.lua", "return 3").0, StatusCode::Ok);
+
+    assert_eq!(send("POST", "/script/versioned.lua", ""), (StatusCode::Ok, "2".to_string()));
+    assert_eq!(send("POST", "/script/versioned.lua@1", ""), (StatusCode::Ok, "1".to_string()));
+    assert_eq!(send("POST", "/script/versioned.lua@3", ""), (StatusCode::Ok, "3".to_string()));
+    assert_eq!(send("POST", "/script/versioned.lua@4", "").0, StatusCode::NotFound);
+    assert_eq!(send("POST", "/script/versioned.lua@latest", "").0, StatusCode::BadRequest);
+
+    let batch = "[{\"action\": \"run_script\", \"name\": \"versioned.lua\", \"version\": 1}]";
+    assert_eq!(send("POST", "/transaction", batch), (StatusCode::Ok, "[1]".to_string()));
+
+    assert_eq!(send("POST", "/scripts/versioned.lua/promote", "{\"version\": 3}").0, StatusCode::Ok);
+    assert_eq!(send("POST", "/script/versioned.lua", ""), (StatusCode::Ok, "3".to_string()));
+    assert_eq!(send("POST", "/scripts/versioned.lua/rollback", "").0, StatusCode::Ok);
+    assert_eq!(send("POST", "/script/versioned.lua", ""), (StatusCode::Ok, "2".to_string()));
+
+    let (status, history) = client.send_json("GET", "/scripts/versioned.lua/versions", vec![], "");
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(history.get("current_version").and_then(|v| v.as_u64()), Some(2));
+    assert_eq!(history.get("versions").and_then(|v| v.as_array()).map(|v| v.len()), Some(3));
+    let promotions: Vec<u64> = history.get("promotions").and_then(|v| v.as_array()).unwrap().iter().filter_map(|p| p.get("version").and_then(|v| v.as_u64())).collect();
+    assert_eq!(promotions, vec![1, 2, 3, 2]);
+
+    let (status, script) = client.send_json("GET", "/scripts/versioned.lua@1", vec![], "");
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(script.get("source").and_then(|v| v.as_str()), Some("return 1"));
+
+    // Version numbers aren't reused after the script is deleted
+    assert_eq!(send("DELETE", "/scripts/versioned.lua", "").0, StatusCode::Ok);
+    assert_eq!(send("PUT", "/scripts/versioned.lua", "return 4").0, StatusCode::Ok);
+    assert_eq!(send("POST", "/script/versioned.lua@1", "").0, StatusCode::NotFound);
+    assert_eq!(send("POST", "/script/versioned.lua@4", ""), (StatusCode::Ok, "4".to_string()));
+    assert_eq!(send("POST", "/script/versioned.lua", ""), (StatusCode::Ok, "4".to_string()));
+}
+
+#[test]
+fn should_return_script_logs_when_debugging() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+
+    let source = "print(\"hello\", 1, nil)\nlog.debug(\"details\")\nlog.warn(\"careful\")\nreturn arg";
+    assert_eq!(client.send("PUT", "/scripts/chatty.lua", vec![], source).0, StatusCode::Ok);
+    assert_eq!(client.send("POST", "/script/chatty.lua", vec![], "42"), (StatusCode::Ok, "42".to_string()));
+
+    let expected: serde_json::Value = serde_json::from_str(r#"{
+        "result": 42,
+        "logs": [
+            {"level": "info", "message": "hello\t1\tnil"},
+            {"level": "debug", "message": "details"},
+            {"level": "warn", "message": "careful"}
+        ]
+    }"#).unwrap();
+
+    let (status, body) = client.send_json("POST", "/script/chatty.lua", vec![("debug", "true".to_string())], "42");
+    assert_eq!(status, StatusCode::Ok, "{}", body);
+    assert_eq!(body, expected);
+
+    let batch = "[{\"action\": \"run_script\", \"name\": \"chatty.lua\", \"payload\": 42, \"debug\": true}]";
+    let (status, body) = client.send_json("POST", "/transaction", vec![], batch);
+    assert_eq!(status, StatusCode::Ok, "{}", body);
+    assert_eq!(body, serde_json::Value::Array(vec![expected]));
+
+    let source = "log.info(\"about to fail\")\nerror(\"oops\")";
+    assert_eq!(client.send("PUT", "/scripts/failing.lua", vec![], source).0, StatusCode::Ok);
+
+    let (status, body) = client.send_json("POST", "/script/failing.lua", vec![("debug", "true".to_string())], "null");
+    assert_eq!(status, StatusCode::InternalServerError, "{}", body);
+    assert_eq!(body.get("code").and_then(|v| v.as_str()), Some("script_runtime_error"));
+    let logs: serde_json::Value = serde_json::from_str(r#"[{"level": "info", "message": "about to fail"}]"#).unwrap();
+    assert_eq!(body.get("logs"), Some(&logs));
+
+    let (status, body) = client.send_json("POST", "/script/failing.lua", vec![], "null");
+    assert_eq!(status, StatusCode::InternalServerError, "{}", body);
+    assert!(body.get("logs").is_none());
+}
+
+#[test]
+fn should_negotiate_cbor_bodies() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+    let cbor: Mime = "application/cbor".parse().unwrap();
+
+    let req = client.request("POST", "/vertex", vec![("type", "foo".to_string())]);
+    let mut res = req.header(Accept(vec![qitem(cbor.clone())])).send().unwrap();
+    assert_eq!(res.status, StatusCode::Ok);
+    assert_eq!(res.headers.get::<ContentType>(), Some(&ContentType(cbor.clone())));
+    let mut payload: Vec<u8> = Vec::new();
+    res.read_to_end(&mut payload).unwrap();
+    let id: String = serde_cbor::from_slice(&payload[..]).unwrap();
+
+    let query: serde_json::Value = serde_json::from_str(&format!("{{\"vertex\": \"{}\"}}", id)[..]).unwrap();
+    let body = serde_cbor::to_vec(&query).unwrap();
+    let req = client.request("POST", "/vertex/query", vec![]);
+    let (status, vertices) = send_json(req.header(ContentType(cbor)).body(&body[..]));
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(vertices.pointer("/0/id").and_then(|v| v.as_str()), Some(&id[..]));
+}
+
+#[test]
+fn should_negotiate_msgpack_bodies() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret);
+    let msgpack: Mime = "application/msgpack".parse().unwrap();
+
+    let read_msgpack = |mut res: hyper::client::Response| {
+        assert_eq!(res.status, StatusCode::Ok);
+        assert_eq!(res.headers.get::<ContentType>(), Some(&ContentType(msgpack.clone())));
+        let mut payload: Vec<u8> = Vec::new();
+        res.read_to_end(&mut payload).unwrap();
+        let mut de = rmp_serde::Deserializer::new(&payload[..]);
+        serde_json::Value::deserialize(&mut de).unwrap()
+    };
+
+    let req = client.request("POST", "/vertex", vec![("type", "foo".to_string())]);
+    let id = read_msgpack(req.header(Accept(vec![qitem(msgpack.clone())])).send().unwrap());
+    let id = id.as_str().unwrap().to_string();
+
+    let query: serde_json::Value = serde_json::from_str(&format!("{{\"vertex\": \"{}\"}}", id)[..]).unwrap();
+    let mut body: Vec<u8> = Vec::new();
+    query.serialize(&mut rmp_serde::Serializer::new(&mut body)).unwrap();
+    let req = client.request("POST", "/vertex/query", vec![])
+        .header(ContentType(msgpack.clone()))
+        .header(Accept(vec![qitem(msgpack.clone())]))
+        .body(&body[..]);
+    let vertices = read_msgpack(req.send().unwrap());
+    assert_eq!(vertices.pointer("/0/id").and_then(|v| v.as_str()), Some(&id[..]));
+}
+
+#[test]
+fn should_audit_destructive_actions() {
+    let (account_id, secret) = create_account().unwrap();
+    let client = AccountClient::new(8000, account_id, secret.clone());
+    let q = format!("{{\"vertex\":\"{}\"}}", uuid::Uuid::new_v4().hyphenated());
+    assert_eq!(client.send("DELETE", "/vertex", vec![("q", q.clone())], "").0, StatusCode::Ok);
+
+    // Successful authentications are only recorded once per interval
+    assert_eq!(client.send("GET", "/vertex", vec![("q", q.clone())], "").0, StatusCode::Ok);
+
+    let wrong_client = AccountClient::new(8000, account_id, format!("{}x", secret));
+    assert_eq!(wrong_client.send("GET", "/vertex", vec![("q", q.clone())], "").0, StatusCode::Unauthorized);
+
+    let (status, _) = get_unauthenticated("/admin/audit");
+    assert_eq!(status, StatusCode::Unauthorized);
+
+    let admin_client = Client::new();
+    let path = format!("/admin/audit?account_id={}", account_id.hyphenated());
+    let (status, entries) = send_json(admin_request(&admin_client, 8000, "GET", path));
+    assert_eq!(status, StatusCode::Ok);
+    let entries = entries.as_array().unwrap();
+
+    let actions: Vec<&str> = entries.iter().filter_map(|entry| entry.get("action").and_then(|v| v.as_str())).collect();
+    assert_eq!(actions, vec!["create_account", "authenticate", "delete_vertices", "authenticate"]);
+    assert_eq!(entries[1].get("success").and_then(|v| v.as_bool()), Some(true));
+    assert_eq!(entries[2].get("target").and_then(|v| v.as_str()), Some(&q[..]));
+    assert!(entries[2].get("request_id").and_then(|v| v.as_str()).is_some());
+    assert!(entries[2].get("source_ip").and_then(|v| v.as_str()).is_some());
+    assert_eq!(entries[3].get("success").and_then(|v| v.as_bool()), Some(false));
+}
+
+#[test]
+fn should_keep_every_rotated_audit_log() {
+    let log_path = "target/audit-rotation-test.log";
+
+    for entry in fs::read_dir("target").unwrap() {
+        let path = entry.unwrap().path();
+
+        if path.to_str().unwrap().starts_with(log_path) {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    // Every entry fills the log, so each one is rotated right away
+    let _server = TestServer::start(8009, &[("BRAID_AUDIT_LOG", log_path), ("BRAID_AUDIT_LOG_MAX_SIZE", "1")]);
+    let account_id = uuid::Uuid::new_v4();
+    let client = AccountClient::new(8009, account_id, "wrong".to_string());
+
+    for _ in 0..3 {
+        assert_eq!(client.send("GET", "/vertex", vec![], "").0, StatusCode::Unauthorized);
+    }
+
+    let rotated = fs::read_dir("target").unwrap().filter(|entry| {
+        let path = entry.as_ref().unwrap().path();
+        path.to_str().unwrap().starts_with(&format!("{}.", log_path)[..])
+    }).count();
+    assert_eq!(rotated, 3);
+
+    let admin_client = Client::new();
+    let path = format!("/admin/audit?account_id={}", account_id.hyphenated());
+    let (status, entries) = send_json(admin_request(&admin_client, 8009, "GET", path));
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(entries.as_array().map(|entries| entries.len()), Some(3));
+}
+
+#[test]
+fn should_list_accounts_that_were_created_concurrently() {
+    fn admin(method: &str, path: &str) -> serde_json::Value {
+        let client = Client::new();
+        let (status, body) = send_json(admin_request(&client, 8000, method, path.to_string()));
+        assert_eq!(status, StatusCode::Ok, "{}", body);
+        body
+    }
+
+    let threads: Vec<thread::JoinHandle<String>> = (0..8).map(|_| {
+        thread::spawn(|| {
+            let credentials = admin("POST", "/admin/accounts");
+            credentials.get("id").and_then(|v| v.as_str()).unwrap().to_string()
+        })
+    }).collect();
+
+    let created: Vec<String> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+    let account_ids = admin("GET", "/admin/accounts");
+    let listed: Vec<&str> = account_ids.as_array().unwrap().iter().filter_map(|id| id.as_str()).collect();
+
+    for account_id in &created {
+        assert!(listed.contains(&&account_id[..]), "{} is not listed", account_id);
+    }
+}
+
+#[test]
+fn should_manage_accounts_through_the_admin_api() {
+    let client = Client::new();
+    let admin = |method: &str, path: String, body: &str| send_json(admin_request(&client, 8000, method, path).body(body));
+
+    let is_authenticated = |account_id: uuid::Uuid, secret: String| {
+        let path = format!("/vertex/{}", uuid::Uuid::new_v4().hyphenated());
+        let res = request(&client, 8000, account_id, secret, "GET", path, vec![]).send().unwrap();
+        res.status != StatusCode::Unauthorized
+    };
+
+    let (status, credentials) = admin("POST", "/admin/accounts".to_string(), "");
+    assert_eq!(status, StatusCode::Ok);
+    let account_id: uuid::Uuid = credentials.get("id").and_then(|v| v.as_str()).unwrap().parse().unwrap();
+    let secret = credentials.get("secret").and_then(|v| v.as_str()).unwrap().to_string();
+    assert!(is_authenticated(account_id, secret.clone()));
+
+    let (status, account_ids) = admin("GET", "/admin/accounts".to_string(), "");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(account_ids.as_array().unwrap().iter().any(|id| id.as_str() == Some(&account_id.hyphenated().to_string()[..])));
+
+    let account_path = format!("/admin/accounts/{}", account_id.hyphenated());
+    let (status, account) = admin("POST", format!("{}/disable", account_path), "");
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(account.get("disabled").and_then(|v| v.as_bool()), Some(true));
+    assert!(!is_authenticated(account_id, secret.clone()));
+    admin("POST", format!("{}/enable", account_path), "");
+    assert!(is_authenticated(account_id, secret.clone()));
+
+    let (status, credentials) = admin("POST", format!("{}/secret", account_path), "");
+    assert_eq!(status, StatusCode::Ok);
+    let new_secret = credentials.get("secret").and_then(|v| v.as_str()).unwrap().to_string();
+    assert!(!is_authenticated(account_id, secret));
+    assert!(is_authenticated(account_id, new_secret));
+
+    let (status, _) = admin("PUT", format!("{}/metadata/plan", account_path), "\"enterprise\"");
+    assert_eq!(status, StatusCode::Ok);
+    let (status, plan) = admin("GET", format!("{}/metadata/plan", account_path), "");
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(plan.as_str(), Some("enterprise"));
+    let (status, body) = admin("GET", format!("{}/metadata/_braid_account_state", account_path), "");
+    assert_eq!(status, StatusCode::BadRequest);
+    assert_eq!(body.get("code").and_then(|v| v.as_str()), Some("reserved_metadata_key"));
+
+    let (status, _) = admin("DELETE", account_path.clone(), "");
+    assert_eq!(status, StatusCode::Ok);
+    let (status, body) = admin("GET", account_path, "");
+    assert_eq!(status, StatusCode::NotFound);
+    assert_eq!(body.get("code").and_then(|v| v.as_str()), Some("account_not_found"));
+}
+
+#[test]
+fn should_finish_in_flight_requests_when_shutting_down() {
+    let (account_id, secret) = create_account().unwrap();
+    let mut server = TestServer::start(8005, &[]);
+
+    let in_flight = thread::spawn(move || {
+        let client = AccountClient::new(8005, account_id, secret);
+        client.send("POST", "/script/slow.lua", vec![], "")
+    });
+
+    thread::sleep(Duration::from_millis(500));
+    let killed = Command::new("kill").arg("-TERM").arg(server.id().to_string()).status().unwrap();
+    assert!(killed.success());
+
+    // New requests are turned away while the slow one finishes
+    thread::sleep(Duration::from_millis(500));
+    assert!(Client::new().get("http://localhost:8005/healthz").send().is_err());
+
+    assert_eq!(in_flight.join().unwrap(), (StatusCode::Ok, "true".to_string()));
+    assert!(server.wait().success());
+}
+
+#[test]
+fn should_abort_transactions_at_the_shutdown_deadline() {
+    let (account_id, secret) = create_account().unwrap();
+    let mut server = TestServer::start(8011, &[("BRAID_SHUTDOWN_DEADLINE", "1")]);
+
+    let in_flight = thread::spawn(move || {
+        let client = AccountClient::new(8011, account_id, secret);
+        let _ = client.request("POST", "/script/slow_write.lua", vec![]).send();
+    });
+
+    thread::sleep(Duration::from_millis(500));
+    let killed = Command::new("kill").arg("-TERM").arg(server.id().to_string()).status().unwrap();
+    assert!(killed.success());
+    assert_eq!(server.wait().code(), Some(1));
+    in_flight.join().unwrap();
+
+    // The script's writes were rolled back rather than committed
+    let path = format!("/admin/accounts/{}/metadata/slow_write", account_id);
+    let (status, _) = send_json(admin_request(&Client::new(), 8000, "GET", path));
+    assert_eq!(status, StatusCode::NotFound);
+}
+
+#[test]
+fn should_write_an_access_log_line_per_request() {
+    let (account_id, secret) = create_account().unwrap();
+    let log_path = "target/access-log-test.log";
+    let _ = fs::remove_file(log_path);
+    let server = TestServer::start(8006, &[("BRAID_LOG_DESTINATION", log_path)]);
+
+    let client = AccountClient::new(8006, account_id, secret);
+    let request_id = uuid::Uuid::new_v4().hyphenated().to_string();
+    let mut res = client.request("GET", "/vertex", vec![]).header(RequestId(request_id.clone())).send().unwrap();
+    let mut payload = String::new();
+    res.read_to_string(&mut payload).unwrap();
+    assert_eq!(res.status, StatusCode::BadRequest);
+    assert_eq!(res.headers.get::<RequestId>(), Some(&RequestId(request_id.clone())));
+
+    // Request IDs that are too long, or have other characters, are replaced
+    let invalid_ids = vec!["a".repeat(129), "line\tbreak".to_string()];
+
+    for invalid_id in invalid_ids {
+        let res = client.request("GET", "/vertex", vec![]).header(RequestId(invalid_id.clone())).send().unwrap();
+        let RequestId(ref echoed_id) = *res.headers.get::<RequestId>().unwrap();
+        assert!(echoed_id != &invalid_id);
+        assert!(uuid::Uuid::parse_str(&echoed_id[..]).is_ok());
+    }
+
+    drop(server);
+
+    let entries: Vec<serde_json::Value> = BufReader::new(File::open(log_path).unwrap())
+        .lines()
+        .map(|line| serde_json::from_str(&line.unwrap()[..]).unwrap())
+        .filter(|entry: &serde_json::Value| entry.get("request_id").and_then(|v| v.as_str()) == Some(&request_id[..]))
+        .collect();
+
+    assert_eq!(entries.len(), 1);
+    let entry = &entries[0];
+    assert_eq!(entry.get("level").and_then(|v| v.as_str()), Some("warn"));
+    assert_eq!(entry.get("method").and_then(|v| v.as_str()), Some("GET"));
+    assert_eq!(entry.get("route").and_then(|v| v.as_str()), Some("get_vertices"));
+    assert_eq!(entry.get("status").and_then(|v| v.as_u64()), Some(400));
+    assert_eq!(entry.get("account_id").and_then(|v| v.as_str()), Some(&account_id.to_string()[..]));
+    assert_eq!(entry.get("size").and_then(|v| v.as_u64()), Some(payload.len() as u64));
+    assert!(entry.get("latency_ms").and_then(|v| v.as_f64()).is_some());
+    assert!(entry.get("time").and_then(|v| v.as_str()).is_some());
+}
+
+#[test]
+fn should_answer_cors_requests_from_allowed_origins() {
+    let (account_id, secret) = create_account().unwrap();
+    let _server = TestServer::start(8007, &[("BRAID_CORS_ALLOWED_ORIGINS", "https://allowed.example, https://other.example")]);
+    let client = Client::new();
+
+    let get_header = |res: &hyper::client::Response, name: &str| {
+        res.headers.get_raw(name).map(|values| String::from_utf8(values[0].clone()).unwrap())
+    };
+
+    // Preflight requests are answered without credentials, even for routes
+    // that need them
+    let res = client.request(hyper::method::Method::Options, "http://localhost:8007/vertex")
+        .header(RequestOrigin("https://allowed.example".to_string()))
+        .header(RequestMethod("DELETE".to_string()))
+        .send()
+        .unwrap();
+    assert_eq!(res.status, StatusCode::NoContent);
+    assert_eq!(get_header(&res, "Access-Control-Allow-Origin"), Some("https://allowed.example".to_string()));
+    assert_eq!(get_header(&res, "Access-Control-Allow-Methods"), Some("GET, POST, PUT, DELETE".to_string()));
+    assert_eq!(get_header(&res, "Access-Control-Allow-Headers"), Some("Authorization, Content-Type".to_string()));
+    assert_eq!(get_header(&res, "Access-Control-Max-Age"), Some("86400".to_string()));
+
+    // Actual requests get the headers whether they succeed or fail
+    let res = client.get("http://localhost:8007/healthz").header(RequestOrigin("https://other.example".to_string())).send().unwrap();
+    assert_eq!(res.status, StatusCode::Ok);
+    assert_eq!(get_header(&res, "Access-Control-Allow-Origin"), Some("https://other.example".to_string()));
+    assert_eq!(get_header(&res, "Access-Control-Expose-Headers"), Some("X-Request-Id".to_string()));
+    assert!(get_header(&res, "Vary").map_or(false, |vary| vary.contains("Origin")));
+
+    let account_client = AccountClient::new(8007, account_id, secret);
+    let res = account_client.request("GET", "/vertex", vec![]).header(RequestOrigin("https://allowed.example".to_string())).send().unwrap();
+    assert_eq!(res.status, StatusCode::BadRequest);
+    assert_eq!(get_header(&res, "Access-Control-Allow-Origin"), Some("https://allowed.example".to_string()));
+
+    // Other origins get nothing
+    let res = client.get("http://localhost:8007/healthz").header(RequestOrigin("https://evil.example".to_string())).send().unwrap();
+    assert_eq!(res.status, StatusCode::Ok);
+    assert_eq!(get_header(&res, "Access-Control-Allow-Origin"), None);
+    assert!(get_header(&res, "Vary").map_or(false, |vary| vary.contains("Origin")));
+
+    let res = client.request(hyper::method::Method::Options, "http://localhost:8007/vertex")
+        .header(RequestOrigin("https://evil.example".to_string()))
+        .header(RequestMethod("DELETE".to_string()))
+        .send()
+        .unwrap();
+    assert!(res.status != StatusCode::NoContent);
+    assert_eq!(get_header(&res, "Access-Control-Allow-Origin"), None);
+}
+
+#[test]
+fn should_allow_any_origin_with_a_wildcard() {
+    let _server = TestServer::start(8010, &[("BRAID_CORS_ALLOWED_ORIGINS", "*")]);
+    let client = Client::new();
+
+    let res = client.get("http://localhost:8010/healthz").header(RequestOrigin("https://any.example".to_string())).send().unwrap();
+    assert_eq!(res.status, StatusCode::Ok);
+    assert_eq!(res.headers.get_raw("Access-Control-Allow-Origin"), Some(&[b"*".to_vec()][..]));
+}
+
+#[test]
+fn should_serve_https_on_the_configured_address() {
+    let status = Command::new("openssl")
+        .args(&["req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1", "-subj", "/CN=localhost"])
+        .args(&["-keyout", "target/tls-test-key.pem", "-out", "target/tls-test-cert.pem"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let _server = TestServer::start(8008, &[
+        ("BRAID_BIND_ADDRESS", "127.0.0.1"),
+        ("BRAID_TLS_CERT", "target/tls-test-cert.pem"),
+        ("BRAID_TLS_KEY", "target/tls-test-key.pem"),
+    ]);
+
+    let mut builder = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+    builder.builder_mut().set_ca_file("target/tls-test-cert.pem").unwrap();
+    let connector = builder.build();
+
+    let stream = TcpStream::connect("127.0.0.1:8008").unwrap();
+    let mut stream = connector.connect("localhost", stream).unwrap();
+    stream.write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+
+    // Plain HTTP isn't served on the same port
+    let mut stream = TcpStream::connect("127.0.0.1:8008").unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    assert!(!response.starts_with(b"HTTP/1.1 200"));
+}